@@ -1,6 +1,71 @@
+// Byte offsets into the text of the field, start inclusive and end exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum Expr {
-    F64(f64),
-    Var(String),
-    Call(String, Vec<Expr>),
+    F64(f64, Span),
+    // Real and imaginary parts, from literals such as 2.5i
+    Complex(f64, f64, Span),
+    Var(String, Span),
+    Call(String, Vec<Expr>, Span),
+    // if cond then a else b
+    If(Box<Expr>, Box<Expr>, Box<Expr>, Span),
+    // name = value; body
+    Let(String, Box<Expr>, Box<Expr>, Span),
+    // def name(params) = ...; body
+    Def(Box<FuncDef>, Box<Expr>, Span),
+}
+
+#[derive(Debug, Clone)]
+pub struct FuncDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+    // From "def" to the end of the body
+    pub span: Span,
+}
+
+// param name = default; at the start of the iteration, a parameter that can be changed
+// without compiling again
+#[derive(Debug, Clone)]
+pub struct ParamDecl {
+    pub name: String,
+    // Has to be a number, such as 0.5 or 1 - 0.5i
+    pub default: Expr,
+    // From "param" to the end of the default
+    pub span: Span,
+}
+
+impl Span {
+    // From the start of self to the end of other
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::F64(_, span) | Expr::Complex(_, _, span) | Expr::Var(_, span) | Expr::Call(_, _, span) | Expr::If(_, _, _, span) | Expr::Let(_, _, _, span) | Expr::Def(_, _, span) => *span,
+        }
+    }
+
+    pub fn with_span(self, span: Span) -> Expr {
+        match self {
+            Expr::F64(x, _) => Expr::F64(x, span),
+            Expr::Complex(x, y, _) => Expr::Complex(x, y, span),
+            Expr::Var(v, _) => Expr::Var(v, span),
+            Expr::Call(f, args, _) => Expr::Call(f, args, span),
+            Expr::If(cond, a, b, _) => Expr::If(cond, a, b, span),
+            Expr::Let(name, value, body, _) => Expr::Let(name, value, body, span),
+            Expr::Def(def, body, _) => Expr::Def(def, body, span),
+        }
+    }
 }
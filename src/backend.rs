@@ -1,142 +1,1344 @@
 use std::collections::HashMap;
-use crate::ast::Expr;
+use std::fmt;
+use std::rc::Rc;
+use crate::ast::{Expr, FuncDef, Span};
+use crate::check::{check_arg_count, check_arity, ArityErr};
 use crate::dag::{Dag, DagNode, Effect, EffectType};
-use crate::module_builder::{BlockType,Func,ModuleBuilder,ValType};
-
-pub fn backend(expr_initz: Option<&Expr>, expr: &Expr, expr_escape2: &Expr, maxiter: &Expr) -> Vec<u8> {
-    if expr_initz.is_none() {
-        let mb = ModuleBuilder::default();
-        let (mb,solve) = backend_solve(mb, expr);
-        let mb = backend_main(mb, None, expr, expr_escape2, maxiter, Some(solve));
-        mb.into_vec()
-    } else {
-        let mb = ModuleBuilder::default();
-        let mb = backend_main(mb, expr_initz, expr, expr_escape2, maxiter, None);
-        mb.into_vec()
+use crate::diff::{self, DiffErr};
+use crate::error::{Field, Spanned};
+use crate::mathlib::{mathlib, MathLib};
+use crate::module_builder::{BlockType,Func,Global,Local,ModuleBuilder,ValType};
+use crate::palette::{backend_colour, Mode, Palette};
+use crate::rational::{rational, rational_up_to, Coeff, Poly};
+
+#[derive(Debug)]
+pub enum ExprErr {
+    UnknownVariable(String),
+    Arity(ArityErr),
+    ExpectedComplex,
+    ExpectedReal,
+    ExpectedBool,
+    NotDifferentiable(String),
+}
+
+#[derive(Debug)]
+pub struct BackendErr {
+    pub field: Field,
+    pub span: Span,
+    pub err: ExprErr,
+}
+
+// An ExprErr along with the sub-expression it came from
+#[derive(Debug)]
+pub struct SpannedErr {
+    span: Span,
+    err: ExprErr,
+}
+
+impl fmt::Display for ExprErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprErr::UnknownVariable(v) => write!(f, "Unknown variable '{}'", v),
+            ExprErr::Arity(e) => write!(f, "{}", e),
+            ExprErr::ExpectedComplex => write!(f, "Expected a number, found a condition"),
+            ExprErr::ExpectedReal => write!(f, "Expected a real number"),
+            ExprErr::ExpectedBool => write!(f, "Expected a condition such as 'sqabs(z) > 100'"),
+            ExprErr::NotDifferentiable(func) => write!(f, "Cannot differentiate '{}'", func),
+        }
+    }
+}
+
+impl From<ArityErr> for ExprErr {
+    fn from(e: ArityErr) -> Self {
+        ExprErr::Arity(e)
+    }
+}
+
+impl From<Spanned<DiffErr>> for SpannedErr {
+    fn from(e: Spanned<DiffErr>) -> Self {
+        let err = match e.err {
+            DiffErr::Arity(e) => e.into(),
+            DiffErr::NotDifferentiable(func) => ExprErr::NotDifferentiable(func),
+        };
+        SpannedErr { span: e.span, err }
     }
 }
 
-fn backend_main(mut mb: ModuleBuilder, expr_initz: Option<&Expr>, expr: &Expr, expr_escape2: &Expr, maxiter: &Expr, solve:Option<Func>) -> ModuleBuilder {
-    let expr_iter = Expr::Call("+".to_owned(), vec![Expr::Var("iter".to_owned()), Expr::F64(1.0)]);
-    let expr_escape1 = Expr::Call(">".to_owned(), vec![Expr::Var("iter".to_owned()), maxiter.clone()]);
+impl ExprErr {
+    pub fn at(self, span: Span) -> SpannedErr {
+        SpannedErr { span, err: self }
+    }
+}
 
-    let return_thing = mb.start_func(&[ValType::F64, ValType::F64, ValType::F64, ValType::F64], &[ValType::F64]);
+impl SpannedErr {
+    pub fn in_field(self, field: Field) -> BackendErr {
+        BackendErr { field, span: self.span, err: self.err }
+    }
+}
 
-    let cx = mb.get_local_param(2);
-    let cy = mb.get_local_param(3);
-    let l0 = mb.add_local(ValType::F64);
-    let l1 = mb.add_local(ValType::F64);
-    let iter = mb.add_local(ValType::F64);
+// Most critical points followed at once; beyond this the root finder is more code than
+// it's worth, and the Newton solver is used instead
+const MAX_CRITICAL: usize = 16;
+// Enough for the roots to settle even where two of them meet, which slows things down
+const ROOT_STEPS: u32 = 60;
+// The largest power multiplied out by cx_powi. Past it z^n goes through exp and log, which
+// is as accurate by then, since z^n overflows for any |z| much above 1 anyway.
+const MAX_POWI: i32 = 1 << 16;
+// Where render_tile starts the Newton solver, for lack of a (zx, zy) to take it from
+const TILE_START: (f64, f64) = (0.1, 0.1);
+// What render_rgba returns: done, or not started because the tile wouldn't fit in memory
+const TILE_DONE: u32 = 0;
+const TILE_NO_MEMORY: u32 = -1i32 as u32;
+// Results are scaled to go from 0 to the next to last bin, so each has one after it
+const HISTOGRAM_BINS: u32 = 4096;
 
-    let mut mb = if let Some(expr_initz) = expr_initz {
-        let mut fc = FuncContext::new(mb);
-        fc.env.insert("i".to_owned(), Structure::Complex(fc.dag.f64_zero(), fc.dag.f64_one()));
-        fc.env.insert("c".to_owned(), Structure::Complex(fc.dag.f64_input(cx), fc.dag.f64_input(cy)));
-        let initz = fc.do_expr(expr_initz);
-        let mut mb = fc.done(&[Effect(EffectType::Push, initz.cx()), Effect(EffectType::Push, initz.cy())]);
-        mb.local_set(l1);
-        mb.local_set(l0);
-        mb
+// How a critical point is looked for when there's no formula for one: Newton's method on
+// f', from the (zx, zy) given to the module
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Solver {
+    pub steps: u32,
+    // Small enough a step, relative to |z| or to 1 if that's smaller, to stop at
+    pub tolerance: f64,
+    // How much of each Newton step to take. Less than 1 is slower but strays less.
+    pub damping: f64,
+}
+
+impl Default for Solver {
+    fn default() -> Self {
+        Solver { steps: 50, tolerance: 1e-10, damping: 1.0 }
+    }
+}
+
+impl Solver {
+    // None unless steps is a whole number from 1 and tolerance and damping are finite and
+    // above 0
+    pub fn new(steps: f64, tolerance: f64, damping: f64) -> Option<Solver> {
+        let positive = |x: f64| x > 0.0 && x.is_finite();
+        if steps >= 1.0 && steps.fract() == 0.0 && positive(tolerance) && positive(damping) {
+            Some(Solver { steps: steps.min(u32::MAX as f64) as u32, tolerance, damping })
+        } else {
+            None
+        }
+    }
+}
+
+// How the search for a starting point went. The codes are what the module returns,
+// negated, in place of an iteration count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Searching,
+    Solved,
+    // Out of steps while still moving
+    NotConverged,
+    // Off towards infinity
+    Diverged,
+    // f' or f'' is infinite or not a number
+    Pole,
+    // f'' is zero where f' isn't, so there's no Newton step
+    Flat,
+}
+
+impl Status {
+    fn code(self) -> f64 {
+        match self {
+            Status::Searching => -1.0,
+            Status::Solved => 0.0,
+            Status::NotConverged => 1.0,
+            Status::Diverged => 2.0,
+            Status::Pole => 3.0,
+            Status::Flat => 4.0,
+        }
+    }
+}
+
+// The real or imaginary part of c or of a named parameter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Axis {
+    pub name: String,
+    pub imag: bool,
+}
+
+impl Axis {
+    // real(a) or imag(a)
+    pub fn parse(text: &str) -> Option<Axis> {
+        let text = text.trim();
+        let (imag, rest) = match (text.strip_prefix("real("), text.strip_prefix("imag(")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => return None,
+        };
+        let name = rest.strip_suffix(')')?.trim();
+        if name.is_empty() {
+            return None;
+        }
+        Some(Axis { name: name.to_owned(), imag })
+    }
+}
+
+impl fmt::Display for Axis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", if self.imag { "imag" } else { "real" }, self.name)
+    }
+}
+
+// Which plane the pixels are in
+#[derive(Clone, Debug, PartialEq)]
+pub enum Plane {
+    // The pixel's x and y are two of the axes of c and the named parameters, usually the
+    // real and imaginary parts of c
+    Parameter { x: Axis, y: Axis },
+    // The pixel is the starting z, or the variable named if the initial z is written in
+    // terms of it. c is fixed at compile time if given, and passed with the pixel if not.
+    Julia { pixel: String, c: Option<(f64, f64)> },
+}
+
+impl Plane {
+    // The whole plane of c or of a parameter
+    pub fn parameter(name: &str) -> Plane {
+        Plane::Parameter {
+            x: Axis { name: name.to_owned(), imag: false },
+            y: Axis { name: name.to_owned(), imag: true },
+        }
+    }
+
+    // The variable the pixel is, if it covers a whole plane of one. Distances are
+    // measured with respect to it.
+    fn variable(&self) -> Option<&str> {
+        match self {
+            Plane::Parameter { x, y } if x.name == y.name => Some(&x.name),
+            Plane::Parameter { .. } => None,
+            Plane::Julia { pixel, .. } => Some(pixel),
+        }
+    }
+}
+
+// The complex variables there are besides z and c
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params {
+    // Passed to the module's functions, for slices through them
+    pub passed: Vec<String>,
+    // Declared with "param name = default", and kept in globals
+    pub declared: Vec<(String, (f64, f64))>,
+}
+
+// Where the functions being built find c and the parameters
+struct Inputs {
+    passed: Vec<String>,
+    globals: Vec<(String, Global, Global)>,
+}
+
+impl Inputs {
+    fn has(&self, name: &str) -> bool {
+        self.passed.iter().any(|p| p == name) || self.globals.iter().any(|g| g.0 == name)
+    }
+}
+
+// The module exports
+//
+//     return_thing(zx, zy, cx, cy): the most iterations any starting point takes to escape
+//     smooth(zx, zy, cx, cy): the same, with a fraction that makes it continuous in c
+//     distance(zx, zy, cx, cy): the least distance estimate of any starting point, if the
+//         iteration can be differentiated
+//     critical_count(): how many starting points there are
+//     orbit(k, zx, zy, cx, cy): the iterations taken by starting point k
+//     render_tile(ptr, width, height, x0, y0, dx, dy): return_thing for each pixel of a
+//         tile, written to memory as f64s from ptr on, with (zx, zy) at TILE_START
+//     render_tile_smooth(ptr, width, height, x0, y0, dx, dy): the same with smooth
+//     render_rgba(ptr, width, height, x0, y0, dx, dy): smooth coloured by the palette, as
+//         RGBA bytes from ptr on. Returns 0, or -1 without storing anything if memory
+//         can't be grown to fit the tile.
+//     memory: where the tiles go. render_rgba grows it to fit; for the others the caller
+//         has to.
+//
+// With an initial z there is one starting point. Without, there is one per critical point.
+// When the iteration is a rational function of z, those are found by polynomial root
+// finding; otherwise a single one is found by Newton's method from (zx, zy). If that
+// fails, return_thing and orbit give minus the Status code instead: -1 for running out of
+// steps, -2 for diverging, -3 for a pole and -4 for a zero second derivative.
+//
+// Each of params.passed is another complex variable like c, passed as two more arguments
+// after (cx, cy). When the plane is a slice through them, the pixel's two axes come in
+// place of (cx, cy) and the rest follow in order, so that with the axes real(c) and
+// imag(c) nothing changes. There's only a distance when the slice is the whole plane of
+// one variable. Each of params.declared is kept in two exported globals instead, such as
+// a_x and a_y for a, which can be set between calls.
+//
+// In the Julia plane (zx, zy) is the pixel, there's the one starting point it gives, and
+// the distance is to the boundary in that plane. With c fixed, (cx, cy) is left out. In both
+// planes the tiles take whatever follows the pixel in the other exports, after dy.
+#[allow(clippy::too_many_arguments)]
+pub fn backend(expr_initz: Option<&Expr>, expr: &Expr, expr_escape2: &Expr, maxiter: &Expr, solver: &Solver, params: &Params, plane: &Plane, palette: &Palette) -> Result<Vec<u8>, BackendErr> {
+    let (mut mb, lib) = mathlib(ModuleBuilder::default());
+    // For the tiles
+    mb.add_memory(1);
+    mb.export_memory("memory");
+    let mut globals = vec![];
+    for (name, (x, y)) in &params.declared {
+        let (gx, gy) = (mb.add_global_f64(*x), mb.add_global_f64(*y));
+        mb.export_global(gx, &format!("{}_x", name));
+        mb.export_global(gy, &format!("{}_y", name));
+        globals.push((name.clone(), gx, gy));
+    }
+    let inputs = Inputs { passed: params.passed.clone(), globals };
+    let inputs = &inputs;
+    let start = match plane {
+        Plane::Parameter { .. } => Start::Given(expr_initz, plane.variable()),
+        Plane::Julia { pixel, .. } => Start::Pixel(expr_initz, pixel),
+    };
+    let (mb, iterate) = backend_iterate(mb, lib, expr, expr_escape2, maxiter, inputs, start, Output::Count)?;
+    let (mb, smooth) = backend_iterate(mb, lib, expr, expr_escape2, maxiter, inputs, start, Output::Smooth)?;
+    // Formulas with functions that can't be differentiated get no distance estimate, and
+    // neither do slices across two variables
+    let distance = match plane.variable() {
+        Some(_) => match backend_iterate(mb.clone(), lib, expr, expr_escape2, maxiter, inputs, start, Output::Distance) {
+            Ok(built) => Some(built),
+            Err(BackendErr { err: ExprErr::NotDifferentiable(_), .. }) => None,
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+    let (mb, distance) = match distance {
+        Some((mb, distance)) => (mb, Some(distance)),
+        None => (mb, None),
+    };
+    let (x, y) = match plane {
+        Plane::Parameter { x, y } => (x, y),
+        Plane::Julia { c, .. } => return Ok(backend_julia_exports(mb, lib, palette, *c, params.passed.len(), iterate, smooth, distance).into_vec()),
+    };
+    let critical = match expr_initz {
+        Some(_) => None,
+        None => critical_numerator(expr, inputs),
+    };
+    let (mb, starts, count) = match (expr_initz, critical) {
+        (Some(expr_initz), _) => {
+            let (mb, starts) = backend_initz(mb, lib, expr_initz, inputs)?;
+            (mb, starts, 1)
+        }
+        (None, Some(poly)) => {
+            let count = poly.degree().unwrap_or(0).max(1);
+            let (mb, starts) = backend_roots(mb, lib, &poly, inputs, expr.span())?;
+            (mb, starts, count)
+        }
+        (None, None) => {
+            let (mb, solve) = backend_solve(mb, lib, expr, inputs, solver)?;
+            (mb, solve, 1)
+        }
+    };
+    let order = slice_order(&params.passed, x, y);
+    Ok(backend_exports(mb, lib, palette, starts, count, &order, iterate, smooth, distance).into_vec())
+}
+
+// The axes of c and then of each parameter, in the order iterate() takes them after (zx, zy)
+fn axes(params: &[String]) -> Vec<Axis> {
+    let names = std::iter::once("c").chain(params.iter().map(String::as_str));
+    names.flat_map(|name| [false, true].iter().map(move |imag| Axis { name: name.to_owned(), imag: *imag })).collect()
+}
+
+// For each of iterate()'s parameters, which of an export's it comes from: (zx, zy), then
+// the pixel's x and y, then the other axes in their usual order
+fn slice_order(params: &[String], x: &Axis, y: &Axis) -> Vec<usize> {
+    let axes = axes(params);
+    let mut rest = 4..;
+    let mut order = vec![0, 1];
+    for axis in &axes {
+        order.push(if axis == x {
+            2
+        } else if axis == y {
+            3
+        } else {
+            rest.next().unwrap()
+        });
+    }
+    order
+}
+
+// The types of iterate()'s parameters: (zx, zy, cx, cy) and two for each parameter passed
+fn input_types(inputs: &Inputs) -> Vec<ValType> {
+    vec![ValType::F64; 4 + 2 * inputs.passed.len()]
+}
+
+// The numerator of f' as a polynomial in z with coefficients in c and the parameters, if f
+// is rational in z and its critical points are few enough to follow
+fn critical_numerator(expr: &Expr, inputs: &Inputs) -> Option<Poly> {
+    // Degree d in z means at least d - 1 critical points, too many past MAX_CRITICAL + 1
+    let f = rational_up_to(expr, MAX_CRITICAL + 1).ok()?;
+    if f.params().iter().any(|p| p != "c" && !inputs.has(p)) {
+        return None;
+    }
+    let poly = f.critical_numerator();
+    if poly.degree().is_some_and(|d| d > MAX_CRITICAL) {
+        return None;
+    }
+    Some(poly)
+}
+
+// Where iterate() gets its first z
+#[derive(Clone, Copy)]
+enum Start<'a> {
+    // Its first two parameters, as found by starts() from the initial z if there is one.
+    // The variable named is the one the pixel is, if it's a whole plane of one.
+    Given(Option<&'a Expr>, Option<&'a str>),
+    // Its first two parameters are the pixel, bound to the name given for the initial z.
+    // Without an initial z, the pixel is the first z.
+    Pixel(Option<&'a Expr>, &'a str),
+}
+
+// What iterate() returns
+#[derive(Clone, Copy)]
+enum Output {
+    // The number of iterations before escaping
+    Count,
+    // The count less log_d(log|z| / log R) for the escaping z, which goes from 1 to 0 as
+    // |z| goes from R to R^d, d being the degree of the iteration and R the escape radius.
+    // Only for iterations that are rational with d > 1; otherwise the same as Count.
+    Smooth,
+    // |z| log|z| / |dz/dp| for the escaping z, p being the pixel, an estimate of the
+    // distance from p to the boundary of the set, or 0 for points that don't escape. dz/dp
+    // is carried along with z, starting from the derivative of the initial z if there is
+    // one. Otherwise it starts from 1 in the Julia plane and from 0 in the parameter
+    // plane, where a critical point needs no more: however it moves with c, f doesn't
+    // change to first order there.
+    Distance,
+}
+
+// R^2 for an escape condition such as sqabs(z) > 100 or abs(z) > 10
+fn escape_radius2(expr: &Expr) -> Option<f64> {
+    let (size, bound) = match expr {
+        Expr::Call(op, args, _) if args.len() == 2 => match op.as_str() {
+            ">" | ">=" => (&args[0], &args[1]),
+            "<" | "<=" => (&args[1], &args[0]),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let bound = match bound {
+        Expr::F64(x, _) if *x > 1.0 => *x,
+        _ => return None,
+    };
+    match size {
+        Expr::Call(f, args, _) if args.len() == 1 && matches!(&args[0], Expr::Var(z, _) if z == "z") => match f.as_str() {
+            "sqabs" => Some(bound),
+            "abs" => Some(bound * bound),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// d where the iteration grows like z^d for large z. Up to rational::MAX_DEGREE, past which
+// smooth is the same as count.
+fn escape_degree(expr: &Expr) -> Option<f64> {
+    let f = rational(expr).ok()?;
+    let d = f.num.degree()? as i64 - f.den.degree()? as i64;
+    if d > 1 {
+        Some(d as f64)
     } else {
-        let initzx = mb.get_local_param(0);
-        let initzy = mb.get_local_param(1);
-        mb.local_get(initzx);
-        mb.local_get(initzy);
-        mb.local_get(cx);
-        mb.local_get(cy);
-        mb.call(solve.unwrap());
-        mb.local_set(l1);
-        mb.local_set(l0);
-        mb
+        None
+    }
+}
+
+// iterate(zx, zy, cx, cy, params...) -> iterations until escape, or whatever else output says
+#[allow(clippy::too_many_arguments)]
+fn backend_iterate(mut mb: ModuleBuilder, lib: MathLib, expr: &Expr, expr_escape2: &Expr, maxiter: &Expr, inputs: &Inputs, start: Start, output: Output) -> Result<(ModuleBuilder, Func), BackendErr> {
+    let iterate = mb.start_func(&input_types(inputs), &[ValType::F64]);
+    let degree = match output {
+        Output::Smooth => escape_degree(expr),
+        _ => None,
+    };
+
+    let l0 = mb.get_local_param(0);
+    let l1 = mb.get_local_param(1);
+    let iter = mb.add_local(ValType::F64);
+    // dz/dp and the distance, for Output::Distance
+    let distance = match output {
+        Output::Distance => Some((mb.add_local(ValType::F64), mb.add_local(ValType::F64), mb.add_local(ValType::F64))),
+        _ => None,
     };
 
     mb.f64_const(0.0);
     mb.local_set(iter);
+    if let Some((_, _, result)) = distance {
+        mb.f64_const(0.0);
+        mb.local_set(result);
+    }
 
+    // The first z, if it's worked out from the pixel, then its derivative
+    let mut fc = FuncContext::new(mb, lib);
+    let mut effects = vec![];
+    let initz = match start {
+        Start::Given(initz, _) | Start::Pixel(initz, _) => initz,
+    };
+    let at = |e: ExprErr| e.at(initz.map_or(expr.span(), Expr::span)).in_field(Field::InitialZ);
+    match (start, distance) {
+        (Start::Pixel(Some(initz), pixel), None) => {
+            fc.bind_inputs(inputs);
+            fc.env.insert(pixel.to_owned(), Structure::Complex(fc.dag.f64_input(l0), fc.dag.f64_input(l1)));
+            let z = fc.do_expr(initz).map_err(|e| e.in_field(Field::InitialZ))?;
+            effects.push(Effect(EffectType::Push, z.cx().map_err(at)?));
+            effects.push(Effect(EffectType::Push, z.cy().map_err(at)?));
+        }
+        (Start::Pixel(Some(initz), pixel), Some(_)) => {
+            let (zero, one) = (fc.dag.f64_zero(), fc.dag.f64_one());
+            fc.bind_inputs_deriv(inputs, None);
+            fc.env.insert(pixel.to_owned(), Structure::CxDeriv([fc.dag.f64_input(l0), fc.dag.f64_input(l1), one, zero, zero, zero]));
+            let d = fc.do_expr_deriv(initz).map_err(|e| e.in_field(Field::InitialZ))?.derivs().map_err(at)?;
+            for z in &d[..2] {
+                effects.push(Effect(EffectType::Push, z.cx().map_err(at)?));
+                effects.push(Effect(EffectType::Push, z.cy().map_err(at)?));
+            }
+        }
+        (Start::Pixel(None, _), Some(_)) => {
+            effects.push(Effect(EffectType::Push, fc.dag.f64_one()));
+            effects.push(Effect(EffectType::Push, fc.dag.f64_zero()));
+        }
+        (Start::Given(Some(initz), wrt), Some(_)) => {
+            fc.bind_inputs_deriv(inputs, wrt);
+            let d = fc.do_expr_deriv(initz).map_err(|e| e.in_field(Field::InitialZ))?.derivs().map_err(at)?;
+            effects.push(Effect(EffectType::Push, d[1].cx().map_err(at)?));
+            effects.push(Effect(EffectType::Push, d[1].cy().map_err(at)?));
+        }
+        (Start::Given(None, _), Some(_)) => {
+            effects.push(Effect(EffectType::Push, fc.dag.f64_zero()));
+            effects.push(Effect(EffectType::Push, fc.dag.f64_zero()));
+        }
+        (_, None) => {}
+    }
+    mb = fc.done(&effects);
+    if let Some((dzx, dzy, _)) = distance {
+        mb.local_set(dzy);
+        mb.local_set(dzx);
+    }
+    if let Start::Pixel(Some(_), _) = start {
+        mb.local_set(l1);
+        mb.local_set(l0);
+    }
+
+    // Reaching maxiter leaves both blocks, escaping only the inner one
+    mb.start_block(BlockType::Empty);
     mb.start_block(BlockType::Empty);
     mb.start_loop(BlockType::Empty);
 
-    let mut fc = FuncContext::new(mb);
-    fc.env.insert("i".to_owned(), Structure::Complex(fc.dag.f64_zero(), fc.dag.f64_one()));
-    fc.env.insert("c".to_owned(), Structure::Complex(fc.dag.f64_input(cx), fc.dag.f64_input(cy)));
+    let mut fc = FuncContext::new(mb, lib);
+    fc.bind_inputs(inputs);
     fc.env.insert("z".to_owned(), Structure::Complex(fc.dag.f64_input(l0), fc.dag.f64_input(l1)));
     fc.env.insert("iter".to_owned(), Structure::Complex(fc.dag.f64_input(iter), fc.dag.f64_zero()));
-    let escape1 = fc.do_expr(&expr_escape1);
-    let escape2 = fc.do_expr(&expr_escape2);
-    let newz = fc.do_expr(expr);
-    let newiter = fc.do_expr(&expr_iter);
-    let mut mb = fc.done(&[
-                         Effect(EffectType::BrIf(1), escape1.boolean()),
-                         Effect(EffectType::BrIf(1), escape2.boolean()),
-                         Effect(EffectType::Push, newz.cx()),
-                         Effect(EffectType::Push, newz.cy()),
-                         Effect(EffectType::Push, newiter.as_real_f64())]);
+    let maxiter = fc.do_expr(maxiter).and_then(|m| m.as_real_f64().map_err(|e| e.at(maxiter.span()))).map_err(|e| e.in_field(Field::MaxIter))?;
+    let escape1 = fc.dag.f64_gt(fc.dag.f64_input(iter), maxiter);
+    let escape2 = fc.do_expr(expr_escape2).and_then(|e| e.boolean().map_err(|e| e.at(expr_escape2.span()))).map_err(|e| e.in_field(Field::Escape))?;
+    let at = |e: ExprErr| e.at(expr.span()).in_field(Field::Iteration);
+    let mut effects = vec![Effect(EffectType::BrIf(2), escape1), Effect(EffectType::BrIf(1), escape2)];
+    match distance {
+        None => {
+            let newz = fc.do_expr(expr).map_err(|e| e.in_field(Field::Iteration))?;
+            effects.push(Effect(EffectType::Push, newz.cx().map_err(at)?));
+            effects.push(Effect(EffectType::Push, newz.cy().map_err(at)?));
+        }
+        Some((dzx, dzy, _)) => {
+            match start {
+                Start::Given(_, wrt) => fc.bind_inputs_deriv(inputs, wrt),
+                Start::Pixel(..) => fc.bind_inputs_deriv(inputs, None),
+            }
+            fc.env.insert("z".to_owned(), Structure::CxDeriv([fc.dag.f64_input(l0), fc.dag.f64_input(l1), fc.dag.f64_input(dzx), fc.dag.f64_input(dzy), fc.dag.f64_zero(), fc.dag.f64_zero()]));
+            fc.env.insert("iter".to_owned(), fc.dconst(fc.dag.f64_input(iter), fc.dag.f64_zero()));
+            let d = fc.do_expr_deriv(expr).and_then(|d| d.derivs().map_err(|e| e.at(expr.span()))).map_err(|e| e.in_field(Field::Iteration))?;
+            for z in &d[..2] {
+                effects.push(Effect(EffectType::Push, z.cx().map_err(at)?));
+                effects.push(Effect(EffectType::Push, z.cy().map_err(at)?));
+            }
+        }
+    }
+    effects.push(Effect(EffectType::Push, fc.dag.f64_add(fc.dag.f64_input(iter), fc.dag.f64_one())));
+    let mut mb = fc.done(&effects);
     mb.local_set(iter);
+    if let Some((dzx, dzy, _)) = distance {
+        mb.local_set(dzy);
+        mb.local_set(dzx);
+    }
     mb.local_set(l1);
     mb.local_set(l0);
 
     mb.br(0);
     mb.end_loop();
     mb.end_block();
-    mb.local_get(iter);
+    if let Some(degree) = degree {
+        // Without a radius to go by, log R is taken as 1/2, which only shifts the count
+        let log_r2 = escape_radius2(expr_escape2).map_or(1.0, f64::ln);
+        let mut fc = FuncContext::new(mb, lib);
+        let z = Structure::Complex(fc.dag.f64_input(l0), fc.dag.f64_input(l1));
+        let zz = fc.abs2(&z).map_err(|e| e.at(expr.span()).in_field(Field::Iteration))?;
+        let log_zz = fc.dag.call1(lib.log, zz);
+        let ratio = fc.dag.f64_div(log_zz, fc.dag.f64_const(log_r2));
+        let log_ratio = fc.dag.call1(lib.log, ratio);
+        let fraction = fc.dag.f64_mul(log_ratio, fc.dag.f64_const(1.0 / degree.ln()));
+        let next = fc.dag.f64_add(fc.dag.f64_input(iter), fc.dag.f64_one());
+        let smooth = fc.dag.f64_sub(next, fraction);
+        // An infinite z would give -infinity
+        let size = fc.dag.f64_abs(smooth);
+        let finite = fc.dag.f64_lt(size, fc.dag.f64_const(f64::MAX));
+        let smooth = fc.dag.select(smooth, fc.dag.f64_input(iter), finite);
+        mb = fc.done(&[Effect(EffectType::Push, smooth)]);
+        mb.local_set(iter);
+    }
+    if let Some((dzx, dzy, result)) = distance {
+        let mut fc = FuncContext::new(mb, lib);
+        let z = Structure::Complex(fc.dag.f64_input(l0), fc.dag.f64_input(l1));
+        let dz = Structure::Complex(fc.dag.f64_input(dzx), fc.dag.f64_input(dzy));
+        let zz = fc.abs2(&z).map_err(at)?;
+        let dd = fc.abs2(&dz).map_err(at)?;
+        let log_zz = fc.dag.call1(lib.log, zz);
+        let log_z = fc.dag.f64_mul(log_zz, fc.dag.f64_const(0.5));
+        let size = fc.dag.f64_sqrt(zz);
+        let top = fc.dag.f64_mul(size, log_z);
+        let bottom = fc.dag.f64_sqrt(dd);
+        let estimate = fc.dag.f64_div(top, bottom);
+        // Overflow on the way gives infinity or NaN; either way c is about on the boundary
+        let finite = fc.dag.f64_lt(estimate, fc.dag.f64_const(f64::MAX));
+        let estimate = fc.dag.select(estimate, fc.dag.f64_zero(), finite);
+        mb = fc.done(&[Effect(EffectType::Push, estimate)]);
+        mb.local_set(result);
+    }
+    mb.end_block();
+    match distance {
+        Some((_, _, result)) => mb.local_get(result),
+        None => mb.local_get(iter),
+    }
 
     mb.end_func();
-    mb.export_func(return_thing, "return_thing");
-    mb
+    Ok((mb, iterate))
 }
 
-fn backend_solve(mut mb: ModuleBuilder, expr: &Expr) -> (ModuleBuilder, Func) {
-    let solve = mb.start_func(&[ValType::F64, ValType::F64, ValType::F64, ValType::F64], &[ValType::F64, ValType::F64]);
+// starts(zx, zy, cx, cy, params...) -> the initial z, and Status::Solved
+fn backend_initz(mut mb: ModuleBuilder, lib: MathLib, expr_initz: &Expr, inputs: &Inputs) -> Result<(ModuleBuilder, Func), BackendErr> {
+    let starts = mb.start_func(&input_types(inputs), &[ValType::F64, ValType::F64, ValType::F64]);
 
-    let zx = mb.get_local_param(0);
-    let zy = mb.get_local_param(1);
-    let cx = mb.get_local_param(2);
-    let cy = mb.get_local_param(3);
-    let zx1 = mb.add_local(ValType::F64);
-    let zy1 = mb.add_local(ValType::F64);
-    let zx2 = mb.add_local(ValType::F64);
-    let zy2 = mb.add_local(ValType::F64);
+    let mut fc = FuncContext::new(mb, lib);
+    fc.bind_inputs(inputs);
+    let initz = fc.do_expr(expr_initz).map_err(|e| e.in_field(Field::InitialZ))?;
+    let initzx = initz.cx().map_err(|e| e.at(expr_initz.span()).in_field(Field::InitialZ))?;
+    let initzy = initz.cy().map_err(|e| e.at(expr_initz.span()).in_field(Field::InitialZ))?;
+    let solved = fc.dag.f64_const(Status::Solved.code());
+    let mut mb = fc.done(&[Effect(EffectType::Push, initzx), Effect(EffectType::Push, initzy), Effect(EffectType::Push, solved)]);
+
+    mb.end_func();
+    Ok((mb, starts))
+}
+
+// starts(zx, zy, cx, cy, params...) -> every root of poly. Roots that don't depend on c are found
+// now, and those of lines, quadratics and z^n = a are written out. The rest are found
+// by the Aberth-Ehrlich method: Newton's method on each root, with the others pushing it
+// away so that no two settle on the same one. A polynomial without roots gives 0 instead,
+// so there's always an orbit. The last result is Status::Solved.
+fn backend_roots(mut mb: ModuleBuilder, lib: MathLib, poly: &Poly, inputs: &Inputs, span: Span) -> Result<(ModuleBuilder, Func), BackendErr> {
+    let n = poly.degree().unwrap_or(0);
+    let starts = mb.start_func(&input_types(inputs), &vec![ValType::F64; 2 * n.max(1) + 1]);
+    if n == 0 {
+        mb.f64_const(0.0);
+        mb.f64_const(0.0);
+        mb.f64_const(Status::Solved.code());
+        mb.end_func();
+        return Ok((mb, starts));
+    }
+    if let Some(roots) = poly.roots() {
+        for (x, y) in roots {
+            mb.f64_const(x);
+            mb.f64_const(y);
+        }
+        mb.f64_const(Status::Solved.code());
+        mb.end_func();
+        return Ok((mb, starts));
+    }
+    // The coefficients divided by the leading one, then the roots, as x and y
+    let coeff_locals: Vec<Local> = (0..2 * n).map(|_| mb.add_local(ValType::F64)).collect();
+    let root_locals: Vec<Local> = (0..2 * n).map(|_| mb.add_local(ValType::F64)).collect();
     let iter = mb.add_local(ValType::I32);
 
+    let mut fc = FuncContext::new(mb, lib);
+    fc.bind_inputs(inputs);
+    let at = |e: ExprErr| e.at(span).in_field(Field::Iteration);
+    let coeffs = poly.coeffs().iter().map(|c| fc.do_expr(&c.to_expr(span))).collect::<Result<Vec<_>, _>>().map_err(|e| e.in_field(Field::Iteration))?;
+    let b = coeffs[..n].iter().map(|c| fc.cx_div(c, &coeffs[n])).collect::<Result<Vec<_>, _>>().map_err(at)?;
+    let binomial = poly.coeffs()[1..n].iter().all(Coeff::is_zero);
+    if let Some(roots) = fc.closed_form_roots(&b, binomial).map_err(at)? {
+        let mut effects = vec![];
+        for root in &roots {
+            effects.push(Effect(EffectType::Push, root.cx().map_err(at)?));
+            effects.push(Effect(EffectType::Push, root.cy().map_err(at)?));
+        }
+        effects.push(Effect(EffectType::Push, fc.dag.f64_const(Status::Solved.code())));
+        let mut mb = fc.done(&effects);
+        mb.end_func();
+        return Ok((mb, starts));
+    }
+
+    // Start on a circle that holds every root, at angles that avoid symmetries of the
+    // polynomial
+    let mut effects = vec![];
+    let mut radius = fc.dag.f64_one();
+    for bk in &b {
+        let rr = fc.abs2(bk).map_err(at)?;
+        let r = fc.dag.f64_sqrt(rr);
+        let r = fc.dag.f64_add(r, fc.dag.f64_one());
+        radius = fc.dag.f64_max(radius, r);
+        effects.push(Effect(EffectType::Push, bk.cx().map_err(at)?));
+        effects.push(Effect(EffectType::Push, bk.cy().map_err(at)?));
+    }
+    for k in 0..n {
+        let angle = 2.0 * std::f64::consts::PI * k as f64 / n as f64 + 0.4;
+        let x = fc.dag.f64_const(angle.cos());
+        let y = fc.dag.f64_const(angle.sin());
+        effects.push(Effect(EffectType::Push, fc.dag.f64_mul(radius, x)));
+        effects.push(Effect(EffectType::Push, fc.dag.f64_mul(radius, y)));
+    }
+    let mut mb = fc.done(&effects);
+    for local in root_locals.iter().rev().chain(coeff_locals.iter().rev()) {
+        mb.local_set(*local);
+    }
+
     mb.i32_const(0);
     mb.local_set(iter);
-
+    mb.start_block(BlockType::Empty);
     mb.start_loop(BlockType::Empty);
-    mb.f64_const(1.0);
-    mb.local_set(zx1);
-    mb.f64_const(0.0);
-    mb.local_set(zy1);
-    mb.f64_const(0.0);
-    mb.local_set(zx2);
-    mb.f64_const(0.0);
-    mb.local_set(zy2);
-    let mut fc = FuncContext::new(mb);
-    fc.env.insert("i".to_owned(), fc.dconst(fc.dag.f64_zero(), fc.dag.f64_one()));
-    fc.env.insert("c".to_owned(), fc.dconst(fc.dag.f64_input(cx), fc.dag.f64_input(cy)));
-    fc.env.insert("z".to_owned(), Structure::CxDeriv([fc.dag.f64_input(zx), fc.dag.f64_input(zy), fc.dag.f64_input(zx1), fc.dag.f64_input(zy1), fc.dag.f64_input(zx2), fc.dag.f64_input(zy2)]));
-    let stuff = fc.do_expr_deriv(expr);
-    let stuff1 = fc.cx_div(&stuff.derivs()[1], &stuff.derivs()[2]);
-    let newz = fc.cx_sub(&Structure::Complex(fc.dag.f64_input(zx), fc.dag.f64_input(zy)), &stuff1);
-    let mut mb = fc.done(&[Effect(EffectType::Push, newz.cx()), Effect(EffectType::Push, newz.cy())]);
-    mb.local_set(zy);
-    mb.local_set(zx);
+
+    let mut fc = FuncContext::new(mb, lib);
+    let input = |fc: &FuncContext, locals: &[Local], k: usize| Structure::Complex(fc.dag.f64_input(locals[2 * k]), fc.dag.f64_input(locals[2 * k + 1]));
+    let b: Vec<Structure> = (0..n).map(|k| input(&fc, &coeff_locals, k)).collect();
+    let z: Vec<Structure> = (0..n).map(|k| input(&fc, &root_locals, k)).collect();
+    let mut moves = fc.dag.f64_zero();
+    let mut effects = vec![];
+    for k in 0..n {
+        // p(z) and p'(z) by Horner's rule, p being monic
+        let mut p = fc.cx_one();
+        let mut dp = Structure::Complex(fc.dag.f64_zero(), fc.dag.f64_zero());
+        for bj in b.iter().rev() {
+            let dp_z = fc.cx_mul(&dp, &z[k]).map_err(at)?;
+            dp = fc.cx_add(&dp_z, &p).map_err(at)?;
+            let p_z = fc.cx_mul(&p, &z[k]).map_err(at)?;
+            p = fc.cx_add(&p_z, bj).map_err(at)?;
+        }
+        let newton = fc.cx_div(&p, &dp).map_err(at)?;
+        let mut repulsion = Structure::Complex(fc.dag.f64_zero(), fc.dag.f64_zero());
+        for (j, zj) in z.iter().enumerate() {
+            if j != k {
+                let diff = fc.cx_sub(&z[k], zj).map_err(at)?;
+                let one = fc.cx_one();
+                let inv = fc.cx_div(&one, &diff).map_err(at)?;
+                repulsion = fc.cx_add(&repulsion, &inv).map_err(at)?;
+            }
+        }
+        // w = N / (1 - N sum 1/(z_k - z_j)), with N the Newton step
+        let n_sum = fc.cx_mul(&newton, &repulsion).map_err(at)?;
+        let one = fc.cx_one();
+        let denom = fc.cx_sub(&one, &n_sum).map_err(at)?;
+        let w = fc.cx_div(&newton, &denom).map_err(at)?;
+        let ww = fc.abs2(&w).map_err(at)?;
+        let moved = fc.cx_sub(&z[k], &w).map_err(at)?;
+        // A root sitting exactly on a multiple root gives 0/0; leave it where it is
+        let finite = fc.dag.f64_lt(ww, fc.dag.f64_const(f64::MAX));
+        let x = fc.dag.select(moved.cx().map_err(at)?, z[k].cx().map_err(at)?, finite);
+        let y = fc.dag.select(moved.cy().map_err(at)?, z[k].cy().map_err(at)?, finite);
+        let ww = fc.dag.select(ww, fc.dag.f64_zero(), finite);
+        moves = fc.dag.f64_add(moves, ww);
+        effects.push(Effect(EffectType::Push, x));
+        effects.push(Effect(EffectType::Push, y));
+    }
+    let converged = fc.dag.f64_le(moves, fc.dag.f64_const(1e-28));
+    effects.insert(0, Effect(EffectType::BrIf(1), converged));
+    let mut mb = fc.done(&effects);
+    for local in root_locals.iter().rev() {
+        mb.local_set(*local);
+    }
 
     mb.local_get(iter);
     mb.i32_const(1);
     mb.i32_add();
     mb.local_tee(iter);
+    mb.i32_const(ROOT_STEPS);
+    mb.i32_lt_u();
+    mb.br_if(0);
+
+    mb.end_loop();
+    mb.end_block();
+    for local in &root_locals {
+        mb.local_get(*local);
+    }
+    mb.f64_const(Status::Solved.code());
+    mb.end_func();
+    Ok((mb, starts))
+}
+
+// order says where each of iterate()'s parameters is among the exports' own, after k
+#[allow(clippy::too_many_arguments)]
+fn backend_exports(mut mb: ModuleBuilder, lib: MathLib, palette: &Palette, starts: Func, count: usize, order: &[usize], iterate: Func, smooth: Func, distance: Option<Func>) -> ModuleBuilder {
+    let critical_count = mb.start_func(&[], &[ValType::I32]);
+    mb.i32_const(count as u32);
+    mb.end_func();
+    mb.export_func(critical_count, "critical_count");
+
+    let return_thing = export_over_starts(&mut mb, "return_thing", starts, count, order, iterate, true);
+    let smooth = export_over_starts(&mut mb, "smooth", starts, count, order, smooth, true);
+    export_tile(&mut mb, "render_tile", return_thing, true, order.len() - 4);
+    export_tile(&mut mb, "render_tile_smooth", smooth, true, order.len() - 4);
+    export_rgba_tile(&mut mb, lib, "render_rgba", palette, smooth, true, order.len() - 4);
+    if let Some(distance) = distance {
+        export_over_starts(&mut mb, "distance", starts, count, order, distance, false);
+    }
+
+    let orbit = mb.start_func(&vec![ValType::F64; 1 + order.len()], &[ValType::F64]);
+    let k = mb.get_local_param(0);
+    let inputs = inputs(&mb, order, 1);
+    let (zs, status) = call_starts(&mut mb, starts, count, &inputs);
+    mb.local_get(status);
+    mb.f64_neg();
+    let mut fc = FuncContext::new(mb, lib);
+    let mut x = fc.dag.f64_input(zs[0]);
+    let mut y = fc.dag.f64_input(zs[1]);
+    for j in 1..count {
+        let is_j = fc.dag.f64_eq(fc.dag.f64_input(k), fc.dag.f64_const(j as f64));
+        x = fc.dag.select(fc.dag.f64_input(zs[2 * j]), x, is_j);
+        y = fc.dag.select(fc.dag.f64_input(zs[2 * j + 1]), y, is_j);
+    }
+    let mut mb = fc.done(&[Effect(EffectType::Push, x), Effect(EffectType::Push, y)]);
+    for input in &inputs[2..] {
+        mb.local_get(*input);
+    }
+    mb.call(iterate);
+    unless_solved(&mut mb, status);
+    mb.end_func();
+    mb.export_func(orbit, "orbit");
+    mb
+}
+
+// The same exports for the Julia plane, where each pixel is its own starting point
+#[allow(clippy::too_many_arguments)]
+fn backend_julia_exports(mut mb: ModuleBuilder, lib: MathLib, palette: &Palette, c: Option<(f64, f64)>, params: usize, iterate: Func, smooth: Func, distance: Option<Func>) -> ModuleBuilder {
+    let critical_count = mb.start_func(&[], &[ValType::I32]);
+    mb.i32_const(1);
+    mb.end_func();
+    mb.export_func(critical_count, "critical_count");
+
+    let rest = if c.is_some() { 0 } else { 2 } + 2 * params;
+    let return_thing = export_pixel(&mut mb, "return_thing", c, params, iterate, false);
+    let smooth = export_pixel(&mut mb, "smooth", c, params, smooth, false);
+    export_tile(&mut mb, "render_tile", return_thing, false, rest);
+    export_tile(&mut mb, "render_tile_smooth", smooth, false, rest);
+    export_rgba_tile(&mut mb, lib, "render_rgba", palette, smooth, false, rest);
+    export_pixel(&mut mb, "orbit", c, params, iterate, true);
+    if let Some(distance) = distance {
+        export_pixel(&mut mb, "distance", c, params, distance, false);
+    }
+    mb
+}
+
+// name([k,] zx, zy[, cx, cy], params...) -> iterate(zx, zy, cx, cy, params...), with c
+// fixed if given
+fn export_pixel(mb: &mut ModuleBuilder, name: &str, c: Option<(f64, f64)>, params: usize, iterate: Func, takes_k: bool) -> Func {
+    let first = if takes_k { 1 } else { 0 };
+    let given = if c.is_some() { 2 } else { 4 } + 2 * params;
+    let func = mb.start_func(&vec![ValType::F64; first + given], &[ValType::F64]);
+    for i in first..first + given {
+        let param = mb.get_local_param(i);
+        mb.local_get(param);
+        // A fixed c goes between z and the parameters
+        if let (Some((cx, cy)), true) = (c, i == first + 1) {
+            mb.f64_const(cx);
+            mb.f64_const(cy);
+        }
+    }
+    mb.call(iterate);
+    mb.end_func();
+    mb.export_func(func, name);
+    func
+}
+
+// name(zx, zy, cx, cy, ...) -> the largest or smallest result of iterate over the starting
+// points
+fn export_over_starts(mb: &mut ModuleBuilder, name: &str, starts: Func, count: usize, order: &[usize], iterate: Func, largest: bool) -> Func {
+    let func = mb.start_func(&vec![ValType::F64; order.len()], &[ValType::F64]);
+    let inputs = inputs(mb, order, 0);
+    let (zs, status) = call_starts(mb, starts, count, &inputs);
+    mb.local_get(status);
+    mb.f64_neg();
+    mb.f64_const(if largest { 0.0 } else { f64::INFINITY });
+    for k in 0..count {
+        mb.local_get(zs[2 * k]);
+        mb.local_get(zs[2 * k + 1]);
+        for input in &inputs[2..] {
+            mb.local_get(*input);
+        }
+        mb.call(iterate);
+        if largest {
+            mb.f64_max();
+        } else {
+            mb.f64_min();
+        }
+    }
+    unless_solved(mb, status);
+    mb.end_func();
+    mb.export_func(func, name);
+    func
+}
+
+// name(ptr, width, height, x0, y0, dx, dy, rest...) calls pixel for each pixel of a tile,
+// row by row, and stores the results as f64s from ptr on. The pixel at column i and row j
+// is (x0 + i dx, y0 + j dy). rest are pixel's arguments after the pixel's own, and with
+// with_start there's a starting guess for the solver before it, TILE_START.
+fn export_tile(mb: &mut ModuleBuilder, name: &str, pixel: Func, with_start: bool, rest: usize) {
+    let (func, tile) = Tile::start(mb, rest, &[]);
+    let at = mb.add_local(ValType::I32);
+    mb.local_get(tile.ptr);
+    mb.local_set(at);
+    tile.for_each_pixel(mb, |mb| {
+        mb.local_get(at);
+        tile.call_pixel(mb, pixel, with_start);
+        mb.f64_store(0);
+        add_i32(mb, at, 8);
+    });
+    mb.end_func();
+    mb.export_func(func, name);
+}
+
+// name(ptr, width, height, x0, y0, dx, dy, rest...) colours each pixel of a tile, laid out
+// as for render_tile, by pixel's result and the palette, and stores them as RGBA bytes from
+// ptr on. Where no starting point was found there's a magenta and red check instead.
+// Memory is grown to fit the tile, and with a histogram the working space after it: the
+// tile's results as f64s, then HISTOGRAM_BINS bins, each holding the fraction of pixels
+// in the bins before it. The histogram is the tile's own, so tiles of the same image can
+// be coloured differently. Returns TILE_DONE, or TILE_NO_MEMORY if it can't have the
+// memory, before storing anything.
+fn export_rgba_tile(mb: &mut ModuleBuilder, lib: MathLib, name: &str, palette: &Palette, pixel: Func, with_start: bool, rest: usize) {
+    let colour = backend_colour(mb, lib, &palette.colours);
+    let (func, tile) = Tile::start(mb, rest, &[ValType::I32]);
+    // Bytes for each pixel and for the whole tile besides, at most, checked in f64 so that
+    // the i32 addresses worked out after can't wrap round
+    let (per_pixel, besides) = match palette.mode {
+        Mode::Smooth(_) => (4.0, 0.0),
+        Mode::Histogram => (12.0, 7.0 + 8.0 * HISTOGRAM_BINS as f64),
+    };
+    give_up_if(mb, |mb| {
+        for local in [tile.ptr, tile.width, tile.height].iter() {
+            mb.local_get(*local);
+            mb.f64_convert_i32_u();
+        }
+        mb.f64_mul();
+        mb.f64_const(per_pixel);
+        mb.f64_mul();
+        mb.f64_add();
+        mb.f64_const(besides);
+        mb.f64_add();
+        mb.f64_const(4294967296.0);
+        mb.f64_ge();
+    });
+    let end = mb.add_local(ValType::I32);
+    mb.local_get(tile.ptr);
+    mb.local_get(tile.width);
+    mb.local_get(tile.height);
+    mb.i32_mul();
+    mb.i32_const(2);
+    mb.i32_shl();
+    mb.i32_add();
+    mb.local_set(end);
+    match palette.mode {
+        Mode::Smooth(period) => {
+            grow_memory(mb, end);
+            let at = mb.add_local(ValType::I32);
+            let value = mb.add_local(ValType::F64);
+            mb.local_get(tile.ptr);
+            mb.local_set(at);
+            tile.for_each_pixel(mb, |mb| {
+                mb.local_get(at);
+                tile.call_pixel(mb, pixel, with_start);
+                mb.local_tee(value);
+                mb.f64_const(period);
+                mb.f64_div();
+                mb.call(colour);
+                tile.unless_failed(mb, value);
+                mb.i32_store(0);
+                add_i32(mb, at, 4);
+            });
+        }
+        Mode::Histogram => histogram_tile(mb, &tile, colour, pixel, with_start, end),
+    }
+    mb.i32_const(TILE_DONE);
+    mb.end_func();
+    mb.export_func(func, name);
+}
+
+// The rest of export_rgba_tile() for a histogram, with the RGBA bytes to go up to end.
+// The results are gathered first, and only coloured once all of them are known.
+fn histogram_tile(mb: &mut ModuleBuilder, tile: &Tile, colour: Func, pixel: Func, with_start: bool, end: Local) {
+    let (at, bin, results, bins, bins_end) = (mb.add_local(ValType::I32), mb.add_local(ValType::I32), mb.add_local(ValType::I32), mb.add_local(ValType::I32), mb.add_local(ValType::I32));
+    // Locals start at 0, as most, fewer and total have to
+    let (value, position, most, scale, fewer, total) = (mb.add_local(ValType::F64), mb.add_local(ValType::F64), mb.add_local(ValType::F64), mb.add_local(ValType::F64), mb.add_local(ValType::F64), mb.add_local(ValType::F64));
+    // The results start at the next multiple of 8 after the RGBA bytes
+    mb.local_get(end);
+    mb.i32_const(7);
+    mb.i32_add();
+    mb.i32_const(!7);
+    mb.i32_and();
+    mb.local_tee(results);
+    mb.local_get(tile.width);
+    mb.local_get(tile.height);
+    mb.i32_mul();
+    mb.i32_const(3);
+    mb.i32_shl();
+    mb.i32_add();
+    mb.local_tee(bins);
+    mb.i32_const(HISTOGRAM_BINS << 3);
+    mb.i32_add();
+    mb.local_set(bins_end);
+    grow_memory(mb, bins_end);
+
+    // The most is of the finite results, so that one infinity doesn't squash the rest
+    mb.local_get(results);
+    mb.local_set(at);
+    tile.for_each_pixel(mb, |mb| {
+        mb.local_get(at);
+        tile.call_pixel(mb, pixel, with_start);
+        mb.local_tee(value);
+        mb.f64_store(0);
+        mb.local_get(value);
+        mb.local_get(most);
+        mb.f64_max();
+        mb.local_get(most);
+        mb.local_get(value);
+        mb.f64_const(f64::INFINITY);
+        mb.f64_lt();
+        mb.select();
+        mb.local_set(most);
+        add_i32(mb, at, 8);
+    });
+    mb.f64_const((HISTOGRAM_BINS - 2) as f64);
+    mb.local_get(most);
+    mb.f64_const(1.0);
+    mb.f64_max();
+    mb.f64_div();
+    mb.local_set(scale);
+
+    for_each_address(mb, at, bins, bins_end, 8, |mb| {
+        mb.local_get(at);
+        mb.f64_const(0.0);
+        mb.f64_store(0);
+    });
+    for_each_address(mb, at, results, bins, 8, |mb| {
+        mb.start_block(BlockType::Empty);
+        mb.local_get(at);
+        mb.f64_load(0);
+        mb.local_tee(value);
+        mb.f64_const(0.0);
+        mb.f64_lt();
+        mb.br_if(0);
+        bin_position(mb, value, scale);
+        mb.local_set(position);
+        bin_address(mb, bins, position);
+        mb.local_tee(bin);
+        mb.local_get(bin);
+        mb.f64_load(0);
+        mb.f64_const(1.0);
+        mb.f64_add();
+        mb.f64_store(0);
+        mb.local_get(total);
+        mb.f64_const(1.0);
+        mb.f64_add();
+        mb.local_set(total);
+        mb.end_block();
+    });
+    // Each bin's count becomes the fraction of pixels in the bins before it
+    for_each_address(mb, at, bins, bins_end, 8, |mb| {
+        mb.local_get(at);
+        mb.f64_load(0);
+        mb.local_get(at);
+        mb.local_get(fewer);
+        mb.local_get(total);
+        mb.f64_div();
+        mb.f64_store(0);
+        mb.local_get(fewer);
+        mb.f64_add();
+        mb.local_set(fewer);
+    });
+
+    // The fraction for a result between two bins is blended from theirs
+    let rgba = mb.add_local(ValType::I32);
+    mb.local_get(tile.ptr);
+    mb.local_set(rgba);
+    mb.local_get(results);
+    mb.local_set(at);
+    tile.for_each_pixel(mb, |mb| {
+        mb.local_get(rgba);
+        mb.local_get(at);
+        mb.f64_load(0);
+        mb.local_set(value);
+        bin_position(mb, value, scale);
+        mb.local_set(position);
+        bin_address(mb, bins, position);
+        mb.local_tee(bin);
+        mb.f64_load(0);
+        mb.local_get(bin);
+        mb.f64_load(8);
+        mb.local_get(bin);
+        mb.f64_load(0);
+        mb.f64_sub();
+        mb.local_get(position);
+        mb.local_get(position);
+        mb.f64_floor();
+        mb.f64_sub();
+        mb.f64_mul();
+        mb.f64_add();
+        mb.call(colour);
+        tile.unless_failed(mb, value);
+        mb.i32_store(0);
+        add_i32(mb, rgba, 4);
+        add_i32(mb, at, 8);
+    });
+}
+
+// The parameters every tile export starts with, and the column and row being worked on
+struct Tile {
+    ptr: Local,
+    width: Local,
+    height: Local,
+    x0: Local,
+    y0: Local,
+    dx: Local,
+    dy: Local,
+    rest: usize,
+    i: Local,
+    j: Local,
+}
+
+impl Tile {
+    fn start(mb: &mut ModuleBuilder, rest: usize, results: &[ValType]) -> (Func, Tile) {
+        let mut types = vec![ValType::I32; 3];
+        types.extend(vec![ValType::F64; 4 + rest]);
+        let func = mb.start_func(&types, results);
+        let param = |k| mb.get_local_param(k);
+        let (ptr, width, height, x0, y0, dx, dy) = (param(0), param(1), param(2), param(3), param(4), param(5), param(6));
+        let (i, j) = (mb.add_local(ValType::I32), mb.add_local(ValType::I32));
+        (func, Tile { ptr, width, height, x0, y0, dx, dy, rest, i, j })
+    }
+
+    // Runs body for each pixel, row by row
+    fn for_each_pixel(&self, mb: &mut ModuleBuilder, mut body: impl FnMut(&mut ModuleBuilder)) {
+        count_up(mb, self.j, self.height, |mb| count_up(mb, self.i, self.width, &mut body));
+    }
+
+    // Pushes pixel's result for the current pixel
+    fn call_pixel(&self, mb: &mut ModuleBuilder, pixel: Func, with_start: bool) {
+        if with_start {
+            mb.f64_const(TILE_START.0);
+            mb.f64_const(TILE_START.1);
+        }
+        for (k, start, d) in [(self.i, self.x0, self.dx), (self.j, self.y0, self.dy)].iter() {
+            mb.local_get(*k);
+            mb.f64_convert_i32_u();
+            mb.local_get(*d);
+            mb.f64_mul();
+            mb.local_get(*start);
+            mb.f64_add();
+        }
+        for k in 0..self.rest {
+            let param = mb.get_local_param(7 + k);
+            mb.local_get(param);
+        }
+        mb.call(pixel);
+    }
+
+    // With RGBA bytes on the stack, swaps them for the check if value is negative, as
+    // when no starting point was found
+    fn unless_failed(&self, mb: &mut ModuleBuilder, value: Local) {
+        mb.i32_const(0xffff_00ff);
+        mb.i32_const(0xff00_00ff);
+        mb.local_get(self.i);
+        mb.local_get(self.j);
+        mb.i32_add();
+        mb.i32_const(4);
+        mb.i32_and();
+        mb.i32_eqz();
+        mb.select();
+        mb.local_get(value);
+        mb.f64_const(0.0);
+        mb.f64_ge();
+        mb.select();
+    }
+}
+
+// Runs body with k going from 0 up to, but not including, n
+fn count_up(mb: &mut ModuleBuilder, k: Local, n: Local, mut body: impl FnMut(&mut ModuleBuilder)) {
+    mb.i32_const(0);
+    mb.local_set(k);
+    mb.start_block(BlockType::Empty);
+    mb.start_loop(BlockType::Empty);
+    mb.local_get(k);
+    mb.local_get(n);
+    mb.i32_lt_u();
+    mb.i32_eqz();
+    mb.br_if(1);
+    body(mb);
+    add_i32(mb, k, 1);
+    mb.br(0);
+    mb.end_loop();
+    mb.end_block();
+}
+
+// Runs body with at going from start up to, but not including, end, step bytes at a time
+fn for_each_address(mb: &mut ModuleBuilder, at: Local, start: Local, end: Local, step: u32, mut body: impl FnMut(&mut ModuleBuilder)) {
+    mb.local_get(start);
+    mb.local_set(at);
+    mb.start_block(BlockType::Empty);
+    mb.start_loop(BlockType::Empty);
+    mb.local_get(at);
+    mb.local_get(end);
+    mb.i32_lt_u();
+    mb.i32_eqz();
+    mb.br_if(1);
+    body(mb);
+    add_i32(mb, at, step);
+    mb.br(0);
+    mb.end_loop();
+    mb.end_block();
+}
+
+fn add_i32(mb: &mut ModuleBuilder, local: Local, x: u32) {
+    mb.local_get(local);
+    mb.i32_const(x);
+    mb.i32_add();
+    mb.local_set(local);
+}
+
+// Pushes value times scale, which is from 0 to HISTOGRAM_BINS - 2 for the results counted,
+// kept there for the rest. NaN stays NaN, which goes in the first bin.
+fn bin_position(mb: &mut ModuleBuilder, value: Local, scale: Local) {
+    mb.local_get(value);
+    mb.local_get(scale);
+    mb.f64_mul();
+    mb.f64_const((HISTOGRAM_BINS - 2) as f64);
+    mb.f64_min();
+}
+
+// Pushes where the histogram bin for the whole part of position is
+fn bin_address(mb: &mut ModuleBuilder, bins: Local, position: Local) {
+    mb.local_get(bins);
+    mb.local_get(position);
+    mb.i32_trunc_sat_f64_u();
+    mb.i32_const(3);
+    mb.i32_shl();
+    mb.i32_add();
+}
+
+// Returns TILE_NO_MEMORY from the tile export if the condition cond pushes holds
+fn give_up_if(mb: &mut ModuleBuilder, cond: impl FnOnce(&mut ModuleBuilder)) {
+    mb.start_block(BlockType::Empty);
+    cond(mb);
+    mb.i32_eqz();
+    mb.br_if(0);
+    mb.i32_const(TILE_NO_MEMORY);
+    mb.ret();
+    mb.end_block();
+}
 
-    mb.i32_const(10);
+// Grows memory, if it has to, to hold the bytes before end, giving up on the tile if it
+// can't. Sizes are compared in 64 KiB pages, since 4 GiB of bytes doesn't fit in an i32.
+fn grow_memory(mb: &mut ModuleBuilder, end: Local) {
+    // end / 2^16 rounded up, which can't overflow as adding 0xffff first could
+    let pages = |mb: &mut ModuleBuilder| {
+        mb.local_get(end);
+        mb.i32_const(16);
+        mb.i32_shr_u();
+        mb.local_get(end);
+        mb.i32_const(0xffff);
+        mb.i32_and();
+        mb.i32_eqz();
+        mb.i32_eqz();
+        mb.i32_add();
+    };
+    mb.start_block(BlockType::Empty);
+    mb.memory_size();
+    pages(mb);
     mb.i32_lt_u();
+    mb.i32_eqz();
     mb.br_if(0);
+    give_up_if(mb, |mb| {
+        pages(mb);
+        mb.memory_size();
+        mb.i32_sub();
+        mb.memory_grow();
+        mb.i32_const(-1i32 as u32);
+        mb.i32_eq();
+    });
+    mb.end_block();
+}
+
+// The current function's parameters from first on, in the order iterate() takes them
+fn inputs(mb: &ModuleBuilder, order: &[usize], first: usize) -> Vec<Local> {
+    order.iter().map(|i| mb.get_local_param(first + i)).collect()
+}
+
+// Calls starts with inputs and keeps the starting points and the status in new locals
+fn call_starts(mb: &mut ModuleBuilder, starts: Func, count: usize, inputs: &[Local]) -> (Vec<Local>, Local) {
+    for input in inputs {
+        mb.local_get(*input);
+    }
+    mb.call(starts);
+    let zs: Vec<Local> = (0..2 * count).map(|_| mb.add_local(ValType::F64)).collect();
+    let status = mb.add_local(ValType::F64);
+    mb.local_set(status);
+    for local in zs.iter().rev() {
+        mb.local_set(*local);
+    }
+    (zs, status)
+}
+
+// With minus the status and then the iteration count on the stack, keeps the count only
+// if the starting points were found
+fn unless_solved(mb: &mut ModuleBuilder, status: Local) {
+    mb.local_get(status);
+    mb.f64_const(Status::Solved.code());
+    mb.f64_gt();
+    mb.select();
+}
+
+fn backend_solve(mut mb: ModuleBuilder, lib: MathLib, expr: &Expr, inputs: &Inputs, solver: &Solver) -> Result<(ModuleBuilder, Func), BackendErr> {
+    let solve = mb.start_func(&input_types(inputs), &[ValType::F64, ValType::F64, ValType::F64]);
+
+    let zx = mb.get_local_param(0);
+    let zy = mb.get_local_param(1);
+    let iter = mb.add_local(ValType::F64);
+    let status = mb.add_local(ValType::F64);
+
+    mb.f64_const(0.0);
+    mb.local_set(iter);
+
+    mb.start_block(BlockType::Empty);
+    mb.start_loop(BlockType::Empty);
+    let mut fc = FuncContext::new(mb, lib);
+    fc.bind_inputs(inputs);
+    fc.env.insert("z".to_owned(), Structure::Complex(fc.dag.f64_input(zx), fc.dag.f64_input(zy)));
+    let last = fc.dag.f64_ge(fc.dag.f64_input(iter), fc.dag.f64_const(solver.steps as f64 - 1.0));
+    let newz = fc.newton_step(expr, solver, last).map_err(|e| e.in_field(Field::Iteration))?;
+    let mut mb = fc.done(&newz);
+    mb.local_set(status);
+    mb.local_set(zy);
+    mb.local_set(zx);
+
+    mb.local_get(iter);
+    mb.f64_const(1.0);
+    mb.f64_add();
+    mb.local_set(iter);
+
+    mb.local_get(status);
+    mb.f64_const(0.0);
+    mb.f64_ge();
+    mb.br_if(1);
+    mb.br(0);
 
     mb.end_loop();
+    mb.end_block();
 
     mb.local_get(zx);
     mb.local_get(zy);
+    mb.local_get(status);
 
     mb.end_func();
-    (mb, solve)
+    Ok((mb, solve))
 }
 
 #[derive(Clone)]
@@ -148,56 +1350,97 @@ enum Structure {
 
 struct FuncContext {
     mb: ModuleBuilder,
+    lib: MathLib,
     dag: Dag,
     env: HashMap<String, Structure>,
+    funcs: HashMap<String, Rc<Closure>>,
+    // Set while evaluating a condition, which never needs derivatives
+    values_only: bool,
+}
+
+// A def along with the variables and functions in scope where it was written.
+// Calls are inlined, so every call site gets its own copy of the body.
+struct Closure {
+    def: FuncDef,
+    env: HashMap<String, Structure>,
+    funcs: HashMap<String, Rc<Closure>>,
 }
 
 impl Structure {
-    fn cx(&self) -> DagNode {
+    fn cx(&self) -> Result<DagNode, ExprErr> {
         match self {
-            Structure::Complex(x, _) => *x,
-            _ => panic!(),
+            Structure::Complex(x, _) => Ok(*x),
+            _ => Err(ExprErr::ExpectedComplex),
         }
     }
-    fn cy(&self) -> DagNode {
+    fn cy(&self) -> Result<DagNode, ExprErr> {
         match self {
-            Structure::Complex(_, y) => *y,
-            _ => panic!(),
+            Structure::Complex(_, y) => Ok(*y),
+            _ => Err(ExprErr::ExpectedComplex),
         }
     }
-    fn as_real_f64(&self) -> DagNode {
-        if !self.cy().is_const_zero() {
-            panic!();
+    fn as_real_f64(&self) -> Result<DagNode, ExprErr> {
+        if !self.cy()?.is_const_zero() {
+            return Err(ExprErr::ExpectedReal);
         }
         self.cx()
     }
-    fn boolean(&self) -> DagNode {
+    fn boolean(&self) -> Result<DagNode, ExprErr> {
+        match self {
+            Structure::Bool(b) => Ok(*b),
+            _ => Err(ExprErr::ExpectedBool),
+        }
+    }
+    fn is_const_zero(&self) -> bool {
+        match self {
+            Structure::Complex(x, y) => x.is_const_zero() && y.is_const_zero(),
+            _ => false,
+        }
+    }
+    // Only up to MAX_POWI either way, so that n - 2 and -n can't overflow
+    fn as_const_int(&self) -> Option<i32> {
+        match self {
+            Structure::Complex(x, y) if y.is_const_zero() => {
+                let x = x.as_const_f64()?;
+                if x.fract() == 0.0 && x.abs() <= MAX_POWI as f64 {
+                    Some(x as i32)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+    fn value(&self) -> Structure {
         match self {
-            Structure::Bool(b) => *b,
-            _ => panic!(),
+            Structure::CxDeriv(xs) => Structure::Complex(xs[0], xs[1]),
+            s => s.clone(),
         }
     }
-    fn derivs(&self) -> [Structure;3] {
+    fn derivs(&self) -> Result<[Structure;3], ExprErr> {
         match self {
-            Structure::CxDeriv(xs) => [
+            Structure::CxDeriv(xs) => Ok([
                 Structure::Complex(xs[0], xs[1]),
                 Structure::Complex(xs[2], xs[3]),
                 Structure::Complex(xs[4], xs[5]),
-            ],
-            _ => panic!(),
+            ]),
+            _ => Err(ExprErr::ExpectedComplex),
         }
     }
-    fn deriv(d0: Structure, d1: Structure, d2: Structure) -> Self {
-        Structure::CxDeriv([d0.cx(), d0.cy(), d1.cx(), d1.cy(), d2.cx(), d2.cy()])
+    fn deriv(d0: Structure, d1: Structure, d2: Structure) -> Result<Self, ExprErr> {
+        Ok(Structure::CxDeriv([d0.cx()?, d0.cy()?, d1.cx()?, d1.cy()?, d2.cx()?, d2.cy()?]))
     }
 }
 
 impl FuncContext {
-    fn new(mb: ModuleBuilder) -> Self {
+    fn new(mb: ModuleBuilder, lib: MathLib) -> Self {
         FuncContext {
             mb,
+            lib,
             dag: Dag::default(),
             env: HashMap::new(),
+            funcs: HashMap::new(),
+            values_only: false,
         }
     }
 
@@ -206,157 +1449,753 @@ impl FuncContext {
         self.mb
     }
 
+    // One damped Newton step towards a zero of f', and the status after it: Status::Searching
+    // to keep going, or why to stop. last says whether the step cap has been reached. f' and
+    // f'' are worked out by diff as formulas, then evaluated like any other.
+    fn newton_step(&mut self, expr: &Expr, solver: &Solver, last: DagNode) -> Result<[Effect;3], SpannedErr> {
+        let at = |e: ExprErr| e.at(expr.span());
+        let f1 = diff::derivative(expr, "z")?;
+        let f2 = diff::derivative(&f1, "z")?;
+        let z = self.lookup("z").map_err(at)?;
+        let f1 = self.do_expr(&f1)?;
+        let f2 = self.do_expr(&f2)?;
+        let d1 = self.abs2(&f1).map_err(at)?;
+        let d2 = self.abs2(&f2).map_err(at)?;
+        let step = self.cx_div(&f1, &f2).map_err(at)?;
+        let step = self.cx_scale(&step, solver.damping).map_err(at)?;
+        let newz = self.cx_sub(&z, &step).map_err(at)?;
+
+        let big = self.dag.f64_const(f64::MAX);
+        let zero = self.dag.f64_zero();
+        // Exactly on a critical point, the step would be 0/0
+        let critical = self.dag.f64_eq(d1, zero);
+        // Comparisons with NaN are false, so this also catches 0/0 inside f
+        let d1_finite = self.dag.f64_lt(d1, big);
+        let d2_finite = self.dag.f64_lt(d2, big);
+        let finite = self.dag.i32_and(d1_finite, d2_finite);
+        let pole = self.dag.i32_eqz(finite);
+        let flat = self.dag.f64_eq(d2, zero);
+        let nn = self.abs2(&newz).map_err(at)?;
+        let bounded = self.dag.f64_lt(nn, self.dag.f64_const(1e300));
+        let diverged = self.dag.i32_eqz(bounded);
+        // Converged once the step is small next to z, or next to 1 near 0
+        let ss = self.abs2(&step).map_err(at)?;
+        let zz = self.abs2(&z).map_err(at)?;
+        let scale = self.dag.f64_max(zz, self.dag.f64_one());
+        let tolerance = self.dag.f64_const(solver.tolerance * solver.tolerance);
+        let limit = self.dag.f64_mul(scale, tolerance);
+        let converged = self.dag.f64_le(ss, limit);
+
+        // From the least to the most important reason to stop
+        let mut status = self.dag.f64_const(Status::Searching.code());
+        for &(reason, cond) in &[(Status::NotConverged, last), (Status::Solved, converged), (Status::Diverged, diverged), (Status::Flat, flat), (Status::Pole, pole), (Status::Solved, critical)] {
+            status = self.dag.select(self.dag.f64_const(reason.code()), status, cond);
+        }
+        // Only a step that went somewhere is taken
+        let stuck = [pole, flat, diverged].iter().fold(critical, |a, b| self.dag.i32_or(a, *b));
+        let x = self.dag.select(z.cx().map_err(at)?, newz.cx().map_err(at)?, stuck);
+        let y = self.dag.select(z.cy().map_err(at)?, newz.cy().map_err(at)?, stuck);
+        Ok([Effect(EffectType::Push, x), Effect(EffectType::Push, y), Effect(EffectType::Push, status)])
+    }
+
     fn dconst(&self, x: DagNode, y: DagNode) -> Structure {
         let zero = self.dag.f64_zero();
         Structure::CxDeriv([x,y,zero,zero,zero,zero])
     }
 
-    fn do_expr_deriv(&mut self, expr: &Expr) -> Structure {
+    // Binds i, c and the parameters. c and those passed are the function's own, which
+    // start (zx, zy, cx, cy) and go on in pairs; the declared ones are globals.
+    fn bind_inputs(&mut self, inputs: &Inputs) {
+        self.env.insert("i".to_owned(), Structure::Complex(self.dag.f64_zero(), self.dag.f64_one()));
+        for (name, x, y) in self.input_values(inputs) {
+            self.env.insert(name, Structure::Complex(x, y));
+        }
+    }
+
+    // The same with derivatives, for differentiating with respect to wrt instead of z, or
+    // when wrt is None, with respect to z or nothing
+    fn bind_inputs_deriv(&mut self, inputs: &Inputs, wrt: Option<&str>) {
+        let (zero, one) = (self.dag.f64_zero(), self.dag.f64_one());
+        self.env.insert("i".to_owned(), self.dconst(zero, one));
+        for (name, x, y) in self.input_values(inputs) {
+            let d = if wrt == Some(name.as_str()) { one } else { zero };
+            self.env.insert(name, Structure::CxDeriv([x, y, d, zero, zero, zero]));
+        }
+    }
+
+    fn input_values(&self, inputs: &Inputs) -> Vec<(String, DagNode, DagNode)> {
+        let passed = std::iter::once("c").chain(inputs.passed.iter().map(String::as_str)).enumerate().map(|(k, name)| {
+            let (x, y) = (self.mb.get_local_param(2 + 2 * k), self.mb.get_local_param(3 + 2 * k));
+            (name.to_owned(), self.dag.f64_input(x), self.dag.f64_input(y))
+        });
+        let declared = inputs.globals.iter().map(|(name, x, y)| (name.clone(), self.dag.f64_global(*x), self.dag.f64_global(*y)));
+        passed.chain(declared).collect()
+    }
+
+    fn do_expr_deriv(&mut self, expr: &Expr) -> Result<Structure, SpannedErr> {
         match expr {
-            Expr::F64(x) => self.dconst(self.dag.f64_const(*x), self.dag.f64_zero()),
-            Expr::Var(z) => self.env.get(z).unwrap().clone(),
-            Expr::Call(f, args) => {
-                let d:Vec<_> = args.iter().map(|arg|self.do_expr_deriv(arg).derivs()).collect();
-                match &f as &str {
-                    "+" => {
-                        let z0 = self.cx_add(&d[0][0], &d[1][0]);
-                        let z1 = self.cx_add(&d[0][1], &d[1][1]);
-                        let z2 = self.cx_add(&d[0][2], &d[1][2]);
-                        Structure::deriv(z0, z1, z2)
-                    }
-                    "-" => {
-                        let z0 = self.cx_sub(&d[0][0], &d[1][0]);
-                        let z1 = self.cx_sub(&d[0][1], &d[1][1]);
-                        let z2 = self.cx_sub(&d[0][2], &d[1][2]);
-                        Structure::deriv(z0, z1, z2)
-                    }
-                    "*" => {
-                        let [a,b,c] = d[0].clone();
-                        let [d,e,f] = d[1].clone();
-                        let ad = self.cx_mul(&a,&d);
-                        let ae = self.cx_mul(&a,&e);
-                        let bd = self.cx_mul(&b,&d);
-                        let cd = self.cx_mul(&c,&d);
-                        let be = self.cx_mul(&b,&e);
-                        let af = self.cx_mul(&a,&f);
-                        let z1 = self.cx_add(&ae, &bd);
-                        let be2 = self.cx_add(&be, &be);
-                        let be2_af = self.cx_add(&be2, &af);
-                        let z2 = self.cx_add(&cd, &be2_af);
-                        Structure::deriv(ad, z1, z2)
-                    }
-                    "/" => {
-                        let [a,b,c] = d[0].clone();
-                        let [d,e,f] = d[1].clone();
-                        let z0 = self.cx_div(&a, &d);
-
-                        let b_d = self.cx_div(&b, &d);
-                        let ae = self.cx_mul(&a, &e);
-                        let dd = self.cx_mul(&d, &d);
-                        let ae_dd = self.cx_div(&ae, &dd);
-                        let z1 = self.cx_sub(&b_d, &ae_dd);
-
-                        let c_d = self.cx_div(&c, &d);
-                        let af = self.cx_mul(&a, &f);
-                        let be = self.cx_mul(&a, &e);
-                        let be2 = self.cx_add(&be, &be);
-                        let af_plus_2be = self.cx_add(&af, &be2);
-                        let middle = self.cx_div(&af_plus_2be, &dd);
-                        let aee = self.cx_mul(&ae, &e);
-                        let aee2 = self.cx_add(&aee, &aee);
-                        let ddd = self.cx_mul(&dd, &d);
-                        let aee2_ddd = self.cx_div(&aee2, &ddd);
-                        let t = self.cx_sub(&c_d, &middle);
-                        let z2 = self.cx_add(&t, &aee2_ddd);
-                        Structure::deriv(z0, z1, z2)
+            Expr::F64(x, _) => Ok(self.dconst(self.dag.f64_const(*x), self.dag.f64_zero())),
+            Expr::Complex(x, y, _) => Ok(self.dconst(self.dag.f64_const(*x), self.dag.f64_const(*y))),
+            Expr::Var(z, span) => self.lookup(z).map_err(|e| e.at(*span)),
+            Expr::Call(f, args, span) if self.funcs.contains_key(f) => {
+                let structs = args.iter().map(|arg|self.do_binding_deriv(arg)).collect::<Result<Vec<_>,_>>()?;
+                self.inline(f, structs, *span, true)
+            }
+            Expr::Call(f, args, span) => {
+                let d = args.iter().map(|arg|self.do_expr_deriv(arg)?.derivs().map_err(|e| e.at(arg.span()))).collect::<Result<Vec<_>,_>>()?;
+                self.do_call_deriv(f, &d).map_err(|e| e.at(*span))
+            }
+            Expr::If(cond, a, b, span) => {
+                let cond = self.do_condition(cond)?;
+                let a = self.do_expr_deriv(a)?;
+                let b = self.do_expr_deriv(b)?;
+                self.select(cond, &a, &b).map_err(|e| e.at(*span))
+            }
+            Expr::Let(name, value, body, _) => {
+                let value = self.do_binding_deriv(value)?;
+                let shadowed = self.env.insert(name.clone(), value);
+                let result = self.do_expr_deriv(body);
+                self.unbind(name, shadowed);
+                result
+            }
+            Expr::Def(def, body, _) => {
+                let shadowed = self.define(def);
+                let result = self.do_expr_deriv(body);
+                self.undefine(&def.name, shadowed);
+                result
+            }
+        }
+    }
+
+    // A let value or def argument, which may be a condition rather than a number.
+    // Conditions have no derivatives, so those are bound by value alone.
+    fn do_binding_deriv(&mut self, expr: &Expr) -> Result<Structure, SpannedErr> {
+        match self.do_expr_deriv(expr) {
+            Ok(value) => Ok(value),
+            Err(e) => match self.do_values(expr) {
+                Ok(b @ Structure::Bool(_)) => Ok(b),
+                _ => Err(e),
+            },
+        }
+    }
+
+    // Conditions only need values, so look variables up with their derivatives stripped
+    fn do_condition(&mut self, expr: &Expr) -> Result<DagNode, SpannedErr> {
+        self.do_values(expr).and_then(|c| c.boolean().map_err(|e| e.at(expr.span())))
+    }
+
+    fn do_values(&mut self, expr: &Expr) -> Result<Structure, SpannedErr> {
+        let values_only = std::mem::replace(&mut self.values_only, true);
+        let result = self.do_expr(expr);
+        self.values_only = values_only;
+        result
+    }
+
+    fn define(&mut self, def: &FuncDef) -> Option<Rc<Closure>> {
+        let closure = Closure {
+            def: def.clone(),
+            env: self.env.clone(),
+            funcs: self.funcs.clone(),
+        };
+        self.funcs.insert(def.name.clone(), Rc::new(closure))
+    }
+
+    fn undefine(&mut self, name: &str, shadowed: Option<Rc<Closure>>) {
+        match shadowed {
+            Some(closure) => self.funcs.insert(name.to_owned(), closure),
+            None => self.funcs.remove(name),
+        };
+    }
+
+    // Evaluates the body of a def in its own scope, with the parameters bound to args
+    fn inline(&mut self, f: &str, args: Vec<Structure>, span: Span, deriv: bool) -> Result<Structure, SpannedErr> {
+        let closure = self.funcs[f].clone();
+        let def = &closure.def;
+        check_arg_count(f, def.params.len(), args.len()).map_err(|e| ExprErr::from(e).at(span))?;
+        let env = std::mem::replace(&mut self.env, closure.env.clone());
+        let funcs = std::mem::replace(&mut self.funcs, closure.funcs.clone());
+        for (param, arg) in def.params.iter().zip(args) {
+            self.env.insert(param.clone(), arg);
+        }
+        let result = if deriv { self.do_expr_deriv(&def.body) } else { self.do_expr(&def.body) };
+        self.env = env;
+        self.funcs = funcs;
+        result
+    }
+
+    fn do_call_deriv(&mut self, f: &str, d: &[[Structure;3]]) -> Result<Structure, ExprErr> {
+        check_arity(f, d.len())?;
+        Ok(match f {
+            "+" => {
+                let z0 = self.cx_add(&d[0][0], &d[1][0])?;
+                let z1 = self.cx_add(&d[0][1], &d[1][1])?;
+                let z2 = self.cx_add(&d[0][2], &d[1][2])?;
+                Structure::deriv(z0, z1, z2)?
+            }
+            "-" => {
+                let z0 = self.cx_sub(&d[0][0], &d[1][0])?;
+                let z1 = self.cx_sub(&d[0][1], &d[1][1])?;
+                let z2 = self.cx_sub(&d[0][2], &d[1][2])?;
+                Structure::deriv(z0, z1, z2)?
+            }
+            "*" => {
+                let [a,b,c] = d[0].clone();
+                let [d,e,f] = d[1].clone();
+                let ad = self.cx_mul(&a,&d)?;
+                let ae = self.cx_mul(&a,&e)?;
+                let bd = self.cx_mul(&b,&d)?;
+                let cd = self.cx_mul(&c,&d)?;
+                let be = self.cx_mul(&b,&e)?;
+                let af = self.cx_mul(&a,&f)?;
+                let z1 = self.cx_add(&ae, &bd)?;
+                let be2 = self.cx_add(&be, &be)?;
+                let be2_af = self.cx_add(&be2, &af)?;
+                let z2 = self.cx_add(&cd, &be2_af)?;
+                Structure::deriv(ad, z1, z2)?
+            }
+            "/" => {
+                let [a,b,c] = d[0].clone();
+                let [d,e,f] = d[1].clone();
+                let z0 = self.cx_div(&a, &d)?;
+
+                let b_d = self.cx_div(&b, &d)?;
+                let ae = self.cx_mul(&a, &e)?;
+                let dd = self.cx_mul(&d, &d)?;
+                let ae_dd = self.cx_div(&ae, &dd)?;
+                let z1 = self.cx_sub(&b_d, &ae_dd)?;
+
+                let c_d = self.cx_div(&c, &d)?;
+                let af = self.cx_mul(&a, &f)?;
+                let be = self.cx_mul(&b, &e)?;
+                let be2 = self.cx_add(&be, &be)?;
+                let af_plus_2be = self.cx_add(&af, &be2)?;
+                let middle = self.cx_div(&af_plus_2be, &dd)?;
+                let aee = self.cx_mul(&ae, &e)?;
+                let aee2 = self.cx_add(&aee, &aee)?;
+                let ddd = self.cx_mul(&dd, &d)?;
+                let aee2_ddd = self.cx_div(&aee2, &ddd)?;
+                let t = self.cx_sub(&c_d, &middle)?;
+                let z2 = self.cx_add(&t, &aee2_ddd)?;
+                Structure::deriv(z0, z1, z2)?
+            }
+            "neg" => {
+                let z0 = self.cx_neg(&d[0][0])?;
+                let z1 = self.cx_neg(&d[0][1])?;
+                let z2 = self.cx_neg(&d[0][2])?;
+                Structure::deriv(z0, z1, z2)?
+            }
+            "^" | "pow" => {
+                if !d[1][1].is_const_zero() || !d[1][2].is_const_zero() {
+                    // u^v = exp(v log u), so with w = v log u the derivatives
+                    // are u^v w' and u^v (w'' + w'^2)
+                    let [u,du,ddu] = d[0].clone();
+                    let [v,dv,ddv] = d[1].clone();
+                    let z0 = self.cx_pow(&u, &v)?;
+                    let l0 = self.cx_log(&u)?;
+                    let l1 = self.cx_div(&du, &u)?;
+                    let ddu_u = self.cx_div(&ddu, &u)?;
+                    let l1_l1 = self.cx_mul(&l1, &l1)?;
+                    let l2 = self.cx_sub(&ddu_u, &l1_l1)?;
+                    let dv_l0 = self.cx_mul(&dv, &l0)?;
+                    let v_l1 = self.cx_mul(&v, &l1)?;
+                    let w1 = self.cx_add(&dv_l0, &v_l1)?;
+                    let ddv_l0 = self.cx_mul(&ddv, &l0)?;
+                    let dv_l1 = self.cx_mul(&dv, &l1)?;
+                    let two_dv_l1 = self.cx_scale(&dv_l1, 2.0)?;
+                    let v_l2 = self.cx_mul(&v, &l2)?;
+                    let t = self.cx_add(&ddv_l0, &two_dv_l1)?;
+                    let w2 = self.cx_add(&t, &v_l2)?;
+                    let z1 = self.cx_mul(&z0, &w1)?;
+                    let w1_w1 = self.cx_mul(&w1, &w1)?;
+                    let w2_w1_w1 = self.cx_add(&w2, &w1_w1)?;
+                    let z2 = self.cx_mul(&z0, &w2_w1_w1)?;
+                    return Structure::deriv(z0, z1, z2);
+                }
+                let n = match d[1][0].as_const_int() {
+                    Some(n) => n,
+                    None => {
+                        // d/du u^a = a u^a / u
+                        let [u,du,ddu] = d[0].clone();
+                        let a = d[1][0].clone();
+                        let z0 = self.cx_pow(&u, &a)?;
+                        let a_z0 = self.cx_mul(&a, &z0)?;
+                        let f1 = self.cx_div(&a_z0, &u)?;
+                        let one = self.cx_one();
+                        let a_1 = self.cx_sub(&a, &one)?;
+                        let a_1_f1 = self.cx_mul(&a_1, &f1)?;
+                        let f2 = self.cx_div(&a_1_f1, &u)?;
+                        return self.chain(&[u,du,ddu], z0, f1, f2);
                     }
-                    "neg" => {
-                        let z0 = self.cx_neg(&d[0][0]);
-                        let z1 = self.cx_neg(&d[0][1]);
-                        let z2 = self.cx_neg(&d[0][2]);
-                        Structure::deriv(z0, z1, z2)
+                };
+                match n {
+                    0 => self.dconst(self.dag.f64_one(), self.dag.f64_zero()),
+                    1 => Structure::deriv(d[0][0].clone(), d[0][1].clone(), d[0][2].clone())?,
+                    _ => {
+                        let [u,du,ddu] = d[0].clone();
+                        let p2 = self.cx_powi(&u, n - 2)?;
+                        let p1 = self.cx_mul(&p2, &u)?;
+                        let z0 = self.cx_mul(&p1, &u)?;
+                        let p1_du = self.cx_mul(&p1, &du)?;
+                        let z1 = self.cx_scale(&p1_du, n as f64)?;
+                        let du_du = self.cx_mul(&du, &du)?;
+                        let p2_du_du = self.cx_mul(&p2, &du_du)?;
+                        let t0 = self.cx_scale(&p2_du_du, (n as f64) * (n as f64 - 1.0))?;
+                        let p1_ddu = self.cx_mul(&p1, &ddu)?;
+                        let t1 = self.cx_scale(&p1_ddu, n as f64)?;
+                        let z2 = self.cx_add(&t0, &t1)?;
+                        Structure::deriv(z0, z1, z2)?
                     }
-                    _ => panic!()
                 }
             }
-        }
+            "exp" => {
+                let e = self.cx_exp(&d[0][0])?;
+                self.chain(&d[0], e.clone(), e.clone(), e)?
+            }
+            "log" => {
+                let z0 = self.cx_log(&d[0][0])?;
+                let one = self.cx_one();
+                let f1 = self.cx_div(&one, &d[0][0])?;
+                let f1_f1 = self.cx_mul(&f1, &f1)?;
+                let f2 = self.cx_neg(&f1_f1)?;
+                self.chain(&d[0], z0, f1, f2)?
+            }
+            "sin" => {
+                let s = self.cx_sin(&d[0][0])?;
+                let c = self.cx_cos(&d[0][0])?;
+                let minus_s = self.cx_neg(&s)?;
+                self.chain(&d[0], s, c, minus_s)?
+            }
+            "cos" => {
+                let s = self.cx_sin(&d[0][0])?;
+                let c = self.cx_cos(&d[0][0])?;
+                let minus_s = self.cx_neg(&s)?;
+                let minus_c = self.cx_neg(&c)?;
+                self.chain(&d[0], c, minus_s, minus_c)?
+            }
+            "sinh" => {
+                let s = self.cx_sinh(&d[0][0])?;
+                let c = self.cx_cosh(&d[0][0])?;
+                self.chain(&d[0], s.clone(), c, s)?
+            }
+            "cosh" => {
+                let s = self.cx_sinh(&d[0][0])?;
+                let c = self.cx_cosh(&d[0][0])?;
+                self.chain(&d[0], c.clone(), s, c)?
+            }
+            "sqrt" => {
+                // f' = 1/(2 sqrt u), f'' = -f'/(2u)
+                let z0 = self.cx_sqrt(&d[0][0])?;
+                let two_z0 = self.cx_scale(&z0, 2.0)?;
+                let one = self.cx_one();
+                let f1 = self.cx_div(&one, &two_z0)?;
+                let f1_u = self.cx_div(&f1, &d[0][0])?;
+                let f2 = self.cx_scale(&f1_u, -0.5)?;
+                self.chain(&d[0], z0, f1, f2)?
+            }
+            _ => return Err(ExprErr::NotDifferentiable(f.to_owned())),
+        })
+    }
+
+    // Derivatives of f(u) given f, f' and f'' evaluated at u
+    fn chain(&mut self, u: &[Structure;3], f0: Structure, f1: Structure, f2: Structure) -> Result<Structure, ExprErr> {
+        let z1 = self.cx_mul(&f1, &u[1])?;
+        let du_du = self.cx_mul(&u[1], &u[1])?;
+        let f2_du_du = self.cx_mul(&f2, &du_du)?;
+        let f1_ddu = self.cx_mul(&f1, &u[2])?;
+        let z2 = self.cx_add(&f2_du_du, &f1_ddu)?;
+        Structure::deriv(f0, z1, z2)
     }
 
-    fn cx_add(&mut self, a: &Structure, b: &Structure) -> Structure {
-        let x = self.dag.f64_add(a.cx(), b.cx());
-        let y = self.dag.f64_add(a.cy(), b.cy());
-        Structure::Complex(x, y)
+    fn cx_add(&mut self, a: &Structure, b: &Structure) -> Result<Structure, ExprErr> {
+        let x = self.dag.f64_add(a.cx()?, b.cx()?);
+        let y = self.dag.f64_add(a.cy()?, b.cy()?);
+        Ok(Structure::Complex(x, y))
     }
 
-    fn cx_sub(&mut self, a: &Structure, b: &Structure) -> Structure {
-        let x = self.dag.f64_sub(a.cx(), b.cx());
-        let y = self.dag.f64_sub(a.cy(), b.cy());
-        Structure::Complex(x, y)
+    fn cx_sub(&mut self, a: &Structure, b: &Structure) -> Result<Structure, ExprErr> {
+        let x = self.dag.f64_sub(a.cx()?, b.cx()?);
+        let y = self.dag.f64_sub(a.cy()?, b.cy()?);
+        Ok(Structure::Complex(x, y))
     }
 
-    fn cx_mul(&mut self, z0: &Structure, z1: &Structure) -> Structure {
-        let x0_x1 = self.dag.f64_mul(z0.cx(), z1.cx());
-        let x0_y1 = self.dag.f64_mul(z0.cx(), z1.cy());
-        let x1_y0 = self.dag.f64_mul(z1.cx(), z0.cy());
-        let y0_y1 = self.dag.f64_mul(z0.cy(), z1.cy());
+    fn cx_mul(&mut self, z0: &Structure, z1: &Structure) -> Result<Structure, ExprErr> {
+        let x0_x1 = self.dag.f64_mul(z0.cx()?, z1.cx()?);
+        let x0_y1 = self.dag.f64_mul(z0.cx()?, z1.cy()?);
+        let x1_y0 = self.dag.f64_mul(z1.cx()?, z0.cy()?);
+        let y0_y1 = self.dag.f64_mul(z0.cy()?, z1.cy()?);
         let x = self.dag.f64_sub(x0_x1, y0_y1);
         let y = self.dag.f64_add(x0_y1, x1_y0);
-        Structure::Complex(x, y)
+        Ok(Structure::Complex(x, y))
     }
 
-    fn cx_div(&mut self, z0: &Structure, z1: &Structure) -> Structure {
-        let ac = self.dag.f64_mul(z0.cx(), z1.cx());
-        let bd = self.dag.f64_mul(z0.cy(), z1.cy());
-        let bc = self.dag.f64_mul(z0.cy(), z1.cx());
-        let ad = self.dag.f64_mul(z0.cx(), z1.cy());
-        let cc = self.dag.f64_mul(z1.cx(), z1.cx());
-        let dd = self.dag.f64_mul(z1.cy(), z1.cy());
+    fn cx_div(&mut self, z0: &Structure, z1: &Structure) -> Result<Structure, ExprErr> {
+        let ac = self.dag.f64_mul(z0.cx()?, z1.cx()?);
+        let bd = self.dag.f64_mul(z0.cy()?, z1.cy()?);
+        let bc = self.dag.f64_mul(z0.cy()?, z1.cx()?);
+        let ad = self.dag.f64_mul(z0.cx()?, z1.cy()?);
+        let cc = self.dag.f64_mul(z1.cx()?, z1.cx()?);
+        let dd = self.dag.f64_mul(z1.cy()?, z1.cy()?);
         let rr = self.dag.f64_add(cc, dd);
         let xrr = self.dag.f64_add(ac, bd);
         let yrr = self.dag.f64_sub(bc, ad);
         let x = self.dag.f64_div(xrr, rr);
         let y = self.dag.f64_div(yrr, rr);
-        Structure::Complex(x, y)
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_neg(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let x = self.dag.f64_neg(z.cx()?);
+        let y = self.dag.f64_neg(z.cy()?);
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_one(&self) -> Structure {
+        Structure::Complex(self.dag.f64_one(), self.dag.f64_zero())
+    }
+
+    fn cx_scale(&mut self, z: &Structure, k: f64) -> Result<Structure, ExprErr> {
+        let k = self.dag.f64_const(k);
+        let x = self.dag.f64_mul(z.cx()?, k);
+        let y = self.dag.f64_mul(z.cy()?, k);
+        Ok(Structure::Complex(x, y))
+    }
+
+    // The roots of the monic polynomial with lower coefficients b, if there's a formula:
+    // for degree 1 or 2, or when binomial says it's z^n + b_0
+    fn closed_form_roots(&mut self, b: &[Structure], binomial: bool) -> Result<Option<Vec<Structure>>, ExprErr> {
+        let n = b.len();
+        if n == 1 {
+            return Ok(Some(vec![self.cx_neg(&b[0])?]));
+        }
+        if n == 2 {
+            // -b_1/2 +- sqrt(b_1^2/4 - b_0)
+            let half = self.cx_scale(&b[1], -0.5)?;
+            let half2 = self.cx_mul(&half, &half)?;
+            let disc = self.cx_sub(&half2, &b[0])?;
+            let root = self.cx_sqrt(&disc)?;
+            return Ok(Some(vec![self.cx_add(&half, &root)?, self.cx_sub(&half, &root)?]));
+        }
+        if !binomial {
+            return Ok(None);
+        }
+        // The n-th roots of -b_0, one of them from its log and the rest by turning it.
+        // Scaling the log rather than multiplying keeps -b_0 = 0 from giving NaN.
+        let a = self.cx_neg(&b[0])?;
+        let log_a = self.cx_log(&a)?;
+        let log_root = self.cx_scale(&log_a, 1.0 / n as f64)?;
+        let root = self.cx_exp(&log_root)?;
+        let mut roots = vec![];
+        for k in 0..n {
+            let angle = 2.0 * std::f64::consts::PI * k as f64 / n as f64;
+            let turn = Structure::Complex(self.dag.f64_const(angle.cos()), self.dag.f64_const(angle.sin()));
+            roots.push(self.cx_mul(&root, &turn)?);
+        }
+        Ok(Some(roots))
+    }
+
+    fn cx_powi(&mut self, z: &Structure, n: i32) -> Result<Structure, ExprErr> {
+        if n < 0 {
+            let one = self.cx_one();
+            let p = self.cx_powi(z, -n)?;
+            return self.cx_div(&one, &p);
+        }
+        // square-and-multiply: z^8 needs only three multiplications
+        let mut n = n as u32;
+        let mut result = self.cx_one();
+        let mut base = z.clone();
+        while n != 0 {
+            if n & 1 != 0 {
+                result = self.cx_mul(&result, &base)?;
+            }
+            n >>= 1;
+            if n != 0 {
+                base = self.cx_mul(&base, &base)?;
+            }
+        }
+        Ok(result)
     }
 
-    fn cx_neg(&mut self, z: &Structure) -> Structure {
-        let x = self.dag.f64_neg(z.cx());
-        let y = self.dag.f64_neg(z.cy());
-        Structure::Complex(x, y)
+    fn abs2(&mut self, z: &Structure) -> Result<DagNode, ExprErr> {
+        let xx = self.dag.f64_mul(z.cx()?, z.cx()?);
+        let yy = self.dag.f64_mul(z.cy()?, z.cy()?);
+        Ok(self.dag.f64_add(xx, yy))
     }
 
-    fn do_expr(&mut self, expr: &Expr) -> Structure {
+    // sin and cos of a constant zero fold away, so functions of real arguments stay real
+    fn f64_sin(&mut self, x: DagNode) -> DagNode {
+        if x.is_const_zero() {
+            self.dag.f64_zero()
+        } else {
+            self.dag.call1(self.lib.sin, x)
+        }
+    }
+
+    fn f64_cos(&mut self, x: DagNode) -> DagNode {
+        if x.is_const_zero() {
+            self.dag.f64_one()
+        } else {
+            self.dag.call1(self.lib.cos, x)
+        }
+    }
+
+    // (sinh x, cosh x)
+    fn sinh_cosh(&mut self, x: DagNode) -> Result<(DagNode, DagNode), ExprErr> {
+        if x.is_const_zero() {
+            return Ok((self.dag.f64_zero(), self.dag.f64_one()));
+        }
+        let ex = self.dag.call1(self.lib.exp, x);
+        let one = self.dag.f64_one();
+        let half = self.dag.f64_const(0.5);
+        let emx = self.dag.f64_div(one, ex);
+        let diff = self.dag.f64_sub(ex, emx);
+        let sum = self.dag.f64_add(ex, emx);
+        Ok((self.dag.f64_mul(diff, half), self.dag.f64_mul(sum, half)))
+    }
+
+    fn cx_exp(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let ex = self.dag.call1(self.lib.exp, z.cx()?);
+        let cos_y = self.f64_cos(z.cy()?);
+        let sin_y = self.f64_sin(z.cy()?);
+        let x = self.dag.f64_mul(ex, cos_y);
+        let y = self.dag.f64_mul(ex, sin_y);
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_log(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let rr = self.abs2(z)?;
+        let log_rr = self.dag.call1(self.lib.log, rr);
+        let half = self.dag.f64_const(0.5);
+        let x = self.dag.f64_mul(log_rr, half);
+        let y = self.dag.call2(self.lib.atan2, z.cy()?, z.cx()?);
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_sin(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let sin_x = self.f64_sin(z.cx()?);
+        let cos_x = self.f64_cos(z.cx()?);
+        let (sinh_y, cosh_y) = self.sinh_cosh(z.cy()?)?;
+        let x = self.dag.f64_mul(sin_x, cosh_y);
+        let y = self.dag.f64_mul(cos_x, sinh_y);
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_cos(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let sin_x = self.f64_sin(z.cx()?);
+        let cos_x = self.f64_cos(z.cx()?);
+        let (sinh_y, cosh_y) = self.sinh_cosh(z.cy()?)?;
+        let x = self.dag.f64_mul(cos_x, cosh_y);
+        let y = self.dag.f64_mul(sin_x, sinh_y);
+        let y = self.dag.f64_neg(y);
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_sinh(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let (sinh_x, cosh_x) = self.sinh_cosh(z.cx()?)?;
+        let sin_y = self.f64_sin(z.cy()?);
+        let cos_y = self.f64_cos(z.cy()?);
+        let x = self.dag.f64_mul(sinh_x, cos_y);
+        let y = self.dag.f64_mul(cosh_x, sin_y);
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_cosh(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let (sinh_x, cosh_x) = self.sinh_cosh(z.cx()?)?;
+        let sin_y = self.f64_sin(z.cy()?);
+        let cos_y = self.f64_cos(z.cy()?);
+        let x = self.dag.f64_mul(cosh_x, cos_y);
+        let y = self.dag.f64_mul(sinh_x, sin_y);
+        Ok(Structure::Complex(x, y))
+    }
+
+    // Principal square root, with the branch cut along the negative real axis
+    fn cx_sqrt(&mut self, z: &Structure) -> Result<Structure, ExprErr> {
+        let rr = self.abs2(z)?;
+        let r = self.dag.f64_sqrt(rr);
+        let half = self.dag.f64_const(0.5);
+        let r_plus_x = self.dag.f64_add(r, z.cx()?);
+        let r_minus_x = self.dag.f64_sub(r, z.cx()?);
+        let xx = self.dag.f64_mul(r_plus_x, half);
+        let yy = self.dag.f64_mul(r_minus_x, half);
+        let x = self.dag.f64_sqrt(xx);
+        let y = self.dag.f64_sqrt(yy);
+        let y = self.dag.f64_copysign(y, z.cy()?);
+        Ok(Structure::Complex(x, y))
+    }
+
+    fn cx_pow(&mut self, z: &Structure, w: &Structure) -> Result<Structure, ExprErr> {
+        let log_z = self.cx_log(z)?;
+        let w_log_z = self.cx_mul(w, &log_z)?;
+        self.cx_exp(&w_log_z)
+    }
+
+    // Both branches have already been computed; this picks one without branching
+    fn select(&mut self, cond: DagNode, a: &Structure, b: &Structure) -> Result<Structure, ExprErr> {
+        Ok(match (a, b) {
+            (Structure::Bool(a), Structure::Bool(b)) => {
+                let not_cond = self.dag.i32_eqz(cond);
+                let a = self.dag.i32_and(cond, *a);
+                let b = self.dag.i32_and(not_cond, *b);
+                Structure::Bool(self.dag.i32_or(a, b))
+            }
+            (Structure::CxDeriv(a), Structure::CxDeriv(b)) => {
+                let mut result = *a;
+                for (r, b) in result.iter_mut().zip(b) {
+                    *r = self.dag.select(*r, *b, cond);
+                }
+                Structure::CxDeriv(result)
+            }
+            _ => {
+                let x = self.dag.select(a.cx()?, b.cx()?, cond);
+                let y = self.dag.select(a.cy()?, b.cy()?, cond);
+                Structure::Complex(x, y)
+            }
+        })
+    }
+
+    fn cx_eq(&mut self, a: &Structure, b: &Structure) -> Result<Structure, ExprErr> {
+        let eq_x = self.dag.f64_eq(a.cx()?, b.cx()?);
+        let eq_y = self.dag.f64_eq(a.cy()?, b.cy()?);
+        Ok(Structure::Bool(self.dag.i32_and(eq_x, eq_y)))
+    }
+
+    fn do_expr(&mut self, expr: &Expr) -> Result<Structure, SpannedErr> {
         match expr {
-            Expr::F64(x) => Structure::Complex(self.dag.f64_const(*x), self.dag.f64_const(0.0)),
-            Expr::Var(z) => self.env.get(z).unwrap().clone(),
-            Expr::Call(f, args) => {
-                let structs:Vec<_> = args.iter().map(|arg|self.do_expr(arg)).collect();
-                match &f as &str {
-                    "+" => self.cx_add(&structs[0], &structs[1]),
-                    "-" => self.cx_sub(&structs[0], &structs[1]),
-                    "*" => self.cx_mul(&structs[0], &structs[1]),
-                    "/" => self.cx_div(&structs[0], &structs[1]),
-                    "neg" => self.cx_neg(&structs[0]),
-                    "sqabs" => {
-                        let xx = self.dag.f64_mul(structs[0].cx(), structs[0].cx());
-                        let yy = self.dag.f64_mul(structs[0].cy(), structs[0].cy());
-                        let rr = self.dag.f64_add(xx, yy);
-                        Structure::Complex(rr, self.dag.f64_zero())
-                    }
-                    "real" => {
-                        Structure::Complex(structs[0].cx(), self.dag.f64_zero())
-                    }
-                    "conj" => {
-                        let y = self.dag.f64_neg(structs[0].cy());
-                        Structure::Complex(structs[0].cx(), y)
-                    }
-                    "<" => Structure::Bool(self.dag.f64_lt(structs[0].as_real_f64(), structs[1].as_real_f64())),
-                    ">" => Structure::Bool(self.dag.f64_gt(structs[0].as_real_f64(), structs[1].as_real_f64())),
-                    "<=" => Structure::Bool(self.dag.f64_le(structs[0].as_real_f64(), structs[1].as_real_f64())),
-                    ">=" => Structure::Bool(self.dag.f64_ge(structs[0].as_real_f64(), structs[1].as_real_f64())),
-                    _ => panic!("Cannot call {}", f),
+            Expr::F64(x, _) => Ok(Structure::Complex(self.dag.f64_const(*x), self.dag.f64_const(0.0))),
+            Expr::Complex(x, y, _) => Ok(Structure::Complex(self.dag.f64_const(*x), self.dag.f64_const(*y))),
+            Expr::Var(z, span) => self.lookup(z).map_err(|e| e.at(*span)),
+            Expr::Call(f, args, span) => {
+                let structs = args.iter().map(|arg|self.do_expr(arg)).collect::<Result<Vec<_>,_>>()?;
+                if self.funcs.contains_key(f) {
+                    return self.inline(f, structs, *span, false);
                 }
+                self.do_call(f, &structs).map_err(|e| e.at(*span))
+            }
+            Expr::If(cond, a, b, span) => {
+                let cond = self.do_expr(cond)?.boolean().map_err(|e| e.at(cond.span()))?;
+                let a = self.do_expr(a)?;
+                let b = self.do_expr(b)?;
+                self.select(cond, &a, &b).map_err(|e| e.at(*span))
+            }
+            Expr::Let(name, value, body, _) => {
+                let value = self.do_expr(value)?;
+                let shadowed = self.env.insert(name.clone(), value);
+                let result = self.do_expr(body);
+                self.unbind(name, shadowed);
+                result
+            }
+            Expr::Def(def, body, _) => {
+                let shadowed = self.define(def);
+                let result = self.do_expr(body);
+                self.undefine(&def.name, shadowed);
+                result
             }
         }
     }
+
+    fn do_call(&mut self, f: &str, structs: &[Structure]) -> Result<Structure, ExprErr> {
+        check_arity(f, structs.len())?;
+        Ok(match f {
+            "+" => self.cx_add(&structs[0], &structs[1])?,
+            "-" => self.cx_sub(&structs[0], &structs[1])?,
+            "*" => self.cx_mul(&structs[0], &structs[1])?,
+            "/" => self.cx_div(&structs[0], &structs[1])?,
+            "neg" => self.cx_neg(&structs[0])?,
+            "^" | "pow" => match structs[1].as_const_int() {
+                Some(n) => self.cx_powi(&structs[0], n)?,
+                None => self.cx_pow(&structs[0], &structs[1])?,
+            }
+            "sqabs" => {
+                let rr = self.abs2(&structs[0])?;
+                Structure::Complex(rr, self.dag.f64_zero())
+            }
+            "abs" => {
+                let rr = self.abs2(&structs[0])?;
+                Structure::Complex(self.dag.f64_sqrt(rr), self.dag.f64_zero())
+            }
+            "arg" => {
+                let a = self.dag.call2(self.lib.atan2, structs[0].cy()?, structs[0].cx()?);
+                Structure::Complex(a, self.dag.f64_zero())
+            }
+            "exp" => self.cx_exp(&structs[0])?,
+            "log" => self.cx_log(&structs[0])?,
+            "sin" => self.cx_sin(&structs[0])?,
+            "cos" => self.cx_cos(&structs[0])?,
+            "sinh" => self.cx_sinh(&structs[0])?,
+            "cosh" => self.cx_cosh(&structs[0])?,
+            "sqrt" => self.cx_sqrt(&structs[0])?,
+            "real" => {
+                Structure::Complex(structs[0].cx()?, self.dag.f64_zero())
+            }
+            "conj" => {
+                let y = self.dag.f64_neg(structs[0].cy()?);
+                Structure::Complex(structs[0].cx()?, y)
+            }
+            "<" => Structure::Bool(self.dag.f64_lt(structs[0].as_real_f64()?, structs[1].as_real_f64()?)),
+            ">" => Structure::Bool(self.dag.f64_gt(structs[0].as_real_f64()?, structs[1].as_real_f64()?)),
+            "<=" => Structure::Bool(self.dag.f64_le(structs[0].as_real_f64()?, structs[1].as_real_f64()?)),
+            ">=" => Structure::Bool(self.dag.f64_ge(structs[0].as_real_f64()?, structs[1].as_real_f64()?)),
+            "==" => self.cx_eq(&structs[0], &structs[1])?,
+            "!=" => {
+                let eq = self.cx_eq(&structs[0], &structs[1])?.boolean()?;
+                Structure::Bool(self.dag.i32_eqz(eq))
+            }
+            "and" => Structure::Bool(self.dag.i32_and(structs[0].boolean()?, structs[1].boolean()?)),
+            "or" => Structure::Bool(self.dag.i32_or(structs[0].boolean()?, structs[1].boolean()?)),
+            "not" => Structure::Bool(self.dag.i32_eqz(structs[0].boolean()?)),
+            _ => return Err(ArityErr::UnknownFunction(f.to_owned()).into()),
+        })
+    }
+
+    // Ends the scope of a let-binding
+    fn unbind(&mut self, name: &str, shadowed: Option<Structure>) {
+        match shadowed {
+            Some(s) => self.env.insert(name.to_owned(), s),
+            None => self.env.remove(name),
+        };
+    }
+
+    fn lookup(&self, name: &str) -> Result<Structure, ExprErr> {
+        let s = self.env.get(name).ok_or_else(|| ExprErr::UnknownVariable(name.to_owned()))?;
+        Ok(if self.values_only { s.value() } else { s.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+
+    fn critical(text: &str, passed: &[&str]) -> Option<Poly> {
+        let inputs = Inputs { passed: passed.iter().map(|p| p.to_string()).collect(), globals: vec![] };
+        critical_numerator(&parse(text).unwrap(), &inputs)
+    }
+
+    #[test]
+    fn finds_critical_points_by_root_finding() {
+        assert_eq!(critical("z^2 + c", &[]).unwrap().degree(), Some(1));
+        assert_eq!(critical("z^3 + a*z + c", &["a"]).unwrap().degree(), Some(2));
+        assert_eq!(critical("(z^3 + c) / (z - 1)", &[]).unwrap().degree(), Some(3));
+        assert_eq!(critical(&format!("z^{} + c", MAX_CRITICAL + 1), &[]).unwrap().degree(), Some(MAX_CRITICAL));
+        // Constant maps have no critical points, but still get an orbit
+        assert_eq!(critical("c", &[]).unwrap().degree(), None);
+    }
+
+    #[test]
+    fn finds_constant_critical_points_now() {
+        let roots = critical("z^3 - 3*z + c", &[]).unwrap().roots().unwrap();
+        assert!(roots.iter().all(|r| r.1.abs() < 1e-12), "{:?}", roots);
+        let mut xs: Vec<f64> = roots.iter().map(|r| r.0).collect();
+        xs.sort_by(f64::total_cmp);
+        assert!((xs[0] + 1.0).abs() < 1e-12 && (xs[1] - 1.0).abs() < 1e-12, "{:?}", xs);
+        assert_eq!(critical("(z^2 + c)^2", &[]).unwrap().roots(), None);
+        assert_eq!(critical("z^3 + a*z", &["a"]).unwrap().roots(), None);
+    }
+
+    #[test]
+    fn solves_for_critical_points_it_cannot_follow() {
+        // Too many critical points, not rational in z, or using a name no input gives
+        assert!(critical(&format!("z^{} + c", MAX_CRITICAL + 2), &[]).is_none());
+        assert!(critical("exp(z) + c", &[]).is_none());
+        assert!(critical("z^3 + a*z + c", &[]).is_none());
+    }
 }
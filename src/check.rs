@@ -0,0 +1,472 @@
+use std::fmt;
+use crate::ast::{Expr, FuncDef, ParamDecl, Span};
+use crate::error::Field;
+use crate::parse;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Bool,
+    Real,
+    Complex,
+}
+
+#[derive(Debug)]
+pub struct CheckErr {
+    pub field: Field,
+    pub span: Span,
+    pub message: String,
+}
+
+// A call to something that isn't a builtin function, or with the wrong number of arguments
+#[derive(Debug)]
+pub enum ArityErr {
+    UnknownFunction(String),
+    WrongArgCount(String, usize, usize),
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Bool => write!(f, "a condition"),
+            Kind::Real => write!(f, "a real number"),
+            Kind::Complex => write!(f, "a complex number"),
+        }
+    }
+}
+
+impl fmt::Display for ArityErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArityErr::UnknownFunction(func) => write!(f, "Unknown function '{}'", func),
+            ArityErr::WrongArgCount(func, expected, got) => write!(f, "'{}' takes {} argument(s) but was given {}", func, expected, got),
+        }
+    }
+}
+
+const VARIABLES: &[&str] = &["z", "c", "i", "iter"];
+
+// Functions that are not holomorphic can't go through the critical point solver
+const NOT_HOLOMORPHIC: &[&str] = &["sqabs", "abs", "arg", "real", "conj"];
+
+const FUNCTIONS: &[&str] = &[
+    "sqabs", "abs", "arg", "real", "conj", "exp", "log", "sin", "cos", "sinh", "cosh", "sqrt", "pow",
+];
+
+pub fn arity(f: &str) -> Option<usize> {
+    match f {
+        "neg" | "not" | "sqabs" | "abs" | "arg" | "real" | "conj" | "exp" | "log" | "sin" | "cos" | "sinh" | "cosh" | "sqrt" => Some(1),
+        "+" | "-" | "*" | "/" | "^" | "pow" | "<" | ">" | "<=" | ">=" | "==" | "!=" | "and" | "or" => Some(2),
+        _ => None,
+    }
+}
+
+pub fn check_arity(f: &str, count: usize) -> Result<(), ArityErr> {
+    let expected = arity(f).ok_or_else(|| ArityErr::UnknownFunction(f.to_owned()))?;
+    check_arg_count(f, expected, count)
+}
+
+// For defs and builtins alike
+pub fn check_arg_count(f: &str, expected: usize, count: usize) -> Result<(), ArityErr> {
+    if count != expected {
+        return Err(ArityErr::WrongArgCount(f.to_owned(), expected, count));
+    }
+    Ok(())
+}
+
+// Problems with the names given to parameters, which have to be free for use as variables
+pub fn check_params(params: &[String]) -> Vec<String> {
+    params.iter().enumerate().filter_map(|(i, param)| param_name_error(param, &params[..i])).collect()
+}
+
+fn param_name_error(param: &str, earlier: &[String]) -> Option<String> {
+    let is_name = matches!(parse::parse(param), Ok(Expr::Var(name, _)) if name == param);
+    if !is_name {
+        Some(format!("'{}' can't be the name of a parameter", param))
+    } else if VARIABLES.contains(&param) {
+        Some(format!("'{}' is already a variable; pick another name for the parameter", param))
+    } else if FUNCTIONS.contains(&param) {
+        Some(format!("'{}' is already a function; pick another name for the parameter", param))
+    } else if earlier.iter().any(|p| p == param) {
+        Some(format!("Parameter '{}' appears more than once", param))
+    } else {
+        None
+    }
+}
+
+// The default of a declared parameter, which has to be a number such as 0.5 or 1 - 0.5i
+pub fn default_value(expr: &Expr) -> Option<(f64, f64)> {
+    match expr {
+        Expr::F64(x, _) => Some((*x, 0.0)),
+        Expr::Complex(x, y, _) => Some((*x, *y)),
+        Expr::Call(f, args, _) if f == "neg" => default_value(&args[0]).map(|(x, y)| (-x, -y)),
+        Expr::Call(f, args, _) if f == "+" || f == "-" => {
+            let (a, b) = (default_value(&args[0])?, default_value(&args[1])?);
+            let sign = if f == "+" { 1.0 } else { -1.0 };
+            Some((a.0 + sign * b.0, a.1 + sign * b.1))
+        }
+        _ => None,
+    }
+}
+
+// Runs over every field and reports all the problems found, rather than stopping at the first.
+// initz is None when the initial z comes from the critical point solver. params are
+// complex variables like c, as are the parameters declared at the start of the iteration.
+// pixel names the variable the pixel is bound to when it isn't c or a parameter, which
+// the initial z can use; without an initial z, it has to be z itself.
+pub fn check(initz: Option<&Expr>, iteration: &Expr, escape: &Expr, maxiter: &Expr, params: &[String], declared: &[ParamDecl], pixel: Option<&str>) -> Vec<CheckErr> {
+    let mut errors = vec![];
+    let names = declare(params, declared, &mut errors);
+    let params = &names[..];
+    match (initz, pixel) {
+        (Some(initz), _) => {
+            let mut checker = Checker::new(Field::InitialZ, false, params, &mut errors);
+            checker.pixel = pixel.map(str::to_owned);
+            checker.field(initz, Kind::Complex);
+        }
+        (None, Some(pixel)) if pixel != "z" => errors.push(CheckErr {
+            field: Field::InitialZ,
+            span: Span { start: 0, end: 0 },
+            message: format!("Expected an initial z in terms of '{}', the pixel", pixel),
+        }),
+        _ => {}
+    }
+    let solving = initz.is_none() && pixel.is_none();
+    Checker::new(Field::Iteration, solving, params, &mut errors).field(iteration, Kind::Complex);
+    Checker::new(Field::Escape, false, params, &mut errors).field(escape, Kind::Bool);
+    Checker::new(Field::MaxIter, false, params, &mut errors).field(maxiter, Kind::Real);
+    errors
+}
+
+// What check() reports about the iteration, for a formula that's worked on by itself, such
+// as one being differentiated
+pub fn check_iteration(iteration: &Expr, params: &[String], declared: &[ParamDecl]) -> Vec<CheckErr> {
+    let mut errors = vec![];
+    let names = declare(params, declared, &mut errors);
+    Checker::new(Field::Iteration, false, &names, &mut errors).field(iteration, Kind::Complex);
+    errors
+}
+
+// params followed by the parameters declared at the start of the iteration, reporting any
+// declarations that are no good
+fn declare(params: &[String], declared: &[ParamDecl], errors: &mut Vec<CheckErr>) -> Vec<String> {
+    let mut names = params.to_vec();
+    for decl in declared {
+        if let Some(message) = param_name_error(&decl.name, &names) {
+            errors.push(CheckErr { field: Field::Iteration, span: decl.span, message });
+        }
+        if default_value(&decl.default).is_none() {
+            let message = format!("Expected a number such as 0.5 or 1 - 0.5i for the default of '{}'", decl.name);
+            errors.push(CheckErr { field: Field::Iteration, span: decl.default.span(), message });
+        }
+        names.push(decl.name.clone());
+    }
+    names
+}
+
+struct Checker<'a, 'e> {
+    field: Field,
+    // The iteration is also differentiated when there's no initial z
+    solving: bool,
+    // The variable bound to the pixel, for the initial z
+    pixel: Option<String>,
+    // More variables like c
+    params: &'a [String],
+    // Only the value of a condition is needed, never its derivative
+    in_condition: bool,
+    // Let-bindings in scope, innermost last
+    locals: Vec<Local>,
+    // defs in scope, innermost last
+    defs: Vec<Def<'e>>,
+    // defs whose bodies are being checked, to catch recursion
+    expanding: Vec<&'e str>,
+    errors: &'a mut Vec<CheckErr>,
+}
+
+struct Local {
+    name: String,
+    // None if the value had errors, which have already been reported
+    kind: Option<Kind>,
+    depends_on_z: bool,
+}
+
+// A def's body is checked at each call, with the kinds of the arguments given there
+struct Def<'e> {
+    def: &'e FuncDef,
+    // How many of the locals were in scope where the def was written
+    locals: usize,
+    // Whether the body uses z other than through its parameters
+    captures_z: bool,
+}
+
+impl<'a, 'e> Checker<'a, 'e> {
+    fn new(field: Field, solving: bool, params: &'a [String], errors: &'a mut Vec<CheckErr>) -> Self {
+        Checker { field, solving, pixel: None, params, in_condition: false, locals: vec![], defs: vec![], expanding: vec![], errors }
+    }
+
+    // The same def can be expanded several times, but each problem is only reported once
+    fn error(&mut self, span: Span, message: String) {
+        let field = self.field;
+        if !self.errors.iter().any(|e| e.field == field && e.span == span && e.message == message) {
+            self.errors.push(CheckErr { field, span, message });
+        }
+    }
+
+    fn field(&mut self, expr: &'e Expr, expected: Kind) {
+        if let Some(kind) = self.expr(expr) {
+            if !fits(kind, expected) {
+                self.error(expr.span(), format!("Expected {}, found {}", expected, kind));
+            }
+        }
+    }
+
+    fn var_kind(&self, name: &str) -> Option<Kind> {
+        match (self.field, name) {
+            (_, "i") => Some(Kind::Complex),
+            (Field::MaxIter, _) => None,
+            (_, "c") => Some(Kind::Complex),
+            (_, name) if self.params.iter().any(|p| p == name) => Some(Kind::Complex),
+            (Field::InitialZ, name) if self.pixel.as_deref() == Some(name) => Some(Kind::Complex),
+            (Field::InitialZ, _) => None,
+            (_, "z") => Some(Kind::Complex),
+            (Field::Iteration, "iter") if self.solving => None,
+            (_, "iter") => Some(Kind::Real),
+            _ => None,
+        }
+    }
+
+    // Errs on the side of yes for bindings nested inside expr
+    fn depends_on_z(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::F64(..) | Expr::Complex(..) => false,
+            Expr::Var(v, _) => match self.locals.iter().rev().find(|l| l.name == *v) {
+                Some(local) => local.depends_on_z,
+                None => v == "z",
+            },
+            Expr::Call(f, args, _) => {
+                let captures_z = self.defs.iter().rev().find(|d| d.def.name == *f).is_some_and(|d| d.captures_z);
+                captures_z || args.iter().any(|arg| self.depends_on_z(arg))
+            }
+            Expr::If(cond, a, b, _) => self.depends_on_z(cond) || self.depends_on_z(a) || self.depends_on_z(b),
+            Expr::Let(_, value, body, _) => self.depends_on_z(value) || self.depends_on_z(body),
+            Expr::Def(_, body, _) => self.depends_on_z(body),
+        }
+    }
+
+    // None means an error has already been reported for this expression
+    fn expr(&mut self, expr: &'e Expr) -> Option<Kind> {
+        match expr {
+            Expr::F64(..) => Some(Kind::Real),
+            Expr::Complex(_, y, _) => Some(if *y == 0.0 { Kind::Real } else { Kind::Complex }),
+            Expr::Var(name, span) => {
+                if let Some(local) = self.locals.iter().rev().find(|l| l.name == *name) {
+                    local.kind
+                } else if let Some(kind) = self.var_kind(name) {
+                    Some(kind)
+                } else if self.solving && name == "iter" {
+                    self.error(*span, "'iter' is not available when solving for the critical point; give an initial z instead".to_owned());
+                    None
+                } else if VARIABLES.contains(&name.as_str()) || self.params.contains(name) {
+                    self.error(*span, format!("'{}' is not available in the {} field", name, self.field.label()));
+                    None
+                } else if FUNCTIONS.contains(&name.as_str()) {
+                    self.error(*span, format!("'{}' is a function; call it as {}(...)", name, name));
+                    None
+                } else {
+                    let mut candidates: Vec<&str> = VARIABLES.iter().copied().filter(|v| self.var_kind(v).is_some()).collect();
+                    candidates.extend(self.pixel.as_deref());
+                    if self.field != Field::MaxIter {
+                        candidates.extend(self.params.iter().map(String::as_str));
+                    }
+                    candidates.extend(self.locals.iter().map(|l| l.name.as_str()));
+                    let message = format!("Unknown variable '{}'{}", name, suggest(name, &candidates));
+                    self.error(*span, message);
+                    None
+                }
+            }
+            Expr::Call(f, args, span) => {
+                let kinds: Vec<_> = args.iter().map(|arg| self.expr(arg)).collect();
+                if let Some(index) = self.defs.iter().rposition(|d| d.def.name == *f) {
+                    return self.call_def(index, args, kinds, *span);
+                }
+                let expected = match arity(f) {
+                    Some(n) => n,
+                    None => {
+                        if VARIABLES.contains(&f.as_str()) {
+                            self.error(*span, format!("'{}' is a variable, not a function; write {}*(...) to multiply", f, f));
+                        } else if self.expanding.contains(&f.as_str()) {
+                            self.error(*span, format!("'{}' calls itself, but recursive functions are not supported", f));
+                        } else {
+                            let mut candidates = FUNCTIONS.to_vec();
+                            candidates.extend(self.defs.iter().map(|d| d.def.name.as_str()));
+                            let message = format!("{}{}", ArityErr::UnknownFunction(f.clone()), suggest(f, &candidates));
+                            self.error(*span, message);
+                        }
+                        return None;
+                    }
+                };
+                if let Err(e) = check_arg_count(f, expected, args.len()) {
+                    self.error(*span, e.to_string());
+                    return None;
+                }
+                if self.solving && !self.in_condition && NOT_HOLOMORPHIC.contains(&f.as_str()) {
+                    self.error(*span, format!("Cannot differentiate '{}' when solving for the critical point; give an initial z instead", f));
+                }
+                if self.solving && !self.in_condition && (f == "^" || f == "pow") && self.depends_on_z(&args[1]) {
+                    self.error(args[1].span(), "Exponent must not depend on z when solving for the critical point".to_owned());
+                }
+                let kinds = kinds.into_iter().collect::<Option<Vec<_>>>()?;
+                self.call(f, args, &kinds, *span)
+            }
+            Expr::If(cond, a, b, span) => {
+                let in_condition = std::mem::replace(&mut self.in_condition, true);
+                let cond_kind = self.expr(cond);
+                self.in_condition = in_condition;
+                let a_kind = self.expr(a);
+                let b_kind = self.expr(b);
+                if let Some(kind) = cond_kind {
+                    if kind != Kind::Bool {
+                        self.error(cond.span(), format!("Expected a condition after 'if', found {}", kind));
+                    }
+                }
+                match (a_kind?, b_kind?) {
+                    (Kind::Bool, Kind::Bool) => Some(Kind::Bool),
+                    (Kind::Bool, _) | (_, Kind::Bool) => {
+                        self.error(*span, "The branches of 'if' must both be numbers or both be conditions".to_owned());
+                        None
+                    }
+                    (Kind::Real, Kind::Real) => Some(Kind::Real),
+                    _ => Some(Kind::Complex),
+                }
+            }
+            Expr::Let(name, value, body, span) => {
+                if VARIABLES.contains(&name.as_str()) {
+                    let name_span = Span { start: span.start, end: span.start + name.len() };
+                    self.error(name_span, format!("'{}' is already a variable; pick another name", name));
+                }
+                let kind = self.expr(value);
+                let depends_on_z = self.depends_on_z(value);
+                self.locals.push(Local { name: name.clone(), kind, depends_on_z });
+                let kind = self.expr(body);
+                self.locals.pop();
+                kind
+            }
+            Expr::Def(def, body, _) => {
+                if VARIABLES.contains(&def.name.as_str()) {
+                    self.error(def.span, format!("'{}' is already a variable; pick another name", def.name));
+                } else if FUNCTIONS.contains(&def.name.as_str()) {
+                    self.error(def.span, format!("'{}' is already a function; pick another name", def.name));
+                }
+                for (i, param) in def.params.iter().enumerate() {
+                    if def.params[..i].contains(param) {
+                        self.error(def.span, format!("Parameter '{}' appears more than once", param));
+                    }
+                }
+                let locals = self.locals.len();
+                for param in &def.params {
+                    self.locals.push(Local { name: param.clone(), kind: Some(Kind::Complex), depends_on_z: false });
+                }
+                let captures_z = self.depends_on_z(&def.body);
+                self.locals.truncate(locals);
+                self.defs.push(Def { def, locals, captures_z });
+                let kind = self.expr(body);
+                self.defs.pop();
+                kind
+            }
+        }
+    }
+
+    fn call_def(&mut self, index: usize, args: &'e [Expr], kinds: Vec<Option<Kind>>, span: Span) -> Option<Kind> {
+        let Def { def, locals, .. } = self.defs[index];
+        if let Err(e) = check_arg_count(&def.name, def.params.len(), args.len()) {
+            self.error(span, e.to_string());
+            return None;
+        }
+        // The body only sees what was in scope where it was written, plus the parameters
+        let depends_on_z: Vec<bool> = args.iter().map(|arg| self.depends_on_z(arg)).collect();
+        let outer_locals = self.locals.split_off(locals);
+        let outer_defs = self.defs.split_off(index);
+        for ((param, kind), depends_on_z) in def.params.iter().zip(kinds).zip(depends_on_z) {
+            self.locals.push(Local { name: param.clone(), kind, depends_on_z });
+        }
+        self.expanding.push(&def.name);
+        let kind = self.expr(&def.body);
+        self.expanding.pop();
+        self.locals.truncate(locals);
+        self.locals.extend(outer_locals);
+        self.defs.extend(outer_defs);
+        kind
+    }
+
+    fn call(&mut self, f: &str, args: &[Expr], kinds: &[Kind], span: Span) -> Option<Kind> {
+        if f == "and" || f == "or" || f == "not" {
+            if kinds.iter().all(|k| *k == Kind::Bool) {
+                return Some(Kind::Bool);
+            }
+            self.error(span, format!("'{}' expects conditions, but was given a number", f));
+            return None;
+        }
+        if kinds.contains(&Kind::Bool) {
+            self.error(span, format!("'{}' expects numbers, but was given a condition", f));
+            return None;
+        }
+        let all_real = kinds.iter().all(|k| *k == Kind::Real);
+        match f {
+            "<" | ">" | "<=" | ">=" => {
+                if all_real {
+                    Some(Kind::Bool)
+                } else {
+                    self.error(span, format!("Both sides of '{}' must be real; try real(...) or sqabs(...)", f));
+                    None
+                }
+            }
+            "==" | "!=" => Some(Kind::Bool),
+            "+" | "-" | "*" | "/" | "neg" | "conj" | "exp" | "sin" | "cos" | "sinh" | "cosh" => {
+                Some(if all_real { Kind::Real } else { Kind::Complex })
+            }
+            "^" | "pow" => match (kinds[0], const_value(&args[1])) {
+                (Kind::Real, Some(n)) if n.fract() == 0.0 => Some(Kind::Real),
+                _ => Some(Kind::Complex),
+            }
+            "sqabs" | "abs" | "arg" | "real" => Some(Kind::Real),
+            _ => Some(Kind::Complex),
+        }
+    }
+}
+
+fn const_value(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::F64(x, _) => Some(*x),
+        Expr::Call(f, args, _) if f == "neg" => Some(-const_value(&args[0])?),
+        _ => None,
+    }
+}
+
+fn fits(kind: Kind, expected: Kind) -> bool {
+    kind == expected || (kind == Kind::Real && expected == Kind::Complex)
+}
+
+fn suggest(name: &str, candidates: &[&str]) -> String {
+    let best = candidates
+        .iter()
+        .map(|c| (edit_distance(name, c), *c))
+        .filter(|(d, c)| *d <= 2 && *d < name.len().max(c.len()))
+        .min();
+    match best {
+        Some((_, c)) => format!(". Did you mean '{}'?", c),
+        None => String::new(),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            row.push((prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
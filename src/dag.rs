@@ -1,24 +1,51 @@
 use std::collections::HashMap;
-use crate::module_builder::{Local,ModuleBuilder,ValType};
+use crate::module_builder::{Func,Global,Local,ModuleBuilder,ValType};
 
 #[derive(Clone,Copy,Hash,Eq,PartialEq)]
 pub enum DagNode {
     F64(u64),  // need to store f64 as bits in order for Eq to work
+    I64(i64),
     Node(usize),
     Input(Local),
+    // Read each time, since it can change between calls
+    Global(Global),
 }
 
 #[derive(Clone,Copy,Hash,Eq,PartialEq)]
 enum DagCalc {
     F64Neg(DagNode),
+    F64Abs(DagNode),
+    F64Sqrt(DagNode),
+    F64Floor(DagNode),
+    F64Nearest(DagNode),
     F64Add(DagNode, DagNode),
     F64Sub(DagNode, DagNode),
     F64Mul(DagNode, DagNode),
     F64Div(DagNode, DagNode),
+    F64Min(DagNode, DagNode),
+    F64Max(DagNode, DagNode),
+    F64Copysign(DagNode, DagNode),
+    F64Eq(DagNode, DagNode),
     F64Lt(DagNode, DagNode),
     F64Gt(DagNode, DagNode),
     F64Le(DagNode, DagNode),
     F64Ge(DagNode, DagNode),
+    F64ConvertI64S(DagNode),
+    F64ReinterpretI64(DagNode),
+    I64TruncSatF64S(DagNode),
+    I64ReinterpretF64(DagNode),
+    I64Add(DagNode, DagNode),
+    I64And(DagNode, DagNode),
+    I64Or(DagNode, DagNode),
+    I64Shl(DagNode, DagNode),
+    I64ShrU(DagNode, DagNode),
+    I32WrapI64(DagNode),
+    I32Eqz(DagNode),
+    I32And(DagNode, DagNode),
+    I32Or(DagNode, DagNode),
+    Select(DagNode, DagNode, DagNode),
+    Call1(Func, DagNode),
+    Call2(Func, DagNode, DagNode),
 }
 
 #[derive(Default)]
@@ -46,9 +73,53 @@ impl EffectType {
 impl DagCalc {
     fn dependencies(&self) -> Vec<DagNode> {
         match self {
-            DagCalc::F64Neg(x) => vec![*x],
+            DagCalc::F64Neg(x) | DagCalc::F64Abs(x) | DagCalc::F64Sqrt(x) | DagCalc::F64Floor(x) | DagCalc::F64Nearest(x)
+                | DagCalc::F64ConvertI64S(x) | DagCalc::F64ReinterpretI64(x) | DagCalc::I64TruncSatF64S(x)
+                | DagCalc::I64ReinterpretF64(x) | DagCalc::I32WrapI64(x) | DagCalc::I32Eqz(x) | DagCalc::Call1(_,x) => vec![*x],
             DagCalc::F64Add(x,y) | DagCalc::F64Sub(x,y) | DagCalc::F64Mul(x,y) | DagCalc::F64Div(x,y)
-                | DagCalc::F64Lt(x,y) | DagCalc::F64Gt(x,y) | DagCalc::F64Le(x,y) | DagCalc::F64Ge(x,y) => vec![*x,*y],
+                | DagCalc::F64Min(x,y) | DagCalc::F64Max(x,y) | DagCalc::F64Copysign(x,y) | DagCalc::F64Eq(x,y)
+                | DagCalc::F64Lt(x,y) | DagCalc::F64Gt(x,y) | DagCalc::F64Le(x,y) | DagCalc::F64Ge(x,y)
+                | DagCalc::I64Add(x,y) | DagCalc::I64And(x,y) | DagCalc::I64Or(x,y) | DagCalc::I64Shl(x,y)
+                | DagCalc::I64ShrU(x,y) | DagCalc::I32And(x,y) | DagCalc::I32Or(x,y) | DagCalc::Call2(_,x,y) => vec![*x,*y],
+            DagCalc::Select(x,y,z) => vec![*x,*y,*z],
+        }
+    }
+
+    // Operands are already on the stack, in the order given by dependencies()
+    fn emit(&self, mb: &mut ModuleBuilder) -> ValType {
+        match self {
+            DagCalc::F64Neg(_) => { mb.f64_neg(); ValType::F64 }
+            DagCalc::F64Abs(_) => { mb.f64_abs(); ValType::F64 }
+            DagCalc::F64Sqrt(_) => { mb.f64_sqrt(); ValType::F64 }
+            DagCalc::F64Floor(_) => { mb.f64_floor(); ValType::F64 }
+            DagCalc::F64Nearest(_) => { mb.f64_nearest(); ValType::F64 }
+            DagCalc::F64Add(_,_) => { mb.f64_add(); ValType::F64 }
+            DagCalc::F64Sub(_,_) => { mb.f64_sub(); ValType::F64 }
+            DagCalc::F64Mul(_,_) => { mb.f64_mul(); ValType::F64 }
+            DagCalc::F64Div(_,_) => { mb.f64_div(); ValType::F64 }
+            DagCalc::F64Min(_,_) => { mb.f64_min(); ValType::F64 }
+            DagCalc::F64Max(_,_) => { mb.f64_max(); ValType::F64 }
+            DagCalc::F64Copysign(_,_) => { mb.f64_copysign(); ValType::F64 }
+            DagCalc::F64Eq(_,_) => { mb.f64_eq(); ValType::I32 }
+            DagCalc::F64Lt(_,_) => { mb.f64_lt(); ValType::I32 }
+            DagCalc::F64Gt(_,_) => { mb.f64_gt(); ValType::I32 }
+            DagCalc::F64Le(_,_) => { mb.f64_le(); ValType::I32 }
+            DagCalc::F64Ge(_,_) => { mb.f64_ge(); ValType::I32 }
+            DagCalc::F64ConvertI64S(_) => { mb.f64_convert_i64_s(); ValType::F64 }
+            DagCalc::F64ReinterpretI64(_) => { mb.f64_reinterpret_i64(); ValType::F64 }
+            DagCalc::I64TruncSatF64S(_) => { mb.i64_trunc_sat_f64_s(); ValType::I64 }
+            DagCalc::I64ReinterpretF64(_) => { mb.i64_reinterpret_f64(); ValType::I64 }
+            DagCalc::I64Add(_,_) => { mb.i64_add(); ValType::I64 }
+            DagCalc::I64And(_,_) => { mb.i64_and(); ValType::I64 }
+            DagCalc::I64Or(_,_) => { mb.i64_or(); ValType::I64 }
+            DagCalc::I64Shl(_,_) => { mb.i64_shl(); ValType::I64 }
+            DagCalc::I64ShrU(_,_) => { mb.i64_shr_u(); ValType::I64 }
+            DagCalc::I32WrapI64(_) => { mb.i32_wrap_i64(); ValType::I32 }
+            DagCalc::I32Eqz(_) => { mb.i32_eqz(); ValType::I32 }
+            DagCalc::I32And(_,_) => { mb.i32_and(); ValType::I32 }
+            DagCalc::I32Or(_,_) => { mb.i32_or(); ValType::I32 }
+            DagCalc::Select(_,_,_) => { mb.select(); ValType::F64 }
+            DagCalc::Call1(f,_) | DagCalc::Call2(f,_,_) => { mb.call(*f); ValType::F64 }
         }
     }
 }
@@ -61,6 +132,13 @@ impl DagNode {
         }
     }
 
+    pub fn as_const_f64(self) -> Option<f64> {
+        match self {
+            DagNode::F64(x) => Some(f64::from_bits(x)),
+            _ => None,
+        }
+    }
+
     fn is_const_one(self) -> bool {
         match self {
             DagNode::F64(x) => f64::from_bits(x) == 1.0,
@@ -86,6 +164,10 @@ impl Dag {
         DagNode::Input(local)
     }
 
+    pub fn f64_global(&self, global: Global) -> DagNode {
+        DagNode::Global(global)
+    }
+
     pub fn f64_neg(&mut self, x: DagNode) -> DagNode {
         match x {
             DagNode::F64(a) => DagNode::F64((-f64::from_bits(a)).to_bits()),
@@ -171,6 +253,127 @@ impl Dag {
         self.calc(DagCalc::F64Ge(x, y))
     }
 
+    pub fn f64_abs(&mut self, x: DagNode) -> DagNode {
+        match x {
+            DagNode::F64(a) => DagNode::F64(f64::from_bits(a).abs().to_bits()),
+            _ => self.calc(DagCalc::F64Abs(x)),
+        }
+    }
+
+    pub fn f64_sqrt(&mut self, x: DagNode) -> DagNode {
+        match x {
+            DagNode::F64(a) => DagNode::F64(f64::from_bits(a).sqrt().to_bits()),
+            _ => self.calc(DagCalc::F64Sqrt(x)),
+        }
+    }
+
+    pub fn f64_floor(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::F64Floor(x))
+    }
+
+    pub fn f64_nearest(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::F64Nearest(x))
+    }
+
+    pub fn f64_min(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::F64Min(x, y))
+    }
+
+    pub fn f64_max(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::F64Max(x, y))
+    }
+
+    pub fn f64_copysign(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::F64Copysign(x, y))
+    }
+
+    pub fn f64_eq(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::F64Eq(x, y))
+    }
+
+    pub fn f64_convert_i64_s(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::F64ConvertI64S(x))
+    }
+
+    pub fn f64_reinterpret_i64(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::F64ReinterpretI64(x))
+    }
+
+    pub fn i64_const(&self, x: i64) -> DagNode {
+        DagNode::I64(x)
+    }
+
+    pub fn i64_trunc_sat_f64_s(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::I64TruncSatF64S(x))
+    }
+
+    pub fn i64_reinterpret_f64(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::I64ReinterpretF64(x))
+    }
+
+    pub fn i64_add(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::I64Add(x, y))
+    }
+
+    pub fn i64_and(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::I64And(x, y))
+    }
+
+    pub fn i64_or(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::I64Or(x, y))
+    }
+
+    pub fn i64_shl(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::I64Shl(x, y))
+    }
+
+    pub fn i64_shr_u(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::I64ShrU(x, y))
+    }
+
+    pub fn i32_wrap_i64(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::I32WrapI64(x))
+    }
+
+    // Logical not of a condition
+    pub fn i32_eqz(&mut self, x: DagNode) -> DagNode {
+        self.calc(DagCalc::I32Eqz(x))
+    }
+
+    // The conditions are all 0 or 1, so bitwise and/or are also logical
+    pub fn i32_and(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        if x == y {
+            x
+        } else {
+            self.calc(DagCalc::I32And(x, y))
+        }
+    }
+
+    pub fn i32_or(&mut self, x: DagNode, y: DagNode) -> DagNode {
+        if x == y {
+            x
+        } else {
+            self.calc(DagCalc::I32Or(x, y))
+        }
+    }
+
+    // f64-valued select: x if cond is nonzero, otherwise y
+    pub fn select(&mut self, x: DagNode, y: DagNode, cond: DagNode) -> DagNode {
+        if x == y {
+            x
+        } else {
+            self.calc(DagCalc::Select(x, y, cond))
+        }
+    }
+
+    pub fn call1(&mut self, f: Func, x: DagNode) -> DagNode {
+        self.calc(DagCalc::Call1(f, x))
+    }
+
+    pub fn call2(&mut self, f: Func, x: DagNode, y: DagNode) -> DagNode {
+        self.calc(DagCalc::Call2(f, x, y))
+    }
+
     fn calc(&mut self, calc: DagCalc) -> DagNode {
         if let Some(n) = self.memo.get(&calc) {
             return *n;
@@ -184,7 +387,7 @@ impl Dag {
     fn dependencies(&self, node: DagNode) -> Vec<DagNode> {
         match node {
             DagNode::Node(i) => self.nodes[i].dependencies(),
-            DagNode::F64(_) | DagNode::Input(_) => vec![],
+            DagNode::F64(_) | DagNode::I64(_) | DagNode::Input(_) | DagNode::Global(_) => vec![],
         }
     }
 
@@ -213,64 +416,14 @@ impl Dag {
         } else {
             match node {
                 DagNode::F64(x) => mb.f64_const(f64::from_bits(x)),
+                DagNode::I64(x) => mb.i64_const(x),
                 DagNode::Input(local) => mb.local_get(local),
+                DagNode::Global(global) => mb.global_get(global),
                 DagNode::Node(i) => {
-                    let typ;
-                    match self.nodes[i] {
-                        DagCalc::F64Neg(x) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            mb.f64_neg();
-                            typ = ValType::F64;
-                        }
-                        DagCalc::F64Add(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_add();
-                            typ = ValType::F64;
-                        }
-                        DagCalc::F64Sub(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_sub();
-                            typ = ValType::F64;
-                        }
-                        DagCalc::F64Mul(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_mul();
-                            typ = ValType::F64;
-                        }
-                        DagCalc::F64Div(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_div();
-                            typ = ValType::F64;
-                        }
-                        DagCalc::F64Lt(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_lt();
-                            typ = ValType::I32;
-                        }
-                        DagCalc::F64Gt(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_gt();
-                            typ = ValType::I32;
-                        }
-                        DagCalc::F64Le(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_le();
-                            typ = ValType::I32;
-                        }
-                        DagCalc::F64Ge(x,y) => {
-                            self.emit_recursive(mb, placement, usage, x);
-                            self.emit_recursive(mb, placement, usage, y);
-                            mb.f64_ge();
-                            typ = ValType::I32;
-                        }
+                    for x in self.nodes[i].dependencies() {
+                        self.emit_recursive(mb, placement, usage, x);
                     }
+                    let typ = self.nodes[i].emit(mb);
                     if let Some(x) = usage.get(&node) {
                         if *x > 1 {
                             let local = mb.add_local(typ);
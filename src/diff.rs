@@ -0,0 +1,488 @@
+use std::collections::HashSet;
+use std::fmt;
+use crate::ast::{Expr, FuncDef, Span};
+use crate::check::{check_arg_count, check_arity, ArityErr};
+use crate::error::Spanned;
+
+// Symbolic differentiation. The result is an ordinary formula, simplified as it's built,
+// so it can be printed or compiled like anything typed into a field.
+//
+// Lets keep their sharing: "w = v; body" becomes "w = v; w_dz = v'; body'". A def gets
+// sibling defs for its partial derivatives, one per parameter plus one for the variable
+// itself when the body refers to it, and calls go through those by the chain rule.
+pub fn derivative(expr: &Expr, var: &str) -> Result<Expr, Spanned<DiffErr>> {
+    let mut names = HashSet::new();
+    collect_names(expr, &mut names);
+    let mut differ = Differ {
+        var: var.to_owned(),
+        names,
+        scope: vec![],
+        funcs: vec![],
+    };
+    differ.d(expr)
+}
+
+#[derive(Debug)]
+pub enum DiffErr {
+    Arity(ArityErr),
+    NotDifferentiable(String),
+}
+
+impl fmt::Display for DiffErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffErr::Arity(e) => write!(f, "{}", e),
+            DiffErr::NotDifferentiable(func) => write!(f, "Cannot differentiate '{}'", func),
+        }
+    }
+}
+
+impl DiffErr {
+    pub fn at(self, span: Span) -> Spanned<DiffErr> {
+        Spanned { span, err: self }
+    }
+}
+
+struct Differ {
+    var: String,
+    // Every name in use, so that new ones don't clash
+    names: HashSet<String>,
+    // Variables in scope with their derivatives, innermost last. None means constant.
+    scope: Vec<(String, Option<Expr>)>,
+    // defs in scope, innermost last
+    funcs: Vec<(String, Partials)>,
+}
+
+// Names of the defs holding a def's partial derivatives. None where they're zero.
+#[derive(Clone)]
+struct Partials {
+    params: Vec<Option<String>>,
+    // With respect to var, through the variables the body captures
+    direct: Option<String>,
+}
+
+impl Differ {
+    fn d(&mut self, expr: &Expr) -> Result<Expr, Spanned<DiffErr>> {
+        let span = expr.span();
+        match expr {
+            Expr::F64(..) | Expr::Complex(..) => Ok(num(0.0, span)),
+            Expr::Var(v, _) => Ok(match self.scope.iter().rev().find(|(name, _)| name == v) {
+                Some((_, Some(dv))) => dv.clone().with_span(span),
+                Some((_, None)) => num(0.0, span),
+                None if *v == self.var => num(1.0, span),
+                None => num(0.0, span),
+            }),
+            Expr::Call(f, args, _) => {
+                let dargs = args.iter().map(|arg| self.d(arg)).collect::<Result<Vec<_>, _>>()?;
+                if let Some((_, partials)) = self.funcs.iter().rev().find(|(name, _)| name == f) {
+                    let partials = partials.clone();
+                    check_arg_count(f, partials.params.len(), args.len()).map_err(|e| DiffErr::Arity(e).at(span))?;
+                    let mut total = match partials.direct {
+                        Some(g) => Expr::Call(g, args.clone(), span),
+                        None => num(0.0, span),
+                    };
+                    for (p, darg) in partials.params.iter().zip(dargs) {
+                        if let Some(p) = p {
+                            total = add(total, mul(Expr::Call(p.clone(), args.clone(), span), darg));
+                        }
+                    }
+                    return Ok(total);
+                }
+                check_arity(f, args.len()).map_err(|e| DiffErr::Arity(e).at(span))?;
+                if dargs.iter().all(is_zero) {
+                    return Ok(num(0.0, span));
+                }
+                builtin(expr, f, args, dargs).map_err(|e| e.at(span))
+            }
+            Expr::If(cond, a, b, _) => {
+                let da = self.d(a)?;
+                let db = self.d(b)?;
+                if same(&da, &db) {
+                    Ok(da)
+                } else {
+                    Ok(Expr::If(cond.clone(), Box::new(da), Box::new(db), span))
+                }
+            }
+            Expr::Let(name, value, body, _) => {
+                let dvalue = self.d(value)?;
+                if let Expr::F64(..) = dvalue {
+                    self.scope.push((name.clone(), Some(dvalue)));
+                    let dbody = self.d(body);
+                    self.scope.pop();
+                    return Ok(bind(name, value, dbody?, span));
+                }
+                let dname = self.fresh(&format!("{}_d{}", name, self.var));
+                self.scope.push((name.clone(), Some(Expr::Var(dname.clone(), span))));
+                let dbody = self.d(body);
+                self.scope.pop();
+                let dbody = bind(&dname, &dvalue, dbody?, span);
+                Ok(bind(name, value, dbody, span))
+            }
+            Expr::Def(def, rest, _) => {
+                let (partials, defs) = self.partials(def)?;
+                self.funcs.push((def.name.clone(), partials));
+                let drest = self.d(rest);
+                self.funcs.pop();
+                let mut result = drest?;
+                for pd in defs.into_iter().rev() {
+                    if mentions(&result, &pd.name) {
+                        result = Expr::Def(Box::new(pd), Box::new(result), span);
+                    }
+                }
+                if mentions(&result, &def.name) {
+                    result = Expr::Def(def.clone(), Box::new(result), span);
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    // Differentiates the body of def once per parameter, holding everything else constant,
+    // and once more for var with the parameters held constant
+    fn partials(&mut self, def: &FuncDef) -> Result<(Partials, Vec<FuncDef>), Spanned<DiffErr>> {
+        let span = def.span;
+        let mut partials = Partials { params: vec![], direct: None };
+        let mut defs = vec![];
+        for p in &def.params {
+            let params = def.params.iter().map(|q| (q.clone(), if q == p { Some(num(1.0, span)) } else { None })).collect();
+            // Variables from outside the def don't depend on a parameter, and neither do
+            // the direct partials of the defs already in scope
+            let funcs = self.funcs.iter().map(|(name, f)| (name.clone(), Partials { params: f.params.clone(), direct: None })).collect();
+            let var = std::mem::replace(&mut self.var, p.clone());
+            let scope = std::mem::replace(&mut self.scope, params);
+            let funcs = std::mem::replace(&mut self.funcs, funcs);
+            let body = self.d(&def.body);
+            self.var = var;
+            self.scope = scope;
+            self.funcs = funcs;
+            partials.params.push(self.partial_def(def, p, body?, &mut defs));
+        }
+        let depth = self.scope.len();
+        self.scope.extend(def.params.iter().map(|p| (p.clone(), None)));
+        let body = self.d(&def.body);
+        self.scope.truncate(depth);
+        let var = self.var.clone();
+        partials.direct = self.partial_def(def, &var, body?, &mut defs);
+        Ok((partials, defs))
+    }
+
+    fn partial_def(&mut self, def: &FuncDef, wrt: &str, body: Expr, defs: &mut Vec<FuncDef>) -> Option<String> {
+        if is_zero(&body) {
+            return None;
+        }
+        let name = self.fresh(&format!("{}_d{}", def.name, wrt));
+        defs.push(FuncDef {
+            name: name.clone(),
+            params: def.params.clone(),
+            body,
+            span: def.span,
+        });
+        Some(name)
+    }
+
+    fn fresh(&mut self, base: &str) -> String {
+        let mut name = base.to_owned();
+        let mut n = 2;
+        while self.names.contains(&name) {
+            name = format!("{}{}", base, n);
+            n += 1;
+        }
+        self.names.insert(name.clone());
+        name
+    }
+}
+
+// The derivative of a call to a builtin, given those of its arguments
+fn builtin(expr: &Expr, f: &str, args: &[Expr], dargs: Vec<Expr>) -> Result<Expr, DiffErr> {
+    let span = expr.span();
+    let call = |f: &str, u: &Expr| Expr::Call(f.to_owned(), vec![u.clone()], span);
+    let (u, du) = (&args[0], dargs[0].clone());
+    Ok(match f {
+        "+" => add(du, dargs[1].clone()),
+        "-" => sub(du, dargs[1].clone()),
+        "*" => add(mul(du, args[1].clone()), mul(u.clone(), dargs[1].clone())),
+        "/" if is_zero(&dargs[1]) => div(du, args[1].clone()),
+        "/" => {
+            let top = sub(mul(du, args[1].clone()), mul(u.clone(), dargs[1].clone()));
+            div(top, pow(args[1].clone(), num(2.0, span)))
+        }
+        "neg" => neg(du),
+        "^" | "pow" if is_zero(&dargs[1]) => {
+            let n = &args[1];
+            let n_1 = sub(n.clone(), num(1.0, span));
+            mul(n.clone(), mul(pow(u.clone(), n_1), du))
+        }
+        "^" | "pow" => {
+            // d(u^v) = u^v (v' log u + v u'/u)
+            let v = &args[1];
+            let t0 = mul(dargs[1].clone(), call("log", u));
+            let t1 = div(mul(v.clone(), du), u.clone());
+            mul(expr.clone(), add(t0, t1))
+        }
+        "exp" => mul(expr.clone(), du),
+        "log" => div(du, u.clone()),
+        "sin" => mul(call("cos", u), du),
+        "cos" => neg(mul(call("sin", u), du)),
+        "sinh" => mul(call("cosh", u), du),
+        "cosh" => mul(call("sinh", u), du),
+        "sqrt" => div(du, mul(num(2.0, span), expr.clone())),
+        _ => return Err(DiffErr::NotDifferentiable(f.to_owned())),
+    })
+}
+
+// Constructors that tidy up as they go: folding constants, dropping zeros and ones, and
+// pulling negation outwards
+
+pub fn num(x: f64, span: Span) -> Expr {
+    Expr::F64(x, span)
+}
+
+fn as_num(e: &Expr) -> Option<f64> {
+    match e {
+        Expr::F64(x, _) => Some(*x),
+        _ => None,
+    }
+}
+
+fn is_zero(e: &Expr) -> bool {
+    as_num(e) == Some(0.0)
+}
+
+fn is_one(e: &Expr) -> bool {
+    as_num(e) == Some(1.0)
+}
+
+fn call2(f: &str, a: Expr, b: Expr) -> Expr {
+    let span = a.span().to(b.span());
+    Expr::Call(f.to_owned(), vec![a, b], span)
+}
+
+fn negated(e: &Expr) -> Option<&Expr> {
+    match e {
+        Expr::Call(f, args, _) if f == "neg" && args.len() == 1 => Some(&args[0]),
+        _ => None,
+    }
+}
+
+pub fn neg(a: Expr) -> Expr {
+    if let Some(x) = as_num(&a) {
+        return num(-x, a.span());
+    }
+    if let Some(x) = negated(&a) {
+        return x.clone();
+    }
+    let span = a.span();
+    Expr::Call("neg".to_owned(), vec![a], span)
+}
+
+pub fn add(a: Expr, b: Expr) -> Expr {
+    match (as_num(&a), as_num(&b)) {
+        (Some(x), Some(y)) => return num(x + y, a.span().to(b.span())),
+        (Some(0.0), _) => return b,
+        (_, Some(0.0)) => return a,
+        _ => {}
+    }
+    if let Some(y) = negated(&b) {
+        return sub(a, y.clone());
+    }
+    if let Some(y) = as_num(&b).filter(|y| *y < 0.0) {
+        return sub(a, num(-y, b.span()));
+    }
+    if let Some(x) = negated(&a) {
+        return sub(b, x.clone());
+    }
+    if same(&a, &b) || swapped_product(&a, &b) {
+        return mul(num(2.0, a.span()), a);
+    }
+    call2("+", a, b)
+}
+
+pub fn sub(a: Expr, b: Expr) -> Expr {
+    match (as_num(&a), as_num(&b)) {
+        (Some(x), Some(y)) => return num(x - y, a.span().to(b.span())),
+        (Some(0.0), _) => return neg(b),
+        (_, Some(0.0)) => return a,
+        _ => {}
+    }
+    if same(&a, &b) {
+        return num(0.0, a.span().to(b.span()));
+    }
+    if let Some(y) = negated(&b) {
+        return add(a, y.clone());
+    }
+    if let Some(y) = as_num(&b).filter(|y| *y < 0.0) {
+        return add(a, num(-y, b.span()));
+    }
+    call2("-", a, b)
+}
+
+pub fn mul(a: Expr, b: Expr) -> Expr {
+    match (as_num(&a), as_num(&b)) {
+        (Some(x), Some(y)) => return num(x * y, a.span().to(b.span())),
+        (Some(0.0), _) | (_, Some(0.0)) => return num(0.0, a.span().to(b.span())),
+        (Some(1.0), _) => return b,
+        (_, Some(1.0)) => return a,
+        // Constants go first
+        (None, Some(_)) => return mul(b, a),
+        _ => {}
+    }
+    if let Some(x) = negated(&a) {
+        return neg(mul(x.clone(), b));
+    }
+    if let Some(y) = negated(&b) {
+        return neg(mul(a, y.clone()));
+    }
+    // 2*(3*x) is 6*x
+    if let (Some(x), Expr::Call(f, args, _)) = (as_num(&a), &b) {
+        if f == "*" {
+            if let Some(y) = as_num(&args[0]) {
+                return mul(num(x * y, a.span()), args[1].clone());
+            }
+        }
+    }
+    call2("*", a, b)
+}
+
+pub fn div(a: Expr, b: Expr) -> Expr {
+    match (as_num(&a), as_num(&b)) {
+        (Some(x), Some(y)) if y != 0.0 => return num(x / y, a.span().to(b.span())),
+        (Some(0.0), _) => return a,
+        (_, Some(1.0)) => return a,
+        _ => {}
+    }
+    if same(&a, &b) {
+        return num(1.0, a.span().to(b.span()));
+    }
+    if let Some(x) = negated(&a) {
+        return neg(div(x.clone(), b));
+    }
+    call2("/", a, b)
+}
+
+pub fn pow(a: Expr, b: Expr) -> Expr {
+    if is_zero(&b) {
+        return num(1.0, a.span().to(b.span()));
+    }
+    if is_one(&b) {
+        return a;
+    }
+    call2("^", a, b)
+}
+
+// x*y and y*x
+fn swapped_product(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::Call(f, xs, _), Expr::Call(g, ys, _)) if f == "*" && g == "*" => same(&xs[0], &ys[1]) && same(&xs[1], &ys[0]),
+        _ => false,
+    }
+}
+
+// name = value; body, unless body doesn't use name
+fn bind(name: &str, value: &Expr, body: Expr, span: Span) -> Expr {
+    if mentions(&body, name) {
+        Expr::Let(name.to_owned(), Box::new(value.clone()), Box::new(body), span)
+    } else {
+        body
+    }
+}
+
+// Whether name appears anywhere in expr, as a variable or a function. Shadowing is ignored,
+// which errs on the side of keeping a binding that isn't needed.
+fn mentions(expr: &Expr, name: &str) -> bool {
+    match expr {
+        Expr::F64(..) | Expr::Complex(..) => false,
+        Expr::Var(v, _) => v == name,
+        Expr::Call(f, args, _) => f == name || args.iter().any(|arg| mentions(arg, name)),
+        Expr::If(cond, a, b, _) => mentions(cond, name) || mentions(a, name) || mentions(b, name),
+        Expr::Let(_, value, body, _) => mentions(value, name) || mentions(body, name),
+        Expr::Def(def, body, _) => mentions(&def.body, name) || mentions(body, name),
+    }
+}
+
+fn collect_names(expr: &Expr, names: &mut HashSet<String>) {
+    match expr {
+        Expr::F64(..) | Expr::Complex(..) => {}
+        Expr::Var(v, _) => {
+            names.insert(v.clone());
+        }
+        Expr::Call(f, args, _) => {
+            names.insert(f.clone());
+            for arg in args {
+                collect_names(arg, names);
+            }
+        }
+        Expr::If(cond, a, b, _) => {
+            collect_names(cond, names);
+            collect_names(a, names);
+            collect_names(b, names);
+        }
+        Expr::Let(name, value, body, _) => {
+            names.insert(name.clone());
+            collect_names(value, names);
+            collect_names(body, names);
+        }
+        Expr::Def(def, body, _) => {
+            names.insert(def.name.clone());
+            names.extend(def.params.iter().cloned());
+            collect_names(&def.body, names);
+            collect_names(body, names);
+        }
+    }
+}
+
+// Structural equality, ignoring spans
+fn same(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::F64(x, _), Expr::F64(y, _)) => x == y,
+        (Expr::Complex(x0, y0, _), Expr::Complex(x1, y1, _)) => x0 == x1 && y0 == y1,
+        (Expr::Var(v, _), Expr::Var(w, _)) => v == w,
+        (Expr::Call(f, xs, _), Expr::Call(g, ys, _)) => f == g && xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| same(x, y)),
+        (Expr::If(c0, a0, b0, _), Expr::If(c1, a1, b1, _)) => same(c0, c1) && same(a0, a1) && same(b0, b1),
+        (Expr::Let(n0, v0, b0, _), Expr::Let(n1, v1, b1, _)) => n0 == n1 && same(v0, v1) && same(b0, b1),
+        (Expr::Def(d0, b0, _), Expr::Def(d1, b1, _)) => d0.name == d1.name && d0.params == d1.params && same(&d0.body, &d1.body) && same(b0, b1),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+
+    // The derivative, folded as it's built, has the same tree as the expected formula
+    fn assert_derivative(text: &str, var: &str, expected: &str) {
+        let d = derivative(&parse(text).unwrap(), var).unwrap();
+        assert!(same(&d, &parse(expected).unwrap()), "d/d{} {} gave {}, not {}", var, text, d, expected);
+    }
+
+    #[test]
+    fn differentiates_and_folds() {
+        assert_derivative("z^2 + c", "z", "2 * z");
+        assert_derivative("z^2 + c", "c", "1");
+        assert_derivative("exp(z)*z", "z", "exp(z) * z + exp(z)");
+        assert_derivative("a/z", "z", "-(a / z^2)");
+        assert_derivative("z^c", "z", "c * z^(c - 1)");
+        assert_derivative("2*(3*z)", "z", "6");
+        assert_derivative("z - z", "z", "0");
+        assert_derivative("if real(c) > 0 then z^2 else 1", "z", "if real(c) > 0 then 2 * z else 0");
+    }
+
+    #[test]
+    fn keeps_lets_and_defs_shared() {
+        assert_derivative("w = z*z; w*w", "z", "w = z * z; w_dz = 2 * z; 2 * (w_dz * w)");
+        assert_derivative("w = c; w*z", "z", "w = c; w");
+        assert_derivative("def f(x) = x^2 + c; f(f(z))", "z", "def f(x) = x^2 + c; def f_dx(x) = 2 * x; f_dx(f(z)) * f_dx(z)");
+        // The derivative's names don't clash with ones already taken
+        assert_derivative("w_dz = 1; w = z*z; w*w + w_dz", "z", "w = z * z; w_dz2 = 2 * z; 2 * (w_dz2 * w)");
+    }
+
+    #[test]
+    fn reports_what_it_cannot_differentiate() {
+        let err = |text: &str| derivative(&parse(text).unwrap(), "z").unwrap_err().err.to_string();
+        assert_eq!(err("real(z) + c"), "Cannot differentiate 'real'");
+        assert_eq!(err("sin(z, z)"), "'sin' takes 1 argument(s) but was given 2");
+        assert_eq!(err("def f(x) = x; f(z, z)"), "'f' takes 1 argument(s) but was given 2");
+        // A constant argument is fine whatever the function
+        assert_derivative("real(c) * z", "z", "real(c)");
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt;
+use wasm_bindgen::prelude::*;
+use crate::ast::Span;
+use crate::backend::BackendErr;
+use crate::check::CheckErr;
+use crate::frac::FracErr;
+use crate::parse::ParseErr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Field {
+    InitialZ,
+    Iteration,
+    Escape,
+    MaxIter,
+}
+
+impl Field {
+    pub const ALL: [Field; 4] = [Field::InitialZ, Field::Iteration, Field::Escape, Field::MaxIter];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Field::InitialZ => "initial z",
+            Field::Iteration => "iteration",
+            Field::Escape => "escape",
+            Field::MaxIter => "max iter",
+        }
+    }
+
+    // The section of a .frac document that holds this field
+    pub fn section(self) -> &'static str {
+        match self {
+            Field::InitialZ => "init",
+            Field::Iteration => "iterate",
+            Field::Escape => "escape",
+            Field::MaxIter => "maxiter",
+        }
+    }
+
+    // Same as the input element ids in index.html
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::InitialZ => "initial_z",
+            Field::Iteration => "iteration",
+            Field::Escape => "escape",
+            Field::MaxIter => "maxiter",
+        }
+    }
+}
+
+// An error from one of the passes that rework formulas, such as finding their rational form
+// or their derivative, along with the sub-expression it came from
+#[derive(Debug)]
+pub struct Spanned<E> {
+    pub span: Span,
+    pub err: E,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    // None for problems with the layout of a .frac document
+    field: Option<Field>,
+    offset: Option<usize>,
+    // Set when the error is about a whole sub-expression rather than a single position
+    end: Option<usize>,
+    message: String,
+    expected: Vec<String>,
+}
+
+#[wasm_bindgen]
+impl Diagnostic {
+    #[wasm_bindgen(getter)]
+    pub fn field(&self) -> Option<String> {
+        self.field.map(|f| f.name().to_owned())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn end(&self) -> Option<usize> {
+        self.end
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn expected(&self) -> Box<[JsValue]> {
+        self.expected.iter().map(JsValue::from).collect()
+    }
+}
+
+impl Diagnostic {
+    pub fn parse(field: Field, e: &ParseErr) -> Self {
+        Diagnostic {
+            field: Some(field),
+            offset: Some(e.offset()),
+            end: None,
+            message: e.description(),
+            expected: e.expected.clone(),
+        }
+    }
+
+    // A problem with what compile() was asked for, rather than with a field or a document
+    pub fn request(message: String) -> Self {
+        Diagnostic {
+            field: None,
+            offset: None,
+            end: None,
+            message,
+            expected: vec![],
+        }
+    }
+}
+
+impl From<CheckErr> for Diagnostic {
+    fn from(e: CheckErr) -> Self {
+        Diagnostic {
+            field: Some(e.field),
+            offset: Some(e.span.start),
+            end: Some(e.span.end),
+            message: e.message,
+            expected: vec![],
+        }
+    }
+}
+
+impl From<BackendErr> for Diagnostic {
+    fn from(e: BackendErr) -> Self {
+        Diagnostic {
+            field: Some(e.field),
+            offset: Some(e.span.start),
+            end: Some(e.span.end),
+            message: e.err.to_string(),
+            expected: vec![],
+        }
+    }
+}
+
+impl<E: fmt::Display> Spanned<E> {
+    pub fn in_field(self, field: Field) -> Diagnostic {
+        Diagnostic {
+            field: Some(field),
+            offset: Some(self.span.start),
+            end: Some(self.span.end),
+            message: self.err.to_string(),
+            expected: vec![],
+        }
+    }
+}
+
+impl From<FracErr> for Diagnostic {
+    fn from(e: FracErr) -> Self {
+        Diagnostic {
+            field: None,
+            offset: Some(e.offset),
+            end: None,
+            message: e.message,
+            expected: vec![],
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.field.map_or("document", |field| field.name());
+        match (self.offset, self.end) {
+            (Some(offset), Some(end)) => write!(f, "{}:{}-{}: {}", name, offset, end, self.message),
+            (Some(offset), None) => write!(f, "{}:{}: {}", name, offset, self.message),
+            _ => write!(f, "{}: {}", name, self.message),
+        }
+    }
+}
+
+// What compile() throws: one diagnostic per problem found
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct CompileError {
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[wasm_bindgen]
+impl CompileError {
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> usize {
+        self.diagnostics.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Diagnostic> {
+        self.diagnostics.get(index).cloned()
+    }
+
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl CompileError {
+    pub fn push(&mut self, d: Diagnostic) {
+        self.diagnostics.push(d);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    // Turns offsets within each field into offsets within the document the fields came from
+    pub fn relocate(&mut self, field_offset: impl Fn(Field) -> usize) {
+        for d in &mut self.diagnostics {
+            if let Some(field) = d.field {
+                let by = field_offset(field);
+                d.offset = d.offset.map(|x| x + by);
+                d.end = d.end.map(|x| x + by);
+            }
+        }
+    }
+}
+
+impl<T: Into<Diagnostic>> From<T> for CompileError {
+    fn from(e: T) -> Self {
+        CompileError {
+            diagnostics: vec![e.into()],
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, d) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CompileError {}
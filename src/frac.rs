@@ -0,0 +1,396 @@
+use std::fmt;
+use wasm_bindgen::prelude::*;
+use crate::backend::Solver;
+use crate::error::{CompileError, Field};
+use crate::palette::Palette;
+
+// A whole fractal in one document:
+//
+//     -- Comments start with two dashes
+//     init: 0
+//     iterate:
+//         w = z - c/z;
+//         w * w
+//     escape: sqabs(z) > 100
+//     maxiter: 1000
+//     view: -0.5 0 4
+//     palette: histogram gradient 0 #000764, 0.5 #ffffff
+//     solver: 50 1e-10 1
+//
+// A section starts with "name:" at the beginning of a line and runs until the next one.
+// The text of each section is kept exactly as written, comments and all, so that
+// printing a Frac gives back the document it was parsed from.
+#[derive(Clone, Debug, Default)]
+pub struct Frac {
+    // Comments and blank lines before the first section
+    preamble: String,
+    sections: Vec<Section>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    // Everything after the colon, up to and including the newline before the next section
+    pub text: String,
+    // Where text starts in the document
+    pub offset: usize,
+}
+
+#[derive(Debug)]
+pub struct FracErr {
+    pub offset: usize,
+    pub message: String,
+}
+
+// Center and width of the visible part of the plane
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct View {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+}
+
+const SECTIONS: &[&str] = &["init", "iterate", "escape", "maxiter", "view", "palette", "solver"];
+
+// Used when the document leaves escape or maxiter out
+const DEFAULT_ESCAPE: &str = " sqabs(z) > 100\n";
+const DEFAULT_MAXITER: &str = " 1000\n";
+
+impl Frac {
+    pub fn parse(source: &str) -> Result<Frac, FracErr> {
+        let mut frac = Frac::default();
+        let mut offset = 0;
+        for line in source.split_inclusive('\n') {
+            if let Some((name, colon)) = section_header(line) {
+                if !SECTIONS.contains(&name) {
+                    return Err(FracErr {
+                        offset,
+                        message: format!("Unknown section '{}'; expected one of {}", name, SECTIONS.join(", ")),
+                    });
+                }
+                if frac.get(name).is_some() {
+                    return Err(FracErr {
+                        offset,
+                        message: format!("Section '{}' appears more than once", name),
+                    });
+                }
+                frac.sections.push(Section {
+                    name: name.to_owned(),
+                    text: line[colon + 1..].to_owned(),
+                    offset: offset + colon + 1,
+                });
+            } else if let Some(section) = frac.sections.last_mut() {
+                section.text.push_str(line);
+            } else if is_comment_or_blank(line) {
+                frac.preamble.push_str(line);
+            } else {
+                return Err(FracErr {
+                    offset,
+                    message: "Expected a section such as 'iterate:'".to_owned(),
+                });
+            }
+            offset += line.len();
+        }
+        if frac.get("iterate").is_none() {
+            return Err(FracErr {
+                offset: source.len(),
+                message: "Missing the 'iterate:' section".to_owned(),
+            });
+        }
+        Ok(frac)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    // Replaces the text of a section, or adds it at the end. Everything else is left alone.
+    pub fn set(&mut self, name: &str, text: &str) {
+        let text = format!(" {}\n", text.trim());
+        match self.sections.iter_mut().find(|s| s.name == name) {
+            Some(section) => section.text = text,
+            None => {
+                if let Some(last) = self.sections.last_mut() {
+                    if !last.text.ends_with('\n') {
+                        last.text.push('\n');
+                    }
+                }
+                self.sections.push(Section { name: name.to_owned(), text, offset: 0 });
+            }
+        }
+        let mut offset = self.preamble.len();
+        for section in &mut self.sections {
+            offset += section.name.len() + 1;
+            section.offset = offset;
+            offset += section.text.len();
+        }
+    }
+
+    // The formula fields in Field::ALL order, each on one line as index.html has them. Only
+    // the sections whose text has changed are rewritten, so comments elsewhere survive.
+    pub fn set_fields(&mut self, texts: &[impl AsRef<str>]) {
+        for (field, text) in Field::ALL.iter().zip(texts) {
+            let text = text.as_ref();
+            if one_line(self.field(*field).0) != text.trim() {
+                self.set(field.section(), text);
+            }
+        }
+    }
+
+    // The text of a formula field, with its offset in the document
+    pub fn field(&self, field: Field) -> (&str, usize) {
+        match (self.get(field.section()), field) {
+            (Some(section), _) => (&section.text, section.offset),
+            (None, Field::Escape) => (DEFAULT_ESCAPE, 0),
+            (None, Field::MaxIter) => (DEFAULT_MAXITER, 0),
+            (None, _) => ("", 0),
+        }
+    }
+
+    pub fn view(&self) -> Result<Option<View>, FracErr> {
+        let section = match self.get("view") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let err = || FracErr {
+            offset: section.offset,
+            message: "Expected three numbers for 'view': center x, center y and width".to_owned(),
+        };
+        let numbers = strip_comments(&section.text)
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| err())?;
+        match numbers[..] {
+            [x, y, width] if width > 0.0 => Ok(Some(View { x, y, width })),
+            _ => Err(err()),
+        }
+    }
+
+    pub fn set_view(&mut self, view: View) {
+        self.set("view", &format!("{} {} {}", view.x, view.y, view.width));
+    }
+
+    // Only used when there's no initial z and no formula for the critical points
+    pub fn solver(&self) -> Result<Option<Solver>, FracErr> {
+        let section = match self.get("solver") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let err = || FracErr {
+            offset: section.offset,
+            message: "Expected three numbers for 'solver': steps, tolerance and damping".to_owned(),
+        };
+        let numbers = strip_comments(&section.text)
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| err())?;
+        match numbers[..] {
+            [steps, tolerance, damping] => Solver::new(steps, tolerance, damping).map(Some).ok_or_else(err),
+            _ => Err(err()),
+        }
+    }
+
+    // How render_rgba colours pixels; see Palette
+    pub fn palette(&self) -> Result<Option<Palette>, FracErr> {
+        let section = match self.get("palette") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let text = strip_comments(&section.text);
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        Palette::parse(&text)
+            .map(Some)
+            .map_err(|message| FracErr { offset: section.offset, message })
+    }
+}
+
+// A .frac document being edited through the single-line fields of index.html
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct FracDoc {
+    frac: Frac,
+}
+
+#[wasm_bindgen]
+impl FracDoc {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> FracDoc {
+        FracDoc::default()
+    }
+
+    pub fn parse(source: &str) -> Result<FracDoc, JsValue> {
+        let frac = Frac::parse(source).map_err(CompileError::from)?;
+        frac.view().map_err(CompileError::from)?;
+        frac.solver().map_err(CompileError::from)?;
+        frac.palette().map_err(CompileError::from)?;
+        Ok(FracDoc { frac })
+    }
+
+    // The fields in the same order as compile() takes them, each on one line
+    pub fn fields(&self) -> Box<[JsValue]> {
+        Field::ALL
+            .iter()
+            .map(|f| JsValue::from(one_line(self.frac.field(*f).0)))
+            .collect()
+    }
+
+    #[allow(clippy::boxed_local)]
+    pub fn set_fields(&mut self, texts: Box<[JsValue]>) {
+        let texts: Vec<String> = texts.iter().map(|t| t.as_string().unwrap_or_default()).collect();
+        self.frac.set_fields(&texts);
+    }
+
+    // [center x, center y, width]
+    pub fn view(&self) -> Option<Box<[f64]>> {
+        let view = self.frac.view().ok()??;
+        Some(Box::new([view.x, view.y, view.width]))
+    }
+
+    pub fn set_view(&mut self, x: f64, y: f64, width: f64) {
+        self.frac.set_view(View { x, y, width });
+    }
+
+    // [steps, tolerance, damping], as compile() takes them
+    pub fn solver(&self) -> Option<Box<[f64]>> {
+        let solver = self.frac.solver().ok()??;
+        Some(Box::new([solver.steps as f64, solver.tolerance, solver.damping]))
+    }
+
+    // The palette as compile() takes it, on one line
+    pub fn palette(&self) -> Option<String> {
+        self.frac.get("palette").map(|section| one_line(&section.text))
+    }
+
+    pub fn set_palette(&mut self, text: &str) {
+        if self.palette().as_deref().unwrap_or("") != text.trim() {
+            self.frac.set("palette", text);
+        }
+    }
+
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        self.frac.to_string()
+    }
+}
+
+impl fmt::Display for Frac {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.preamble)?;
+        for section in &self.sections {
+            write!(f, "{}:{}", section.name, section.text)?;
+        }
+        Ok(())
+    }
+}
+
+// "name:" at the very start of a line. Returns the name and the position of the colon.
+fn section_header(line: &str) -> Option<(&str, usize)> {
+    let len = line.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))?;
+    if len == 0 || !line.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let colon = len + line[len..].find(|c: char| c != ' ' && c != '\t')?;
+    if line[colon..].starts_with(':') {
+        Some((&line[..len], colon))
+    } else {
+        None
+    }
+}
+
+fn is_comment_or_blank(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with("--")
+}
+
+fn one_line(text: &str) -> String {
+    strip_comments(text).split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// As in formulas, "--" only starts a comment at the start of a line or after whitespace
+fn strip_comments(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let before = |i: usize| &line[..i];
+            let start = line.match_indices("--").map(|(i, _)| i).find(|i| before(*i).is_empty() || before(*i).ends_with(char::is_whitespace));
+            start.map_or(line, before)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = "-- A preamble\n\n-- over two comments\ninit: 0\niterate:\n    w = z - c/z; -- halfway\n    w * w\nescape: sqabs(z) > 100\nview: -0.5 0 4\n";
+
+    // Every formula field's offset points at its text in the printed document
+    fn assert_offsets(frac: &Frac) {
+        let printed = frac.to_string();
+        for field in Field::ALL.iter() {
+            if let Some(section) = frac.get(field.section()) {
+                assert_eq!(&printed[section.offset..section.offset + section.text.len()], section.text);
+            }
+        }
+    }
+
+    #[test]
+    fn prints_what_it_parsed() {
+        for source in [
+            DOC,
+            "iterate: z*z + c",
+            "iterate: z*z + c\nmaxiter: 50",
+            "\n\n-- only a preamble before\niterate:z^3 + c\r\nescape:  abs(z) > 2 -- trailing\r\n",
+            "iterate:\n\n\n",
+        ].iter() {
+            let frac = Frac::parse(source).unwrap();
+            assert_eq!(frac.to_string(), *source);
+            assert_offsets(&frac);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_sections() {
+        let err = Frac::parse("iterate: z\nitrate: c\n").unwrap_err();
+        assert_eq!(err.offset, 11);
+        assert!(err.message.starts_with("Unknown section 'itrate'"));
+
+        let err = Frac::parse("iterate: z\ninit: 0\niterate: c\n").unwrap_err();
+        assert_eq!(err.offset, 19);
+        assert_eq!(err.message, "Section 'iterate' appears more than once");
+
+        let err = Frac::parse("z*z + c\n").unwrap_err();
+        assert_eq!(err.offset, 0);
+
+        let err = Frac::parse("-- nothing\ninit: 0\n").unwrap_err();
+        assert_eq!(err.message, "Missing the 'iterate:' section");
+    }
+
+    #[test]
+    fn sets_a_missing_section_at_the_end() {
+        let mut frac = Frac::parse("-- hi\niterate: z*z + c").unwrap();
+        frac.set("escape", "abs(z) > 2");
+        assert_eq!(frac.to_string(), "-- hi\niterate: z*z + c\nescape: abs(z) > 2\n");
+        assert_eq!(frac.field(Field::Escape), (" abs(z) > 2\n", 30));
+        assert_offsets(&frac);
+    }
+
+    #[test]
+    fn set_fields_keeps_unchanged_sections() {
+        let mut frac = Frac::parse(DOC).unwrap();
+        let mut fields: Vec<String> = Field::ALL.iter().map(|f| one_line(frac.field(*f).0)).collect();
+        // maxiter isn't in the document, and stays out while it's the default
+        assert_eq!(fields, ["0", "w = z - c/z; w * w", "sqabs(z) > 100", "1000"]);
+        fields[0] = "0.5".to_owned();
+        fields[2] = "abs(z) > 3".to_owned();
+        frac.set_fields(&fields);
+        let printed = frac.to_string();
+        assert_eq!(printed, "-- A preamble\n\n-- over two comments\ninit: 0.5\niterate:\n    w = z - c/z; -- halfway\n    w * w\nescape: abs(z) > 3\nview: -0.5 0 4\n");
+        assert_offsets(&frac);
+        assert_eq!(Frac::parse(&printed).unwrap().to_string(), printed);
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::ast::Span;
+use crate::error::Spanned;
+use crate::rational::{cx_abs, cx_div, cx_mul, Coeff, Cx, Rational, RationalErr};
+
+// Functional square roots: maps h with h(h(z)) equal to a target such as
+//
+//     (z^4 + c + 1) / (2*z^2 + c)
+//
+// h comes from an ansatz like (A*z^2 + B) / (D*z^2 + E). Its unknowns are the names the
+// target doesn't use, other than c. Composing the ansatz with itself and cross-multiplying
+// against the target gives a polynomial in z whose coefficients must all vanish, for every
+// value of c as well unless c is given a value. Those equations are solved numerically by
+// Levenberg-Marquardt from many starting points.
+
+const STARTS: usize = 256;
+const STEPS: usize = 100;
+// How small the equations have to get, relative to the size of their terms
+const TOLERANCE: f64 = 1e-10;
+
+const SPAN: Span = Span { start: 0, end: 0 };
+
+#[derive(Debug)]
+pub enum SearchErr {
+    NoUnknowns,
+    Rational(RationalErr),
+}
+
+pub struct Search {
+    pub unknowns: Vec<String>,
+    pub equations: usize,
+    pub solutions: Vec<Rational>,
+}
+
+impl fmt::Display for SearchErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SearchErr::NoUnknowns => write!(f, "Expected unknown coefficients, such as A and B, that the target doesn't use"),
+            SearchErr::Rational(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl SearchErr {
+    pub fn at(self, span: Span) -> Spanned<SearchErr> {
+        Spanned { span, err: self }
+    }
+}
+
+// values fixes some of the target's parameters, usually c, to numbers
+pub fn search(target: &Rational, ansatz: &Rational, values: &BTreeMap<String, Cx>) -> Result<Search, SearchErr> {
+    let known = target.params();
+    let unknowns: Vec<String> =
+        ansatz.params().into_iter().filter(|p| p != "c" && !known.contains(p) && !values.contains_key(p)).collect();
+    if unknowns.is_empty() {
+        return Err(SearchErr::NoUnknowns);
+    }
+    let target = target.substitute(values).ok_or(SearchErr::Rational(RationalErr::DivideByZero))?;
+    let ansatz = ansatz.substitute(values).ok_or(SearchErr::Rational(RationalErr::DivideByZero))?;
+    let is_unknown = |v: &str| unknowns.iter().any(|u| u == v);
+    let hh = ansatz.compose_unreduced(&ansatz).map_err(SearchErr::Rational)?;
+    let mut equations: Vec<Coeff> = hh.difference(&target).coeffs().iter().flat_map(|c| c.split(is_unknown)).collect();
+    // Where all of these vanish, h(h(z)) is 0/0 and the equations hold for no good reason
+    let denominator: Vec<Coeff> = hh.den.coeffs().iter().flat_map(|c| c.split(is_unknown)).collect();
+    // When every equation is homogeneous of the same degree, any multiple of a solution is
+    // one too, down to the useless zero. Fixing a random linear combination of the unknowns
+    // to 1 picks one from each line.
+    let count = equations.len();
+    let mut seed = 1;
+    let degrees: Vec<_> = equations.iter().map(Coeff::homogeneous_degree).collect();
+    if degrees.iter().all(|d| d.is_some() && *d == degrees[0]) {
+        let chart = unknowns.iter().fold(Coeff::constant((-1.0, 0.0)), |sum, u| {
+            sum.add(&Coeff::param(u).mul(&Coeff::constant((random(&mut seed), random(&mut seed)))))
+        });
+        equations.push(chart);
+    }
+    let jacobian: Vec<Vec<Coeff>> = equations.iter().map(|e| unknowns.iter().map(|u| e.derivative(u)).collect()).collect();
+
+    let mut solutions: Vec<Rational> = vec![];
+    for _ in 0..STARTS {
+        let start = unknowns.iter().map(|_| (random(&mut seed), random(&mut seed))).collect();
+        let mut x = match levenberg_marquardt(&equations, &jacobian, &unknowns, start) {
+            Some(x) => x,
+            None => continue,
+        };
+        // Unknowns that the search has all but zeroed are zero; left in, they'd become
+        // huge when h is scaled to make its denominator's leading number 1
+        let largest = x.iter().map(|x| cx_abs(*x)).fold(0.0, f64::max);
+        for x in &mut x {
+            if cx_abs(*x) <= 1e-9 * largest {
+                *x = (0.0, 0.0);
+            }
+        }
+        let (values, sizes) = (bind(&unknowns, &x), sizes(&unknowns, &x));
+        if denominator.iter().all(|d| cx_abs(d.eval(&values)) <= 1e-6 * d.magnitude(&sizes)) {
+            continue;
+        }
+        // Check the answer, now that the numbers are known and common factors can cancel
+        let h = match ansatz.substitute(&values) {
+            Some(h) if h.compose(&h).is_ok_and(|hh| hh.approx_eq(&target, 1e-6)) => h.map_numbers(|x| (snap(x.0), snap(x.1))),
+            _ => continue,
+        };
+        let text = h.to_expr(SPAN).to_string();
+        if !solutions.iter().any(|s| s.to_expr(SPAN).to_string() == text) {
+            solutions.push(h);
+        }
+    }
+    solutions.sort_by_key(|h| !h.is_real());
+    Ok(Search { unknowns, equations: count, solutions })
+}
+
+// Levenberg-Marquardt on the complex unknowns, stopping once the equations hold: Newton
+// steps on the least squares problem, shortened towards steepest descent whenever one
+// would make things worse. The ansatz usually leaves some freedom, such as scaling the
+// top and bottom together, so the solutions aren't isolated and the plain Newton system
+// would be singular; the damping copes with that too.
+fn levenberg_marquardt(equations: &[Coeff], jacobian: &[Vec<Coeff>], unknowns: &[String], mut x: Vec<Cx>) -> Option<Vec<Cx>> {
+    let n = x.len();
+    let residual = |x: &[Cx]| {
+        let values = bind(unknowns, x);
+        let f: Vec<Cx> = equations.iter().map(|e| e.eval(&values)).collect();
+        let cost: f64 = f.iter().map(|y| y.0 * y.0 + y.1 * y.1).sum();
+        (f, cost)
+    };
+    let (mut f, mut cost) = residual(&x);
+    let mut lambda = 1e-3;
+    for _ in 0..STEPS {
+        if !cost.is_finite() {
+            return None;
+        }
+        // Each equation is compared with the size of its terms
+        let sizes = sizes(unknowns, &x);
+        if equations.iter().zip(&f).all(|(e, y)| cx_abs(*y) <= TOLERANCE * e.magnitude(&sizes)) {
+            return Some(x);
+        }
+        let values = bind(unknowns, &x);
+        let j: Vec<Vec<Cx>> = jacobian.iter().map(|row| row.iter().map(|d| d.eval(&values)).collect()).collect();
+        // J^H J dx = -J^H f, as an augmented matrix
+        let mut a = vec![vec![(0.0, 0.0); n + 1]; n];
+        for r in 0..n {
+            for (row, y) in j.iter().zip(&f) {
+                let conj = (row[r].0, -row[r].1);
+                for col in 0..n {
+                    a[r][col] = cx_add(a[r][col], cx_mul(conj, row[col]));
+                }
+                a[r][n] = cx_add(a[r][n], cx_mul(conj, (-y.0, -y.1)));
+            }
+        }
+        let scale = (0..n).map(|r| a[r][r].0).fold(f64::MIN_POSITIVE, f64::max);
+        loop {
+            let mut damped = a.clone();
+            for (r, row) in damped.iter_mut().enumerate() {
+                row[r].0 += lambda * scale;
+            }
+            let next: Vec<Cx> = x.iter().zip(solve(damped)?).map(|(x, dx)| cx_add(*x, dx)).collect();
+            let (next_f, next_cost) = residual(&next);
+            if next_cost < cost {
+                (x, f, cost) = (next, next_f, next_cost);
+                lambda = (lambda / 3.0).max(1e-15);
+                break;
+            }
+            lambda *= 4.0;
+            if lambda > 1e10 {
+                return None;
+            }
+        }
+    }
+    None
+}
+
+// Gaussian elimination with partial pivoting on an augmented matrix
+fn solve(mut a: Vec<Vec<Cx>>) -> Option<Vec<Cx>> {
+    let n = a.len();
+    for k in 0..n {
+        let pivot = (k..n).max_by(|&r, &s| cx_abs(a[r][k]).total_cmp(&cx_abs(a[s][k])))?;
+        if cx_abs(a[pivot][k]) == 0.0 {
+            return None;
+        }
+        a.swap(k, pivot);
+        let (above, below) = a.split_at_mut(k + 1);
+        let pivot = &above[k];
+        for row in below {
+            let m = cx_div(row[k], pivot[k]);
+            for (y, p) in row[k..].iter_mut().zip(&pivot[k..]) {
+                let t = cx_mul(m, *p);
+                *y = (y.0 - t.0, y.1 - t.1);
+            }
+        }
+    }
+    let mut x = vec![(0.0, 0.0); n];
+    for k in (0..n).rev() {
+        let mut sum = a[k][n];
+        for (p, y) in a[k][k + 1..n].iter().zip(&x[k + 1..]) {
+            let t = cx_mul(*p, *y);
+            sum = (sum.0 - t.0, sum.1 - t.1);
+        }
+        x[k] = cx_div(sum, a[k][k]);
+    }
+    Some(x)
+}
+
+fn bind(unknowns: &[String], x: &[Cx]) -> BTreeMap<String, Cx> {
+    unknowns.iter().cloned().zip(x.iter().copied()).collect()
+}
+
+// The size of each unknown, counted as at least a little of the largest, for judging
+// whether something is zero. Otherwise an equation such as B*(A + E) = 0 could never
+// pass at B = 0, its terms being as small as its value.
+fn sizes(unknowns: &[String], x: &[Cx]) -> BTreeMap<String, Cx> {
+    let largest = x.iter().map(|x| cx_abs(*x)).fold(0.0, f64::max);
+    unknowns.iter().cloned().zip(x.iter().map(|x| (cx_abs(*x).max(1e-3 * largest), 0.0))).collect()
+}
+
+fn cx_add(a: Cx, b: Cx) -> Cx {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+// Rounds away what levenberg_marquardt leaves behind, so that the same solution found from
+// two starts prints the same
+fn snap(x: f64) -> f64 {
+    let rounded = (x * 1e8).round() / 1e8;
+    if rounded == 0.0 {
+        0.0
+    } else {
+        rounded
+    }
+}
+
+// A reproducible spread of starting points in [-2, 2)
+fn random(seed: &mut u64) -> f64 {
+    *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    (*seed >> 11) as f64 / (1u64 << 53) as f64 * 4.0 - 2.0
+}
+
+// One line per solution, the real ones first:
+//
+//     unknowns A, B, D, E; 9 equations
+//     h(z) = (z^2 + c) / (-z^2 - 1)
+//     h(z) = ...   (complex)
+impl fmt::Display for Search {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "unknowns {}; {} equations", self.unknowns.join(", "), self.equations)?;
+        if self.solutions.is_empty() {
+            writeln!(f, "no solutions found from {} starts", STARTS)?;
+        }
+        for h in &self.solutions {
+            let text = h.to_expr(SPAN);
+            if h.is_real() {
+                writeln!(f, "h(z) = {}", text)?;
+            } else {
+                writeln!(f, "h(z) = {}   (complex)", text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+    use crate::rational::rational;
+
+    fn rat(text: &str) -> Rational {
+        rational(&parse(text).unwrap()).unwrap()
+    }
+
+    // Searches for square roots of h(h(z)), which should find h itself
+    fn assert_recovers(h: &str, ansatz: &str, values: &[(&str, f64)]) {
+        let h = rat(h);
+        let values = values.iter().map(|(v, x)| (v.to_string(), (*x, 0.0))).collect();
+        let search = search(&h.compose(&h).unwrap(), &rat(ansatz), &values).unwrap();
+        let h = h.substitute(&values).unwrap();
+        assert!(search.solutions.iter().any(|s| s.approx_eq(&h, 1e-9)), "no {} in\n{}", h.to_expr(SPAN), search);
+    }
+
+    #[test]
+    fn recovers_a_known_square_root() {
+        assert_recovers("(2*z^2 + 1)/(z^2 + 3)", "(A*z^2 + B)/(D*z^2 + E)", &[]);
+        assert_recovers("(z^2 + c)/(z^2 - 1)", "(A*z^2 + c)/(D*z^2 + E)", &[]);
+        assert_recovers("(z^2 + c)/(z^2 - 1)", "(A*z^2 + B)/(D*z^2 + E)", &[("c", 0.5)]);
+    }
+
+    #[test]
+    fn stops_once_the_equations_hold() {
+        let unknowns = ["A".to_owned(), "B".to_owned()];
+        let solve = |equations: &[&str]| {
+            let equations: Vec<Coeff> = equations.iter().map(|e| rat(e).num.coeffs()[0].clone()).collect();
+            let jacobian: Vec<Vec<Coeff>> = equations.iter().map(|e| unknowns.iter().map(|u| e.derivative(u)).collect()).collect();
+            levenberg_marquardt(&equations, &jacobian, &unknowns, vec![(1.0, 0.5), (-0.5, 1.0)])
+        };
+        let x = solve(&["A^2 - 4", "A*B - 1"]).unwrap();
+        let b = cx_div((1.0, 0.0), x[0]);
+        assert!((cx_abs(x[0]) - 2.0).abs() < 1e-9 && cx_abs((x[1].0 - b.0, x[1].1 - b.1)) < 1e-9, "{:?}", x);
+        // More equations than unknowns is fine as long as they agree
+        assert!(solve(&["A - 3", "B + 1", "A + 3*B"]).is_some());
+        assert!(solve(&["A - 1", "A - 2", "B"]).is_none());
+    }
+
+    #[test]
+    fn needs_unknowns() {
+        let h = rat("(z^2 + c)/(z^2 - 1)");
+        assert!(matches!(search(&h, &rat("(z^2 + c)/(z^2 - c)"), &BTreeMap::new()), Err(SearchErr::NoUnknowns)));
+    }
+}
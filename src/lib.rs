@@ -1,18 +1,311 @@
+use std::collections::BTreeMap;
 use wasm_bindgen::prelude::*;
 
 mod ast;
 mod backend;
+mod check;
 mod dag;
+mod diff;
+mod error;
+mod frac;
+mod fsqrt;
+mod mathlib;
 mod module_builder;
+mod palette;
 mod parse;
+mod print;
+mod rational;
 
+use backend::{Axis, Params, Plane, Solver};
+use error::{CompileError, Diagnostic, Field};
+use frac::{Frac, View};
+use palette::Palette;
+
+// texts are the initial z, iteration, escape and max iter fields, in that order.
+// An empty initial z means "solve for a critical point". solver is [steps, tolerance,
+// damping] for that, as FracDoc.solver() gives them, or undefined for the defaults.
+//
+// params names more complex variables like c, such as ["a"], which the module takes after
+// c as two more arguments each.
+//
+// pixel names the variable the pixel is, c by default, or else two of the real axes of c
+// and the parameters, such as "real(a) imag(c)", for a slice through them. A name that
+// isn't c or a parameter gives the Julia plane: z itself, or a variable the initial z is
+// written in. There julia_c, as [x, y], fixes c; left undefined, c is passed to the module
+// along with the pixel.
+//
+// The iteration can start with declarations such as "param a = 0.5;". Those parameters
+// aren't arguments but mutable globals, exported as a_x and a_y, so they can be changed
+// between calls without compiling again. They can't be axes of the pixel.
+//
+// palette describes how render_rgba colours pixels, as in a .frac's palette: section, such
+// as "histogram gradient 0 #000764, 0.5 #ffffff". Left undefined or empty, it's the default.
+#[allow(clippy::boxed_local)]
+#[wasm_bindgen]
+pub fn compile(texts: Box<[JsValue]>, solver: Option<Box<[f64]>>, pixel: Option<String>, julia_c: Option<Box<[f64]>>, params: Option<Box<[JsValue]>>, palette: Option<String>) -> Result<Box<[u8]>, JsValue> {
+    let texts: Vec<String> = (0..Field::ALL.len())
+        .map(|i| texts.get(i).and_then(JsValue::as_string).unwrap_or_default())
+        .collect();
+    let params: Vec<String> = params.unwrap_or_default().iter().filter_map(JsValue::as_string).map(|p| p.trim().to_owned()).collect();
+    let mut errors = CompileError::default();
+    let solver = solver_settings(solver.as_deref()).unwrap_or_else(|message| {
+        errors.push(Diagnostic::request(message));
+        Solver::default()
+    });
+    for message in check::check_params(&params) {
+        errors.push(Diagnostic::request(message));
+    }
+    let pixel = pixel.as_deref().map(str::trim).filter(|p| !p.is_empty()).unwrap_or("c");
+    let julia_c = julia_c.as_deref().and_then(|c| match *c {
+        [x, y] => Some((x, y)),
+        _ => None,
+    });
+    let plane = match plane(pixel, &params, julia_c) {
+        Ok(plane) => plane,
+        Err(message) => {
+            errors.push(Diagnostic::request(message));
+            Plane::parameter("c")
+        }
+    };
+    let palette = match palette.as_deref().map(str::trim).filter(|p| !p.is_empty()).map(Palette::parse) {
+        Some(Ok(palette)) => palette,
+        Some(Err(message)) => {
+            errors.push(Diagnostic::request(message));
+            Palette::default()
+        }
+        None => Palette::default(),
+    };
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+    compile_fields(&texts, &solver, &params, &plane, &palette)
+        .map(Vec::into_boxed_slice)
+        .map_err(JsValue::from)
+}
+
+// The solver as compile() takes it: steps, tolerance and damping, checked as in a .frac
+fn solver_settings(numbers: Option<&[f64]>) -> Result<Solver, String> {
+    let solver = match numbers {
+        None => return Ok(Solver::default()),
+        Some(&[steps, tolerance, damping]) => Solver::new(steps, tolerance, damping),
+        Some(_) => None,
+    };
+    solver.ok_or_else(|| "Expected the solver's steps to be a whole number from 1, and its tolerance and damping to be above 0".to_owned())
+}
+
+// The plane a pixel such as "c", "z" or "real(a) imag(c)" is in
+fn plane(pixel: &str, params: &[String], julia_c: Option<(f64, f64)>) -> Result<Plane, String> {
+    let is_param = |name: &str| name == "c" || params.iter().any(|p| p == name);
+    if !pixel.contains('(') {
+        if is_param(pixel) {
+            return Ok(Plane::parameter(pixel));
+        }
+        return Ok(Plane::Julia { pixel: pixel.to_owned(), c: julia_c });
+    }
+    let split = pixel.find(')').map_or(pixel.len(), |i| i + 1);
+    let (x, y) = match (Axis::parse(&pixel[..split]), Axis::parse(&pixel[split..])) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Err(format!("Expected two axes for the pixel, such as 'real(c) imag(a)', found '{}'", pixel)),
+    };
+    for axis in [&x, &y].iter() {
+        if !is_param(&axis.name) {
+            return Err(format!("'{}' isn't c or a parameter passed in, so it can't be an axis of the pixel", axis.name));
+        }
+    }
+    if x == y {
+        return Err(format!("The pixel's x and y are both {}", x));
+    }
+    Ok(Plane::Parameter { x, y })
+}
+
+// The derivative of a formula with respect to var, usually z or c, as a formula. The
+// formula is checked as the iteration field would be by compile(), given the same params,
+// and errors are reported against the iteration field. var counts as one of the params
+// if it isn't z or c.
+#[allow(clippy::boxed_local)]
+#[wasm_bindgen]
+pub fn derivative(text: &str, var: &str, params: Option<Box<[JsValue]>>) -> Result<String, JsValue> {
+    let (declared, expr) = parse::parse_declared(text).map_err(|e| CompileError::from(Diagnostic::parse(Field::Iteration, &e)))?;
+    let mut params: Vec<String> = params.unwrap_or_default().iter().filter_map(JsValue::as_string).map(|p| p.trim().to_owned()).collect();
+    if var != "z" && var != "c" && !params.iter().any(|p| p == var) {
+        params.push(var.to_owned());
+    }
+    let mut errors = CompileError::default();
+    for message in check::check_params(&params) {
+        errors.push(Diagnostic::request(message));
+    }
+    for e in check::check_iteration(&expr, &params, &declared) {
+        errors.push(e.into());
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+    let d = diff::derivative(&expr, var).map_err(|e| CompileError::from(e.in_field(Field::Iteration)))?;
+    Ok(d.to_string())
+}
+
+// A formula as one polynomial in z over another, with the degree and coefficients of each
+#[wasm_bindgen]
+pub fn rational_form(text: &str) -> Result<String, JsValue> {
+    Ok(to_rational(text)?.to_string())
+}
+
+// f(g(z)) as one polynomial in z over another
 #[wasm_bindgen]
-pub fn compile(texts: Box<[JsValue]>) -> Box<[u8]> {
-    if texts[0].as_string().unwrap().is_empty() {
-        let exprs:Vec<_> = texts[1..].iter().map(|text|parse::parse(&text.as_string().unwrap()).unwrap()).collect();
-        backend::backend(None, &exprs[0], &exprs[1], &exprs[2]).into_boxed_slice()
-    } else {
-        let exprs:Vec<_> = texts.iter().map(|text|parse::parse(&text.as_string().unwrap()).unwrap()).collect();
-        backend::backend(Some(&exprs[0]), &exprs[1], &exprs[2], &exprs[3]).into_boxed_slice()
+pub fn compose(f: &str, g: &str) -> Result<String, JsValue> {
+    let span = ast::Span { start: 0, end: f.len() };
+    let composed = to_rational(f)?.compose(&to_rational(g)?).map_err(|e| CompileError::from(e.at(span).in_field(Field::Iteration)))?;
+    Ok(composed.to_string())
+}
+
+// Searches for h of the form ansatz with h(h(z)) = target, such as
+// (A*z^2 + B) / (D*z^2 + E) for (z^4 + c + 1) / (2*z^2 + c). Given c_x and c_y, c is fixed
+// to that value; otherwise the coefficients of h have to work for every c.
+#[wasm_bindgen]
+pub fn functional_sqrt(target: &str, ansatz: &str, c_x: Option<f64>, c_y: Option<f64>) -> Result<String, JsValue> {
+    let span = ast::Span { start: 0, end: ansatz.len() };
+    let values = match (c_x, c_y) {
+        (None, None) => BTreeMap::new(),
+        (x, y) => BTreeMap::from([("c".to_owned(), (x.unwrap_or(0.0), y.unwrap_or(0.0)))]),
+    };
+    let search = fsqrt::search(&to_rational(target)?, &to_rational(ansatz)?, &values)
+        .map_err(|e| CompileError::from(e.at(span).in_field(Field::Iteration)))?;
+    Ok(search.to_string())
+}
+
+fn to_rational(text: &str) -> Result<rational::Rational, CompileError> {
+    let (_, expr) = parse::parse_declared(text).map_err(|e| Diagnostic::parse(Field::Iteration, &e))?;
+    Ok(rational::rational(&expr).map_err(|e| e.in_field(Field::Iteration))?)
+}
+
+// What compile_frac() returns: the module and the metadata from the document
+#[wasm_bindgen]
+pub struct Program {
+    wasm: Vec<u8>,
+    view: Option<View>,
+}
+
+#[wasm_bindgen]
+impl Program {
+    #[wasm_bindgen(getter)]
+    pub fn wasm(&self) -> Box<[u8]> {
+        self.wasm.clone().into_boxed_slice()
+    }
+
+    // [center x, center y, width]
+    #[wasm_bindgen(getter)]
+    pub fn view(&self) -> Option<Box<[f64]>> {
+        self.view.map(|v| Box::new([v.x, v.y, v.width]) as Box<[f64]>)
+    }
+}
+
+// Compiles a whole .frac document. Diagnostic offsets are within the document.
+#[wasm_bindgen]
+pub fn compile_frac(source: &str) -> Result<Program, JsValue> {
+    let frac = Frac::parse(source).map_err(CompileError::from)?;
+    let view = frac.view().map_err(CompileError::from)?;
+    let solver = frac.solver().map_err(CompileError::from)?.unwrap_or_default();
+    let palette = frac.palette().map_err(CompileError::from)?.unwrap_or_default();
+    let texts: Vec<&str> = Field::ALL.iter().map(|f| frac.field(*f).0).collect();
+    let wasm = compile_fields(&texts, &solver, &[], &Plane::parameter("c"), &palette).map_err(|mut e| {
+        e.relocate(|f| frac.field(f).1);
+        e
+    })?;
+    Ok(Program { wasm, view })
+}
+
+fn compile_fields(texts: &[impl AsRef<str>], solver: &Solver, params: &[String], plane: &Plane, palette: &Palette) -> Result<Vec<u8>, CompileError> {
+    let mut errors = CompileError::default();
+    let mut exprs = vec![];
+    // Parameters are declared at the start of the iteration
+    let mut declared = vec![];
+    for (field, text) in Field::ALL.iter().zip(texts) {
+        let text = text.as_ref();
+        if *field == Field::InitialZ && parse::is_blank(text) {
+            exprs.push(None);
+            continue;
+        }
+        let parsed = match field {
+            Field::Iteration => parse::parse_declared(text).map(|(decls, expr)| {
+                declared = decls;
+                expr
+            }),
+            _ => parse::parse(text),
+        };
+        match parsed {
+            Ok(expr) => exprs.push(Some(expr)),
+            Err(e) => {
+                errors.push(Diagnostic::parse(*field, &e));
+                exprs.push(None);
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let required = |i: usize| exprs[i].as_ref().unwrap();
+    let pixel = match plane {
+        Plane::Parameter { .. } => None,
+        Plane::Julia { pixel, .. } => Some(pixel.as_str()),
+    };
+    for e in check::check(exprs[0].as_ref(), required(1), required(2), required(3), params, &declared, pixel) {
+        errors.push(e.into());
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let params = Params {
+        passed: params.to_vec(),
+        declared: declared.iter().map(|d| (d.name.clone(), check::default_value(&d.default).unwrap())).collect(),
+    };
+    backend::backend(exprs[0].as_ref(), required(1), required(2), required(3), solver, &params, plane, palette).map_err(CompileError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Compiles a formula over the c plane and says whether it exports the distance estimate
+    fn exports_distance(init: &str, iteration: &str) -> bool {
+        let texts = [init, iteration, "sqabs(z) > 100", "100"];
+        let wasm = compile_fields(&texts, &Solver::default(), &[], &Plane::parameter("c"), &Palette::default())
+            .unwrap_or_else(|e| panic!("{}: {}", iteration, e));
+        wasm.windows(b"distance".len()).any(|w| w == b"distance")
+    }
+
+    #[test]
+    fn checks_solver_settings() {
+        assert_eq!(solver_settings(None), Ok(Solver::default()));
+        assert_eq!(solver_settings(Some(&[20.0, 1e-8, 0.5])), Ok(Solver { steps: 20, tolerance: 1e-8, damping: 0.5 }));
+        let bad: [&[f64]; 9] = [
+            &[0.0, 1e-8, 0.5],
+            &[2.5, 1e-8, 0.5],
+            &[f64::NAN, 1e-8, 0.5],
+            &[20.0, 0.0, 0.5],
+            &[20.0, f64::INFINITY, 0.5],
+            &[20.0, 1e-8, -1.0],
+            &[20.0, 1e-8, f64::NAN],
+            &[20.0, 1e-8],
+            &[],
+        ];
+        for numbers in bad.iter() {
+            assert!(solver_settings(Some(numbers)).is_err(), "{:?}", numbers);
+        }
+    }
+
+    #[test]
+    fn powers_with_varying_exponents_compile() {
+        assert!(exports_distance("0", "z^c + c"));
+        assert!(exports_distance("", "z^c + c"));
+        assert!(exports_distance("0.5", "z^z + c"));
+        assert!(exports_distance("", "z^2 + c"));
+    }
+
+    #[test]
+    fn conditions_bound_by_let_keep_the_distance_estimate() {
+        assert!(exports_distance("0", "b = real(z) > 0; if b then z^2 + c else z^3 + c"));
+        assert!(exports_distance("0", "b = real(z) > 0 and abs(z) < 1; if not b then z^2 + c else z^3 + c"));
+        assert!(exports_distance("0", "def f(b, w) = if b then w^2 else w^3; f(real(z) > 0, z) + c"));
+        assert!(!exports_distance("0", "w = conj(z); w^2 + c"));
     }
 }
@@ -0,0 +1,254 @@
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+use crate::dag::{Dag, DagNode, Effect, EffectType};
+use crate::module_builder::{Func, ModuleBuilder, ValType};
+
+// ln 2 split so that k * LN2_HI is exact for the k we care about
+const LN2_HI: f64 = 0.693_147_180_369_123_8;
+const LN2_LO: f64 = 1.908_214_929_270_587_7e-10;
+
+// WebAssembly has no exp/log/trig instructions, so every module carries its own
+// copies of these, written as straight-line code in the Dag.
+#[derive(Clone, Copy)]
+pub struct MathLib {
+    pub exp: Func,
+    pub log: Func,
+    pub sin: Func,
+    pub cos: Func,
+    pub atan2: Func,
+}
+
+pub fn mathlib(mut mb: ModuleBuilder) -> (ModuleBuilder, MathLib) {
+    let exp = unary(&mut mb, exp);
+    let log = unary(&mut mb, log);
+    let sin = unary(&mut mb, |dag, x| sin_cos(dag, x, 0));
+    let cos = unary(&mut mb, |dag, x| sin_cos(dag, x, 1));
+    let atan2 = binary(&mut mb, atan2);
+    (mb, MathLib { exp, log, sin, cos, atan2 })
+}
+
+fn unary(mb: &mut ModuleBuilder, body: impl Fn(&mut Dag, DagNode) -> DagNode) -> Func {
+    let f = mb.start_func(&[ValType::F64], &[ValType::F64]);
+    let mut dag = Dag::default();
+    let x = dag.f64_input(mb.get_local_param(0));
+    let result = body(&mut dag, x);
+    dag.emit(mb, &[Effect(EffectType::Push, result)]);
+    mb.end_func();
+    f
+}
+
+fn binary(mb: &mut ModuleBuilder, body: impl Fn(&mut Dag, DagNode, DagNode) -> DagNode) -> Func {
+    let f = mb.start_func(&[ValType::F64, ValType::F64], &[ValType::F64]);
+    let mut dag = Dag::default();
+    let x = dag.f64_input(mb.get_local_param(0));
+    let y = dag.f64_input(mb.get_local_param(1));
+    let result = body(&mut dag, x, y);
+    dag.emit(mb, &[Effect(EffectType::Push, result)]);
+    mb.end_func();
+    f
+}
+
+// coeffs[0] + x * (coeffs[1] + x * (...))
+fn horner(dag: &mut Dag, x: DagNode, coeffs: &[f64]) -> DagNode {
+    let mut result = dag.f64_const(coeffs[coeffs.len() - 1]);
+    for c in coeffs[..coeffs.len() - 1].iter().rev() {
+        let t = dag.f64_mul(result, x);
+        let c = dag.f64_const(*c);
+        result = dag.f64_add(t, c);
+    }
+    result
+}
+
+// 2^k for an integer-valued k in the normal exponent range
+fn pow2(dag: &mut Dag, k: DagNode) -> DagNode {
+    let k = dag.i64_trunc_sat_f64_s(k);
+    let bias = dag.i64_const(1023);
+    let e = dag.i64_add(k, bias);
+    let shift = dag.i64_const(52);
+    let bits = dag.i64_shl(e, shift);
+    dag.f64_reinterpret_i64(bits)
+}
+
+fn exp(dag: &mut Dag, x: DagNode) -> DagNode {
+    // Anything outside this range over- or underflows anyway
+    let hi = dag.f64_const(1400.0);
+    let lo = dag.f64_const(-1400.0);
+    let x = dag.f64_min(x, hi);
+    let x = dag.f64_max(x, lo);
+
+    // x = k ln2 + r with |r| <= ln2/2
+    let log2e = dag.f64_const(std::f64::consts::LOG2_E);
+    let x_log2e = dag.f64_mul(x, log2e);
+    let k = dag.f64_nearest(x_log2e);
+    let ln2_hi = dag.f64_const(LN2_HI);
+    let ln2_lo = dag.f64_const(LN2_LO);
+    let k_hi = dag.f64_mul(k, ln2_hi);
+    let k_lo = dag.f64_mul(k, ln2_lo);
+    let r = dag.f64_sub(x, k_hi);
+    let r = dag.f64_sub(r, k_lo);
+
+    let mut coeffs = vec![1.0];
+    for n in 1..=13 {
+        coeffs.push(coeffs[n - 1] / n as f64);
+    }
+    let p = horner(dag, r, &coeffs);
+
+    // Split 2^k in two halves so that neither factor leaves the exponent range
+    let half = dag.f64_const(0.5);
+    let k_half = dag.f64_mul(k, half);
+    let k1 = dag.f64_floor(k_half);
+    let k2 = dag.f64_sub(k, k1);
+    let s1 = pow2(dag, k1);
+    let s2 = pow2(dag, k2);
+    let p = dag.f64_mul(p, s1);
+    dag.f64_mul(p, s2)
+}
+
+fn log(dag: &mut Dag, x: DagNode) -> DagNode {
+    // Scale subnormals up so the exponent field is meaningful
+    let min_normal = dag.f64_const(f64::MIN_POSITIVE);
+    let tiny = dag.f64_lt(x, min_normal);
+    let two54 = dag.f64_const(18_014_398_509_481_984.0);
+    let x_scaled = dag.f64_mul(x, two54);
+    let xs = dag.select(x_scaled, x, tiny);
+    let minus54 = dag.f64_const(-54.0);
+    let zero = dag.f64_zero();
+    let e_adj = dag.select(minus54, zero, tiny);
+
+    // x = 2^e m with m in [1,2)
+    let bits = dag.i64_reinterpret_f64(xs);
+    let shift = dag.i64_const(52);
+    let e_bits = dag.i64_shr_u(bits, shift);
+    let e = dag.f64_convert_i64_s(e_bits);
+    let bias = dag.f64_const(-1023.0);
+    let e = dag.f64_add(e, bias);
+    let e = dag.f64_add(e, e_adj);
+    let mantissa_mask = dag.i64_const(0x000f_ffff_ffff_ffff);
+    let one_bits = dag.i64_const(0x3ff0_0000_0000_0000);
+    let m_bits = dag.i64_and(bits, mantissa_mask);
+    let m_bits = dag.i64_or(m_bits, one_bits);
+    let m = dag.f64_reinterpret_i64(m_bits);
+
+    // Move m into [sqrt(1/2), sqrt(2))
+    let sqrt2 = dag.f64_const(std::f64::consts::SQRT_2);
+    let big = dag.f64_gt(m, sqrt2);
+    let half = dag.f64_const(0.5);
+    let m_half = dag.f64_mul(m, half);
+    let m = dag.select(m_half, m, big);
+    let one = dag.f64_one();
+    let e_plus_one = dag.f64_add(e, one);
+    let e = dag.select(e_plus_one, e, big);
+
+    // log(m) = 2 atanh(s), s = (m-1)/(m+1)
+    let num = dag.f64_sub(m, one);
+    let den = dag.f64_add(m, one);
+    let s = dag.f64_div(num, den);
+    let s2 = dag.f64_mul(s, s);
+    let coeffs: Vec<f64> = (0..10).map(|n| 2.0 / (2 * n + 1) as f64).collect();
+    let p = horner(dag, s2, &coeffs);
+    let p = dag.f64_mul(s, p);
+
+    let ln2_hi = dag.f64_const(LN2_HI);
+    let ln2_lo = dag.f64_const(LN2_LO);
+    let e_hi = dag.f64_mul(e, ln2_hi);
+    let e_lo = dag.f64_mul(e, ln2_lo);
+    let p = dag.f64_add(p, e_lo);
+    let result = dag.f64_add(e_hi, p);
+
+    // log(0) = -inf, log(inf) = inf, log(negative or NaN) = NaN
+    let neg_inf = dag.f64_const(f64::NEG_INFINITY);
+    let inf = dag.f64_const(f64::INFINITY);
+    let is_zero = dag.f64_eq(x, zero);
+    let sqrt_x = dag.f64_sqrt(x);
+    let fallback = dag.select(neg_inf, sqrt_x, is_zero);
+    let finite = dag.f64_lt(x, inf);
+    let positive = dag.f64_gt(x, zero);
+    let result = dag.select(result, fallback, finite);
+    dag.select(result, fallback, positive)
+}
+
+// quadrant = 0 gives sin, quadrant = 1 gives cos
+fn sin_cos(dag: &mut Dag, x: DagNode, quadrant: i64) -> DagNode {
+    // x = k pi/2 + r with |r| <= pi/4
+    let two_over_pi = dag.f64_const(std::f64::consts::FRAC_2_PI);
+    let x_2_pi = dag.f64_mul(x, two_over_pi);
+    let k = dag.f64_nearest(x_2_pi);
+    let mut r = x;
+    for part in &[1.570_796_326_734_125_6, 6.077_100_506_303_966e-11, 2.022_266_248_711_166_5e-21] {
+        let part = dag.f64_const(*part);
+        let k_part = dag.f64_mul(k, part);
+        r = dag.f64_sub(r, k_part);
+    }
+    let r2 = dag.f64_mul(r, r);
+
+    let mut sin_coeffs = vec![1.0];
+    let mut cos_coeffs = vec![1.0];
+    for n in 1..=8 {
+        let prev_sin = sin_coeffs[n - 1];
+        let prev_cos = cos_coeffs[n - 1];
+        sin_coeffs.push(-prev_sin / ((2 * n) * (2 * n + 1)) as f64);
+        cos_coeffs.push(-prev_cos / ((2 * n - 1) * (2 * n)) as f64);
+    }
+    let s = horner(dag, r2, &sin_coeffs);
+    let s = dag.f64_mul(r, s);
+    let c = horner(dag, r2, &cos_coeffs);
+
+    let k = dag.i64_trunc_sat_f64_s(k);
+    let quadrant = dag.i64_const(quadrant);
+    let q = dag.i64_add(k, quadrant);
+    let one = dag.i64_const(1);
+    let two = dag.i64_const(2);
+    let odd = dag.i64_and(q, one);
+    let odd = dag.i32_wrap_i64(odd);
+    let negative = dag.i64_and(q, two);
+    let negative = dag.i32_wrap_i64(negative);
+    let v = dag.select(c, s, odd);
+    let minus_v = dag.f64_neg(v);
+    dag.select(minus_v, v, negative)
+}
+
+fn atan2(dag: &mut Dag, y: DagNode, x: DagNode) -> DagNode {
+    let ax = dag.f64_abs(x);
+    let ay = dag.f64_abs(y);
+    let hi = dag.f64_max(ax, ay);
+    let lo = dag.f64_min(ax, ay);
+    let zero = dag.f64_zero();
+    let one = dag.f64_one();
+    let t = dag.f64_div(lo, hi);
+    let nonzero = dag.f64_gt(hi, zero);
+    let t = dag.select(t, zero, nonzero);
+
+    // atan(t) = pi/4 + atan((t-1)/(t+1)), used when t > tan(pi/8)
+    let tan_pi_8 = dag.f64_const(0.414_213_562_373_095_03);
+    let big = dag.f64_gt(t, tan_pi_8);
+    let num = dag.f64_sub(t, one);
+    let den = dag.f64_add(t, one);
+    let t_shifted = dag.f64_div(num, den);
+    let u = dag.select(t_shifted, t, big);
+
+    // atan(u) = 2 atan(u / (1 + sqrt(1 + u^2)))
+    let u2 = dag.f64_mul(u, u);
+    let u2_1 = dag.f64_add(u2, one);
+    let root = dag.f64_sqrt(u2_1);
+    let den = dag.f64_add(root, one);
+    let v = dag.f64_div(u, den);
+    let v2 = dag.f64_mul(v, v);
+    let coeffs: Vec<f64> = (0..12).map(|n| if n % 2 == 0 { 2.0 } else { -2.0 } / (2 * n + 1) as f64).collect();
+    let a = horner(dag, v2, &coeffs);
+    let a = dag.f64_mul(v, a);
+    let pi_4 = dag.f64_const(FRAC_PI_4);
+    let offset = dag.select(pi_4, zero, big);
+    let a = dag.f64_add(a, offset);
+
+    let pi_2 = dag.f64_const(FRAC_PI_2);
+    let steep = dag.f64_gt(ay, ax);
+    let a_steep = dag.f64_sub(pi_2, a);
+    let a = dag.select(a_steep, a, steep);
+
+    let pi = dag.f64_const(PI);
+    let sign_x = dag.f64_copysign(one, x);
+    let left = dag.f64_lt(sign_x, zero);
+    let a_left = dag.f64_sub(pi, a);
+    let a = dag.select(a_left, a, left);
+
+    dag.f64_copysign(a, y)
+}
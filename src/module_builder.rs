@@ -1,8 +1,12 @@
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct ModuleBuilder {
     types: Vec<(Vec<ValType>, Vec<ValType>)>,
     funcs: Vec<usize>,
     code_blob: Vec<u8>,
+    // Mutable f64 globals, by their initial values
+    globals: Vec<f64>,
+    // The minimum size of the one linear memory in 64KiB pages, if there is one
+    memory: Option<usize>,
     exports: Vec<(String, u8, usize)>,
     current_func_type: usize,
     current_func_param_count: usize,
@@ -12,6 +16,7 @@ pub struct ModuleBuilder {
     in_func: bool,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ValType {
     I32,
@@ -25,12 +30,15 @@ pub enum BlockType {
     Empty,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Func(usize);
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Local(usize);
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Global(usize);
+
 
 impl ValType {
     fn as_byte(&self) -> u8 {
@@ -71,6 +79,25 @@ impl ModuleBuilder {
             extend_leb128_usize(&mut func_section, *t);
         }
 
+        // memory section
+        let mut memory_section = vec![];
+        if let Some(pages) = self.memory {
+            memory_section.push(0x01);
+            memory_section.push(0x00);
+            extend_leb128_usize(&mut memory_section, pages);
+        }
+
+        // global section
+        let mut global_section = vec![];
+        extend_leb128_usize(&mut global_section, self.globals.len());
+        for x in &self.globals {
+            global_section.push(ValType::F64.as_byte());
+            global_section.push(0x01);
+            global_section.push(0x44);
+            global_section.extend_from_slice(&x.to_le_bytes());
+            global_section.push(0x0b);
+        }
+
         // export section
         let mut export_section = vec![];
         extend_leb128_usize(&mut export_section, self.exports.len());
@@ -89,6 +116,14 @@ impl ModuleBuilder {
         result.push(0x03);
         extend_leb128_usize(&mut result, func_section.len());
         result.extend_from_slice(&func_section);
+        if !memory_section.is_empty() {
+            result.push(0x05);
+            extend_leb128_usize(&mut result, memory_section.len());
+            result.extend_from_slice(&memory_section);
+        }
+        result.push(0x06);
+        extend_leb128_usize(&mut result, global_section.len());
+        result.extend_from_slice(&global_section);
         result.push(0x07);
         extend_leb128_usize(&mut result, export_section.len());
         result.extend_from_slice(&export_section);
@@ -126,6 +161,12 @@ impl ModuleBuilder {
         }
         extend_sleb128_u32(&mut self.current_func_code, n);
     }
+    fn emit_sleb128_i64(&mut self, n: i64) {
+        if !self.in_func {
+            panic!("cannot emit code outside of a func");
+        }
+        extend_sleb128_i64(&mut self.current_func_code, n);
+    }
     fn emit_blocktype(&mut self, blocktype: BlockType) {
         match blocktype {
             BlockType::Empty => self.emit(&[0x40])
@@ -183,6 +224,28 @@ impl ModuleBuilder {
         self.exports.push((name.to_owned(), 0x00, f.0));
     }
 
+    // A mutable f64 global, which can be set from outside once exported
+    pub fn add_global_f64(&mut self, x: f64) -> Global {
+        self.globals.push(x);
+        Global(self.globals.len() - 1)
+    }
+
+    pub fn export_global(&mut self, g: Global, name: &str) {
+        self.exports.push((name.to_owned(), 0x03, g.0));
+    }
+
+    // The module's linear memory, which it can grow past pages from outside
+    pub fn add_memory(&mut self, pages: usize) {
+        self.memory = Some(pages);
+    }
+
+    pub fn export_memory(&mut self, name: &str) {
+        if self.memory.is_none() {
+            panic!("export_memory cannot be called without a memory");
+        }
+        self.exports.push((name.to_owned(), 0x02, 0));
+    }
+
     pub fn i32_const(&mut self, x: u32) {
         self.emit(&[0x41]);
         self.emit_sleb128_u32(x);
@@ -192,10 +255,83 @@ impl ModuleBuilder {
         self.emit(&[0x6a]);
     }
 
+    pub fn i32_sub(&mut self) {
+        self.emit(&[0x6b]);
+    }
+
+    pub fn i32_mul(&mut self) {
+        self.emit(&[0x6c]);
+    }
+
+    pub fn i32_shl(&mut self) {
+        self.emit(&[0x74]);
+    }
+
+    pub fn i32_shr_u(&mut self) {
+        self.emit(&[0x76]);
+    }
+
     pub fn i32_lt_u(&mut self) {
         self.emit(&[0x49]);
     }
 
+    pub fn i32_eqz(&mut self) {
+        self.emit(&[0x45]);
+    }
+
+    pub fn i32_eq(&mut self) {
+        self.emit(&[0x46]);
+    }
+
+    pub fn i32_and(&mut self) {
+        self.emit(&[0x71]);
+    }
+
+    pub fn i32_or(&mut self) {
+        self.emit(&[0x72]);
+    }
+
+    pub fn i32_wrap_i64(&mut self) {
+        self.emit(&[0xa7]);
+    }
+
+    pub fn i32_trunc_sat_f64_u(&mut self) {
+        self.emit(&[0xfc, 0x03]);
+    }
+
+    pub fn i64_const(&mut self, x: i64) {
+        self.emit(&[0x42]);
+        self.emit_sleb128_i64(x);
+    }
+
+    pub fn i64_add(&mut self) {
+        self.emit(&[0x7c]);
+    }
+
+    pub fn i64_and(&mut self) {
+        self.emit(&[0x83]);
+    }
+
+    pub fn i64_or(&mut self) {
+        self.emit(&[0x84]);
+    }
+
+    pub fn i64_shl(&mut self) {
+        self.emit(&[0x86]);
+    }
+
+    pub fn i64_shr_u(&mut self) {
+        self.emit(&[0x88]);
+    }
+
+    pub fn i64_trunc_sat_f64_s(&mut self) {
+        self.emit(&[0xfc, 0x06]);
+    }
+
+    pub fn i64_reinterpret_f64(&mut self) {
+        self.emit(&[0xbd]);
+    }
+
     /*pub fn f32_const(&mut self, x: f32) {
         self.emit(&[0x43]);
         self.emit(&x.to_le_bytes());
@@ -216,9 +352,21 @@ impl ModuleBuilder {
         self.emit(&[0x44]);
         self.emit(&x.to_le_bytes());
     }
+    pub fn f64_abs(&mut self) {
+        self.emit(&[0x99]);
+    }
     pub fn f64_neg(&mut self) {
         self.emit(&[0x9a]);
     }
+    pub fn f64_floor(&mut self) {
+        self.emit(&[0x9c]);
+    }
+    pub fn f64_nearest(&mut self) {
+        self.emit(&[0x9e]);
+    }
+    pub fn f64_sqrt(&mut self) {
+        self.emit(&[0x9f]);
+    }
     pub fn f64_add(&mut self) {
         self.emit(&[0xa0]);
     }
@@ -231,6 +379,27 @@ impl ModuleBuilder {
     pub fn f64_div(&mut self) {
         self.emit(&[0xa3]);
     }
+    pub fn f64_min(&mut self) {
+        self.emit(&[0xa4]);
+    }
+    pub fn f64_max(&mut self) {
+        self.emit(&[0xa5]);
+    }
+    pub fn f64_copysign(&mut self) {
+        self.emit(&[0xa6]);
+    }
+    pub fn f64_convert_i32_u(&mut self) {
+        self.emit(&[0xb8]);
+    }
+    pub fn f64_convert_i64_s(&mut self) {
+        self.emit(&[0xb9]);
+    }
+    pub fn f64_reinterpret_i64(&mut self) {
+        self.emit(&[0xbf]);
+    }
+    pub fn f64_eq(&mut self) {
+        self.emit(&[0x61]);
+    }
     pub fn f64_lt(&mut self) {
         self.emit(&[0x63]);
     }
@@ -255,6 +424,44 @@ impl ModuleBuilder {
         self.emit(&[0x22]);
         self.emit_leb128_usize(local.0);
     }
+    pub fn global_get(&mut self, global: Global) {
+        self.emit(&[0x23]);
+        self.emit_leb128_usize(global.0);
+    }
+    // Loads and stores take the address from the stack, plus a constant offset. align is
+    // log2 of the alignment promised, which wasm only uses as a hint.
+    fn emit_memarg(&mut self, align: usize, offset: usize) {
+        if self.memory.is_none() {
+            panic!("cannot access memory in a module without one");
+        }
+        self.emit_leb128_usize(align);
+        self.emit_leb128_usize(offset);
+    }
+    #[allow(dead_code)]
+    pub fn i32_load(&mut self, offset: usize) {
+        self.emit(&[0x28]);
+        self.emit_memarg(2, offset);
+    }
+    pub fn f64_load(&mut self, offset: usize) {
+        self.emit(&[0x2b]);
+        self.emit_memarg(3, offset);
+    }
+    pub fn i32_store(&mut self, offset: usize) {
+        self.emit(&[0x36]);
+        self.emit_memarg(2, offset);
+    }
+    pub fn f64_store(&mut self, offset: usize) {
+        self.emit(&[0x39]);
+        self.emit_memarg(3, offset);
+    }
+    // The size of memory in 64KiB pages
+    pub fn memory_size(&mut self) {
+        self.emit(&[0x3f, 0x00]);
+    }
+    // Adds that many pages, leaving the old size or -1 on the stack
+    pub fn memory_grow(&mut self) {
+        self.emit(&[0x40, 0x00]);
+    }
     pub fn br(&mut self, label: usize) {
         self.emit(&[0x0c]);
         self.emit_leb128_usize(label);
@@ -263,6 +470,10 @@ impl ModuleBuilder {
         self.emit(&[0x0d]);
         self.emit_leb128_usize(label);
     }
+    // return, leaving the function's results from the top of the stack
+    pub fn ret(&mut self) {
+        self.emit(&[0x0f]);
+    }
     pub fn start_loop(&mut self, blocktype: BlockType) {
         self.emit(&[0x03]);
         self.emit_blocktype(blocktype);
@@ -277,6 +488,9 @@ impl ModuleBuilder {
     pub fn end_block(&mut self) {
         self.emit(&[0x0b]);
     }
+    pub fn select(&mut self) {
+        self.emit(&[0x1b]);
+    }
     pub fn call(&mut self, f: Func) {
         self.emit(&[0x10]);
         self.emit_leb128_usize(f.0);
@@ -292,7 +506,7 @@ fn extend_leb128_usize(v: &mut Vec<u8>, mut n: usize) {
 }
 
 fn extend_sleb128_u32(v: &mut Vec<u8>, mut n: u32) {
-    while n >= 0x40 && n < 0xffff_ffc0 {
+    while (0x40..0xffff_ffc0).contains(&n) {
         v.push(128 | (n & 127) as u8);
         n >>= 7;
         if (n & 0x0100_0000) != 0 {
@@ -302,6 +516,18 @@ fn extend_sleb128_u32(v: &mut Vec<u8>, mut n: u32) {
     v.push((n & 0x7f) as u8);
 }
 
+fn extend_sleb128_i64(v: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            v.push(byte);
+            return;
+        }
+        v.push(byte | 0x80);
+    }
+}
+
 fn leb_usize_len(mut n: usize) -> usize {
     let mut len = 1;
     while n >= 128 {
@@ -0,0 +1,208 @@
+use std::f64::consts::PI;
+use crate::dag::{Dag, DagNode, Effect, EffectType};
+use crate::mathlib::MathLib;
+use crate::module_builder::{Func, ModuleBuilder, ValType};
+
+// How escape times become colours, as written in a palette: section or field:
+//
+//     smooth 32 cosine 0.5 0.5 0.5, 0.5 0.5 0.5, 1 1 1, 0 0.1 0.2
+//     histogram gradient 0 #000764, 0.16 #206bcb, 0.42 #edffff, 0.64 #ffaa00, 0.86 #000200
+//
+// The mode turns the smooth escape time into a position t along the palette. "smooth n"
+// goes round the palette once every n iterations, and is the default, with n = 32.
+// "histogram" spreads t from 0 to 1 over the pixels of the tile, so that each part of the
+// palette colours about as many of them. Each tile has its own histogram, so an image
+// drawn as several tiles may not match where they meet; index.html draws just one.
+//
+// A cosine palette is four RGB triples a, b, c and d, giving a + b cos(2π(c t + d)) for
+// each channel. A gradient is stops, each a position from 0 to 1 and a #rrggbb colour,
+// blended in between and wrapping round from the last to the first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub mode: Mode,
+    pub colours: Colours,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    // Iterations per trip round the palette
+    Smooth(f64),
+    Histogram,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Colours {
+    Cosine([[f64; 3]; 4]),
+    // In order of position, with channels from 0 to 1
+    Gradient(Vec<(f64, [f64; 3])>),
+}
+
+const DEFAULT_PERIOD: f64 = 32.0;
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            mode: Mode::Smooth(DEFAULT_PERIOD),
+            colours: Colours::Cosine([[0.5, 0.5, 0.5], [0.5, 0.5, 0.5], [1.0, 1.0, 1.0], [0.0, 0.1, 0.2]]),
+        }
+    }
+}
+
+impl Palette {
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let spaced = text.replace(',', " , ");
+        let mut words = spaced.split_whitespace().peekable();
+        let mode = match words.peek() {
+            Some(&"smooth") => {
+                words.next();
+                match words.peek().map(|w| w.parse::<f64>()) {
+                    Some(Ok(period)) if period > 0.0 => {
+                        words.next();
+                        Mode::Smooth(period)
+                    }
+                    Some(Ok(_)) => return Err("The period of 'smooth' has to be more than 0".to_owned()),
+                    _ => Mode::Smooth(DEFAULT_PERIOD),
+                }
+            }
+            Some(&"histogram") => {
+                words.next();
+                Mode::Histogram
+            }
+            _ => Mode::Smooth(DEFAULT_PERIOD),
+        };
+        let kind = words.next();
+        let rest: Vec<&str> = words.collect();
+        let groups: Vec<&[&str]> = rest.split(|w| *w == ",").collect();
+        let colours = match kind {
+            Some("cosine") => Colours::Cosine(cosine(&groups)?),
+            Some("gradient") => Colours::Gradient(gradient(&groups)?),
+            Some(word) => return Err(format!("Expected 'cosine' or 'gradient' in the palette, found '{}'", word)),
+            None => return Err("Expected 'cosine' or 'gradient' in the palette".to_owned()),
+        };
+        Ok(Palette { mode, colours })
+    }
+}
+
+fn cosine(groups: &[&[&str]]) -> Result<[[f64; 3]; 4], String> {
+    let err = || "Expected four RGB triples for 'cosine', such as 0.5 0.5 0.5, 0.5 0.5 0.5, 1 1 1, 0 0.1 0.2".to_owned();
+    if groups.len() != 4 {
+        return Err(err());
+    }
+    let mut abcd = [[0.0; 3]; 4];
+    for (triple, group) in abcd.iter_mut().zip(groups) {
+        if group.len() != 3 {
+            return Err(err());
+        }
+        for (x, word) in triple.iter_mut().zip(group.iter()) {
+            *x = word.parse().map_err(|_| err())?;
+        }
+    }
+    Ok(abcd)
+}
+
+fn gradient(groups: &[&[&str]]) -> Result<Vec<(f64, [f64; 3])>, String> {
+    let mut stops: Vec<(f64, [f64; 3])> = vec![];
+    for group in groups {
+        let stop = match group {
+            [position, colour] => position.parse().ok().zip(hex_colour(colour)),
+            _ => None,
+        };
+        let (position, colour) = stop.ok_or_else(|| format!("Expected a position and a #rrggbb colour for each stop of 'gradient', found '{}'", group.join(" ")))?;
+        if !(0.0..=1.0).contains(&position) {
+            return Err(format!("Gradient stops go from 0 to 1, found {}", position));
+        }
+        if stops.last().is_some_and(|(last, _)| position < *last) {
+            return Err(format!("Gradient stops have to be in order, but {} comes after {}", position, stops.last().unwrap().0));
+        }
+        stops.push((position, colour));
+    }
+    Ok(stops)
+}
+
+// #rrggbb with each channel from 0 to 1
+fn hex_colour(word: &str) -> Option<[f64; 3]> {
+    let digits = word.strip_prefix('#')?;
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut rgb = [0.0; 3];
+    for (k, x) in rgb.iter_mut().enumerate() {
+        *x = u8::from_str_radix(&digits[2 * k..2 * k + 2], 16).ok()? as f64 / 255.0;
+    }
+    Some(rgb)
+}
+
+// colour(t) -> the RGBA bytes for position t, packed little-endian into an i32 so that one
+// store puts them in memory in order
+pub fn backend_colour(mb: &mut ModuleBuilder, lib: MathLib, colours: &Colours) -> Func {
+    let f = mb.start_func(&[ValType::F64], &[ValType::I32]);
+    let mut dag = Dag::default();
+    let t = dag.f64_input(mb.get_local_param(0));
+    let rgb = match colours {
+        Colours::Cosine(abcd) => cosine_rgb(&mut dag, lib, abcd, t),
+        Colours::Gradient(stops) => gradient_rgb(&mut dag, stops, t),
+    };
+    let mut rgba = dag.i64_const(0xff << 24);
+    for (k, x) in rgb.iter().enumerate() {
+        let x = dag.f64_max(*x, dag.f64_zero());
+        let x = dag.f64_min(x, dag.f64_one());
+        let x = dag.f64_mul(x, dag.f64_const(255.0));
+        let x = dag.f64_nearest(x);
+        let byte = dag.i64_trunc_sat_f64_s(x);
+        let byte = dag.i64_shl(byte, dag.i64_const(8 * k as i64));
+        rgba = dag.i64_or(rgba, byte);
+    }
+    let rgba = dag.i32_wrap_i64(rgba);
+    dag.emit(mb, &[Effect(EffectType::Push, rgba)]);
+    mb.end_func();
+    f
+}
+
+fn cosine_rgb(dag: &mut Dag, lib: MathLib, [a, b, c, d]: &[[f64; 3]; 4], t: DagNode) -> [DagNode; 3] {
+    let mut rgb = [dag.f64_zero(); 3];
+    for (k, x) in rgb.iter_mut().enumerate() {
+        let turns = dag.f64_mul(t, dag.f64_const(c[k]));
+        let turns = dag.f64_add(turns, dag.f64_const(d[k]));
+        let angle = dag.f64_mul(turns, dag.f64_const(2.0 * PI));
+        let wave = dag.call1(lib.cos, angle);
+        let wave = dag.f64_mul(wave, dag.f64_const(b[k]));
+        *x = dag.f64_add(wave, dag.f64_const(a[k]));
+    }
+    rgb
+}
+
+// Each stop's colour holds until the next stop's takes over, blending on the way. Which
+// blend applies is decided by comparing t with every stop in turn.
+fn gradient_rgb(dag: &mut Dag, stops: &[(f64, [f64; 3])], t: DagNode) -> [DagNode; 3] {
+    let floor = dag.f64_floor(t);
+    let t = dag.f64_sub(t, floor);
+    let (first, last) = (stops[0], stops[stops.len() - 1]);
+    // Before the first stop, blending in from the last one, a whole turn earlier
+    let mut rgb = blend(dag, (last.0 - 1.0, last.1), first, t);
+    for (i, stop) in stops.iter().enumerate() {
+        let next = match stops.get(i + 1) {
+            Some(next) => *next,
+            None => (first.0 + 1.0, first.1),
+        };
+        let blended = blend(dag, *stop, next, t);
+        let past = dag.f64_ge(t, dag.f64_const(stop.0));
+        for (x, y) in rgb.iter_mut().zip(blended.iter()) {
+            *x = dag.select(*y, *x, past);
+        }
+    }
+    rgb
+}
+
+fn blend(dag: &mut Dag, (p0, c0): (f64, [f64; 3]), (p1, c1): (f64, [f64; 3]), t: DagNode) -> [DagNode; 3] {
+    if p1 <= p0 {
+        return [dag.f64_const(c1[0]), dag.f64_const(c1[1]), dag.f64_const(c1[2])];
+    }
+    let along = dag.f64_sub(t, dag.f64_const(p0));
+    let along = dag.f64_mul(along, dag.f64_const(1.0 / (p1 - p0)));
+    let mut rgb = [dag.f64_zero(); 3];
+    for (k, x) in rgb.iter_mut().enumerate() {
+        let change = dag.f64_mul(along, dag.f64_const(c1[k] - c0[k]));
+        *x = dag.f64_add(change, dag.f64_const(c0[k]));
+    }
+    rgb
+}
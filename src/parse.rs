@@ -1,29 +1,161 @@
-use crate::ast::Expr;
+use crate::ast::{Expr, FuncDef, ParamDecl, Span};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{digit1, multispace0},
-    combinator::{all_consuming, map, opt, recognize, success, value},
-    multi::fold_many0,
-    sequence::{delimited, preceded, terminated, tuple},
+    bytes::complete::{tag, take_while},
+    character::complete::{digit0, digit1, multispace1, not_line_ending, one_of, satisfy},
+    combinator::{all_consuming, cut, map, not, opt, peek, recognize, success, value, verify},
+    multi::{fold_many0, many0, separated_list1},
+    sequence::{preceded, terminated, tuple},
     Finish, IResult,
 };
 use std::{cmp::Ordering, fmt};
 
 pub fn parse(input: &str) -> Result<Expr, ParseErr> {
-    Ok(all_consuming(preceded(whitespace, expr))(input)
+    let mut expr = all_consuming(preceded(leading_whitespace, block))(input)
         .finish()
         .map_err(|e| ParseErr::new(e, input))?
-        .1)
+        .1;
+    locate(&mut expr, input.len());
+    Ok(expr)
+}
+
+// A formula that can start with parameter declarations, as in
+//
+//     param a = 0.03125; z*z + c - a/z
+pub fn parse_declared(input: &str) -> Result<(Vec<ParamDecl>, Expr), ParseErr> {
+    let (mut decls, mut expr) = all_consuming(preceded(leading_whitespace, tuple((many0(param_decl), block))))(input)
+        .finish()
+        .map_err(|e| ParseErr::new(e, input))?
+        .1;
+    for decl in &mut decls {
+        locate(&mut decl.default, input.len());
+        decl.span = located(decl.span, input.len());
+    }
+    locate(&mut expr, input.len());
+    Ok((decls, expr))
+}
+
+// True for text with nothing but whitespace and comments
+pub fn is_blank(input: &str) -> bool {
+    all_consuming(leading_whitespace)(input).is_ok()
+}
+
+// While parsing, spans count bytes back from the end of the input (like Err::remaining)
+// because the parsers never see the whole text. This turns them into offsets.
+fn locate(expr: &mut Expr, len: usize) {
+    let span = match expr {
+        Expr::F64(_, span) | Expr::Complex(_, _, span) | Expr::Var(_, span) => span,
+        Expr::Call(_, args, span) => {
+            for arg in args {
+                locate(arg, len);
+            }
+            span
+        }
+        Expr::If(cond, a, b, span) => {
+            locate(cond, len);
+            locate(a, len);
+            locate(b, len);
+            span
+        }
+        Expr::Let(_, value, body, span) => {
+            locate(value, len);
+            locate(body, len);
+            span
+        }
+        Expr::Def(def, body, span) => {
+            locate(&mut def.body, len);
+            def.span = located(def.span, len);
+            locate(body, len);
+            span
+        }
+    };
+    *span = located(*span, len);
+}
+
+fn located(span: Span, len: usize) -> Span {
+    Span {
+        start: len - span.start,
+        end: len - span.end,
+    }
+}
+
+// Words that can't be used as variable or function names
+const KEYWORDS: &[&str] = &["and", "or", "not", "if", "then", "else", "def", "param"];
+
+// Any number of `name = value;` bindings and `def f(x) = ...;` functions followed by an expression
+fn block(input: &str) -> IResult<&str, Expr, Err> {
+    alt((expr_def, expr_let, expr))(input)
+}
+
+fn expr_def(input: &str) -> IResult<&str, Expr, Err> {
+    let params = preceded(symbol("("), terminated(separated_list1(symbol(","), word_owned), symbol(")")));
+    map(
+        spanned(tuple((
+            spanned(preceded(keyword("def"), cut(tuple((word_owned, params, preceded(assign, expr)))))),
+            preceded(cut(symbol(";")), cut(block)),
+        ))),
+        |((((name, params, body), def_span), rest), span)| {
+            let def = FuncDef { name, params, body, span: def_span };
+            Expr::Def(Box::new(def), Box::new(rest), span)
+        },
+    )(input)
+}
+
+fn param_decl(input: &str) -> IResult<&str, ParamDecl, Err> {
+    map(
+        terminated(
+            spanned(preceded(keyword("param"), cut(tuple((word_owned, preceded(assign, expr)))))),
+            cut(symbol(";")),
+        ),
+        |((name, default), span)| ParamDecl { name, default, span },
+    )(input)
+}
+
+fn expr_let(input: &str) -> IResult<&str, Expr, Err> {
+    map(
+        spanned(tuple((
+            word_owned,
+            preceded(assign, cut(expr)),
+            preceded(cut(symbol(";")), cut(block)),
+        ))),
+        |((name, value, body), span)| Expr::Let(name, Box::new(value), Box::new(body), span),
+    )(input)
 }
 
 fn expr(input: &str) -> IResult<&str, Expr, Err> {
+    let (input, init) = expr_and(input)?;
+    fold_many0(
+        preceded(keyword("or"), cut(expr_and)),
+        init,
+        |lhs, rhs| binary("or", lhs, rhs),
+    )(input)
+}
+
+fn expr_and(input: &str) -> IResult<&str, Expr, Err> {
+    let (input, init) = expr_not(input)?;
+    fold_many0(
+        preceded(keyword("and"), cut(expr_not)),
+        init,
+        |lhs, rhs| binary("and", lhs, rhs),
+    )(input)
+}
+
+fn expr_not(input: &str) -> IResult<&str, Expr, Err> {
+    alt((
+        map(spanned(preceded(keyword("not"), cut(expr_not))), |(e, span)|Expr::Call("not".to_owned(), vec![e], span)),
+        expr_cmp,
+    ))(input)
+}
+
+fn expr_cmp(input: &str) -> IResult<&str, Expr, Err> {
     let (input, lhs) = expr_add(input)?;
     let x = alt((
-        map(preceded(symbol("<"), expr_add), |rhs|Expr::Call("<".to_owned(), vec![lhs.clone(), rhs])),
-        map(preceded(symbol(">"), expr_add), |rhs|Expr::Call(">".to_owned(), vec![lhs.clone(), rhs])),
-        map(preceded(symbol("<="), expr_add), |rhs|Expr::Call("<=".to_owned(), vec![lhs.clone(), rhs])),
-        map(preceded(symbol(">="), expr_add), |rhs|Expr::Call(">=".to_owned(), vec![lhs.clone(), rhs])),
+        map(preceded(symbol("<="), cut(expr_add)), |rhs|binary("<=", lhs.clone(), rhs)),
+        map(preceded(symbol(">="), cut(expr_add)), |rhs|binary(">=", lhs.clone(), rhs)),
+        map(preceded(symbol("=="), cut(expr_add)), |rhs|binary("==", lhs.clone(), rhs)),
+        map(preceded(symbol("!="), cut(expr_add)), |rhs|binary("!=", lhs.clone(), rhs)),
+        map(preceded(symbol("<"), cut(expr_add)), |rhs|binary("<", lhs.clone(), rhs)),
+        map(preceded(symbol(">"), cut(expr_add)), |rhs|binary(">", lhs.clone(), rhs)),
         success(lhs.clone())
     ))(input);
     drop(lhs);
@@ -34,11 +166,11 @@ fn expr_add(input: &str) -> IResult<&str, Expr, Err> {
     let (input, init) = expr_term(input)?;
     fold_many0(
         alt((
-            tuple((symbol_return("+"), expr_term)),
-            tuple((symbol_return("-"), expr_term)),
+            tuple((symbol_return("+"), cut(expr_term))),
+            tuple((symbol_return("-"), cut(expr_term))),
         )),
         init,
-        |lhs, (op, rhs)| Expr::Call(op.to_owned(), vec![lhs, rhs]),
+        |lhs, (op, rhs)| binary(op, lhs, rhs),
     )(input)
 }
 
@@ -46,65 +178,156 @@ fn expr_term(input: &str) -> IResult<&str, Expr, Err> {
     let (input, init) = expr_tight(input)?;
     fold_many0(
         alt((
-            tuple((symbol_return("*"), expr_tight)),
-            tuple((symbol_return("/"), expr_tight)),
+            tuple((symbol_return("*"), cut(expr_tight))),
+            tuple((symbol_return("/"), cut(expr_tight))),
+            tuple((success("*"), implicit_factor)),
         )),
         init,
-        |lhs, (op, rhs)| Expr::Call(op.to_owned(), vec![lhs, rhs]),
+        |lhs, (op, rhs)| binary(op, lhs, rhs),
     )(input)
 }
 
+// Juxtaposition, as in 2z or 3(z + 1), multiplies with the same precedence as "*".
+// The second factor can't be a number ("2 3") or start with a minus ("z -1").
+fn implicit_factor(input: &str) -> IResult<&str, Expr, Err> {
+    preceded(not(peek(one_of("0123456789."))), expr_pow)(input)
+}
+
 fn expr_tight(input: &str) -> IResult<&str, Expr, Err> {
     alt((
-        delimited(symbol("("), expr, symbol(")")),
-        map(preceded(symbol("-"), expr_tight), |e|Expr::Call("neg".to_owned(), vec![e])),
-        map(preceded(keyword("sqabs"), delimited(symbol("("), expr, symbol(")"))), |e|Expr::Call("sqabs".to_owned(), vec![e])),
-        map(preceded(keyword("real"), delimited(symbol("("), expr, symbol(")"))), |e|Expr::Call("real".to_owned(), vec![e])),
-        map(preceded(keyword("conj"), delimited(symbol("("), expr, symbol(")"))), |e|Expr::Call("conj".to_owned(), vec![e])),
+        map(spanned(preceded(symbol("-"), cut(expr_tight))), |(e, span)|Expr::Call("neg".to_owned(), vec![e], span)),
+        expr_pow,
+    ))(input)
+}
+
+fn expr_pow(input: &str) -> IResult<&str, Expr, Err> {
+    let (input, base) = expr_atom(input)?;
+    let x = alt((
+        map(preceded(symbol("^"), cut(expr_tight)), |rhs|binary("^", base.clone(), rhs)),
+        success(base.clone())
+    ))(input);
+    drop(base);
+    x
+}
+
+fn expr_atom(input: &str) -> IResult<&str, Expr, Err> {
+    alt((
+        map(spanned(preceded(symbol("("), cut(terminated(block, symbol(")"))))), |(e, span)|e.with_span(span)),
+        expr_if,
+        expr_call,
         expr_f32,
         expr_var,
     ))(input)
 }
 
+// 12, 1.5, .5, 1e-6, and any of those followed by i for an imaginary number
 fn expr_f32(input: &str) -> IResult<&str, Expr, Err> {
+    let mantissa = alt((
+        recognize(tuple((digit1, opt(tuple((tag("."), digit0)))))),
+        recognize(tuple((tag("."), digit1))),
+    ));
+    let exponent = opt(tuple((one_of("eE"), opt(one_of("+-")), digit1)));
     map(
-        terminated(
-            recognize(tuple((digit1, opt(tuple((tag("."), digit1)))))),
+        spanned(terminated(
+            tuple((recognize(tuple((mantissa, exponent))), opt(imaginary_unit))),
             whitespace,
-        ),
-        |s: &str| Expr::F64(s.parse().unwrap()),
+        )),
+        |((s, imag), span): ((&str, Option<()>), Span)| {
+            let x = s.parse().unwrap();
+            match imag {
+                Some(()) => Expr::Complex(0.0, x, span),
+                None => Expr::F64(x, span),
+            }
+        },
+    )(input)
+    .map_err(|e| expecting(e, "number"))
+}
+
+// The i in 2.5i, as long as it isn't the start of a name like in 2iter
+fn imaginary_unit(input: &str) -> IResult<&str, (), Err> {
+    terminated(tagv("i"), not(satisfy(is_word_char)))(input)
+}
+
+// The else branch extends as far to the right as possible
+fn expr_if(input: &str) -> IResult<&str, Expr, Err> {
+    map(
+        spanned(tuple((
+            preceded(keyword("if"), cut(expr)),
+            preceded(cut(keyword("then")), cut(expr)),
+            preceded(cut(keyword("else")), cut(expr)),
+        ))),
+        |((cond, a, b), span)| Expr::If(Box::new(cond), Box::new(a), Box::new(b), span),
+    )(input)
+}
+
+// The "(" has to follow the name directly: "f(x)" is a call, but "z (z + 1)" multiplies
+fn expr_call(input: &str) -> IResult<&str, Expr, Err> {
+    map(
+        spanned(tuple((callee, preceded(symbol("("), cut(terminated(separated_list1(symbol(","), expr), symbol(")"))))))),
+        |((f, args), span)| Expr::Call(f, args, span),
     )(input)
 }
 
 fn expr_var(input: &str) -> IResult<&str, Expr, Err> {
-    map(word_owned, Expr::Var)(input)
+    map(spanned(word_owned), |(v, span)| Expr::Var(v, span))(input)
+}
+
+fn binary(op: &str, lhs: Expr, rhs: Expr) -> Expr {
+    let span = lhs.span().to(rhs.span());
+    Expr::Call(op.to_owned(), vec![lhs, rhs], span)
+}
+
+// Whatever the parser consumed, not counting trailing whitespace and comments
+fn spanned<'a, O>(mut parser: impl FnMut(&'a str) -> IResult<&'a str, O, Err>) -> impl FnMut(&'a str) -> IResult<&'a str, (O, Span), Err> {
+    move |input| {
+        let (rest, o) = parser(input)?;
+        let consumed = without_trailing_comments(&input[..input.len() - rest.len()]);
+        Ok((rest, (o, Span { start: input.len(), end: input.len() - consumed.len() })))
+    }
+}
+
+// Comments run to the end of the line, and start at a "--" with whitespace before it, so
+// they come off the last line first
+fn without_trailing_comments(mut text: &str) -> &str {
+    loop {
+        text = text.trim_end();
+        let line = text.rfind('\n').map_or(0, |i| i + 1);
+        let comment = text[line..].match_indices("--").map(|(i, _)| line + i).find(|&i| text[..i].ends_with(is_space));
+        match comment {
+            Some(i) => text = &text[..i],
+            None => return text,
+        }
+    }
+}
+
+fn is_space(c: char) -> bool {
+    " \t\r\n".contains(c)
 }
 
 fn symbol_return<'a, 'b: 'a>(sym: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str, Err> {
     move |input| {
         terminated(tag(sym), whitespace)(input)
-            .map_err(|e| decorate(e, format!("Expected: {:?}", sym)))
+            .map_err(|e| expecting(e, format!("{:?}", sym)))
     }
 }
 
 fn symbol<'a, 'b: 'a>(sym: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, (), Err> {
     move |input| {
         terminated(tagv(sym), whitespace)(input)
-            .map_err(|e| decorate(e, format!("Expected: {:?}", sym)))
+            .map_err(|e| expecting(e, format!("{:?}", sym)))
     }
 }
 
+// "=" but not "=="
+fn assign(input: &str) -> IResult<&str, (), Err> {
+    terminated(terminated(tagv("="), not(tag("="))), whitespace)(input)
+        .map_err(|e| expecting(e, format!("{:?}", "=")))
+}
+
 fn keyword<'a, 'b: 'a>(kw: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, (), Err> {
     move |input| {
-        let (input2, w) = word(input).map_err(|e| decorate(e, format!("Expected '{}'", kw)))?;
-        if w == kw {
-            Ok((input2, ()))
-        } else {
-            Err(nom::Err::Error(Err {
-                remaining: input.len(), // "peek" semantics - error gives location at the start of the keyword
-                message: format!("Expected '{}'", kw),
-            }))
-        }
+        value((), verify(word, |w: &str| w == kw))(input)
+            .map_err(|e| expecting(e, format!("{:?}", kw)))
     }
 }
 
@@ -112,19 +335,41 @@ fn tagv<'a, 'b: 'a>(t: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, (), Err>
     move |input| value((), tag(t))(input)
 }
 
+// Comments run from "--" to the end of the line, but only after whitespace, so that
+// "z--1" is still z - -1
 fn whitespace(input: &str) -> IResult<&str, (), Err> {
-    value((), multispace0)(input)
+    value((), many0(tuple((multispace1, opt(comment)))))(input)
+}
+
+// At the very start of the text a comment needs nothing before it
+fn leading_whitespace(input: &str) -> IResult<&str, (), Err> {
+    value((), tuple((opt(comment), whitespace)))(input)
+}
+
+fn comment(input: &str) -> IResult<&str, &str, Err> {
+    recognize(tuple((tag("--"), not_line_ending)))(input)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Names can't start with a digit, so that 2z is 2 * z
+fn name(input: &str) -> IResult<&str, &str, Err> {
+    recognize(tuple((satisfy(|c| c.is_ascii_alphabetic() || c == '_'), take_while(is_word_char))))(input)
 }
 
 fn word(input: &str) -> IResult<&str, &str, Err> {
-    terminated(
-        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
-        whitespace,
-    )(input)
+    terminated(name, whitespace)(input)
 }
 
 fn word_owned(input: &str) -> IResult<&str, String, Err> {
-    map(word, str::to_owned)(input).map_err(|e| decorate(e, "word"))
+    map(verify(word, |w: &str| !KEYWORDS.contains(&w)), str::to_owned)(input).map_err(|e| expecting(e, "identifier"))
+}
+
+// A name without the whitespace after it, which a call can't have
+fn callee(input: &str) -> IResult<&str, String, Err> {
+    map(verify(name, |w: &str| !KEYWORDS.contains(&w)), str::to_owned)(input).map_err(|e| expecting(e, "identifier"))
 }
 
 //////////////
@@ -134,6 +379,7 @@ fn word_owned(input: &str) -> IResult<&str, String, Err> {
 struct Err {
     remaining: usize,
     message: String,
+    expected: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -141,13 +387,19 @@ pub struct ParseErr {
     pub text: String,
     pub remaining: usize,
     pub message: String,
+    pub expected: Vec<String>,
 }
 
 impl<'a> nom::error::ParseError<&'a str> for Err {
     fn from_error_kind(input: &'a str, kind: nom::error::ErrorKind) -> Self {
+        let message = match kind {
+            nom::error::ErrorKind::Eof => "Unexpected input".to_owned(),
+            _ => format!("{:?}", kind),
+        };
         Err {
             remaining: input.len(),
-            message: format!("{:?}", kind),
+            message,
+            expected: vec![],
         }
     }
     fn append(input: &'a str, kind: nom::error::ErrorKind, other: Self) -> Self {
@@ -160,15 +412,25 @@ impl<'a> nom::error::ParseError<&'a str> for Err {
     fn from_char(input: &'a str, x: char) -> Self {
         Err {
             remaining: input.len(),
-            message: format!("Expected: {:?}", x),
+            message: String::new(),
+            expected: vec![format!("{:?}", x)],
         }
     }
-    fn or(self, other: Self) -> Self {
+    fn or(mut self, other: Self) -> Self {
         match other.remaining.cmp(&self.remaining) {
-            Ordering::Equal => Err {
-                remaining: self.remaining,
-                message: format!("{} | {}", self.message, other.message),
-            },
+            Ordering::Equal => {
+                for e in other.expected {
+                    if !self.expected.contains(&e) {
+                        self.expected.push(e);
+                    }
+                }
+                if self.message.is_empty() || self.message == other.message {
+                    self.message = other.message;
+                } else if !other.message.is_empty() {
+                    self.message = format!("{} | {}", self.message, other.message);
+                }
+                self
+            }
             Ordering::Less => other,
             Ordering::Greater => self,
         }
@@ -176,18 +438,22 @@ impl<'a> nom::error::ParseError<&'a str> for Err {
 }
 
 impl Err {
-    fn decorate(self, extra: impl fmt::Display) -> Self {
+    // Failures of a specific token replace nom's generic message ("Tag" etc.)
+    fn expecting(self, token: String) -> Self {
+        let mut expected = self.expected;
+        expected.push(token);
         Err {
             remaining: self.remaining,
-            message: format!("{} {}", self.message, extra),
+            message: String::new(),
+            expected,
         }
     }
 }
 
-fn decorate(err: nom::Err<Err>, extra: impl fmt::Display) -> nom::Err<Err> {
+fn expecting(err: nom::Err<Err>, token: impl fmt::Display) -> nom::Err<Err> {
     match err {
-        nom::Err::Error(e) => nom::Err::Error(e.decorate(extra)),
-        nom::Err::Failure(e) => nom::Err::Failure(e.decorate(extra)),
+        nom::Err::Error(e) => nom::Err::Error(e.expecting(token.to_string())),
+        nom::Err::Failure(e) => nom::Err::Failure(e.expecting(token.to_string())),
         e => e,
     }
 }
@@ -198,21 +464,94 @@ impl ParseErr {
             text: text.to_owned(),
             remaining: e.remaining,
             message: e.message,
+            expected: e.expected,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.text.len() - self.remaining
+    }
+
+    pub fn description(&self) -> String {
+        if self.expected.is_empty() {
+            self.message.clone()
+        } else {
+            format!("Expected {}", self.expected.join(" or "))
         }
     }
 }
 
 impl fmt::Display for ParseErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let pos = self.text.len() - self.remaining;
+        let pos = self.offset();
         write!(
             f,
             "{}####{} {}",
             &self.text[..pos],
             &self.text[pos..],
-            self.message
+            self.description()
         )
     }
 }
 
 impl std::error::Error for ParseErr {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tree without spans, as nested calls
+    fn shape(text: &str) -> String {
+        fn go(e: &Expr) -> String {
+            match e {
+                Expr::F64(x, _) => format!("{}", x),
+                Expr::Var(v, _) => v.clone(),
+                Expr::Call(f, args, _) => format!("{}({})", f, args.iter().map(go).collect::<Vec<_>>().join(", ")),
+                e => format!("{:?}", e),
+            }
+        }
+        go(&parse(text).unwrap())
+    }
+
+    #[test]
+    fn comments_need_whitespace_before() {
+        assert_eq!(shape("z--1"), "-(z, neg(1))");
+        assert_eq!(shape("(z)--c"), "-(z, neg(c))");
+        assert_eq!(shape("z -- minus one"), "z");
+        assert_eq!(shape("-- first\nz\n-- last"), "z");
+        assert!(is_blank("-- nothing but this"));
+        assert!(!is_blank("z-- not a comment"));
+    }
+
+    #[test]
+    fn calls_need_no_space_before_the_parenthesis() {
+        assert_eq!(shape("f(x)"), "f(x)");
+        assert_eq!(shape("sin(z)^2"), "^(sin(z), 2)");
+        assert_eq!(shape("z (z + 1)"), "*(z, +(z, 1))");
+        assert_eq!(shape("sin (z)"), "*(sin, z)");
+        assert_eq!(shape("2 (z + 1)"), "*(2, +(z, 1))");
+        assert_eq!(shape("f(x) (y)"), "*(f(x), y)");
+        // A def's parameters aren't a call, so they can be spaced out
+        assert!(parse("def f (x) = x^2; f(z)").is_ok());
+    }
+
+    #[test]
+    fn spans_stop_before_trailing_comments() {
+        fn span(text: &str) -> &str {
+            let span = parse(text).unwrap().span();
+            &text[span.start..span.end]
+        }
+        assert_eq!(span("c -- note"), "c");
+        assert_eq!(span("z*z + c -- note\n"), "z*z + c");
+        assert_eq!(span("z--1 -- note -- and more"), "z--1");
+        assert_eq!(span("(z + c) -- one\n-- two\n"), "(z + c)");
+        assert_eq!(span("z + -- a comment\n  c -- another"), "z + -- a comment\n  c");
+        match parse("sin(z -- the angle\n) + c").unwrap() {
+            Expr::Call(_, args, _) => match &args[0] {
+                Expr::Call(_, z, _) => assert_eq!(z[0].span(), Span { start: 4, end: 5 }),
+                e => panic!("{:?}", e),
+            },
+            e => panic!("{:?}", e),
+        }
+    }
+}
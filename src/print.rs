@@ -0,0 +1,283 @@
+use std::fmt;
+use crate::ast::Expr;
+
+// Prints in the syntax parse::parse accepts, with only the parentheses it needs, so that
+// parsing the output gives back the same tree (apart from spans). Numbers that came from
+// folding rather than parsing are written as arithmetic on ones that can be parsed: -2
+// comes back as neg(2) and 1 - 2i as 1 - 2i, with the same values. Infinity is written
+// 1e999. NaN can't be written at all.
+
+// Binding strength, loosest first
+const BLOCK: u8 = 0;
+const OR: u8 = 1;
+const AND: u8 = 2;
+const NOT: u8 = 3;
+const CMP: u8 = 4;
+const ADD: u8 = 5;
+const MUL: u8 = 6;
+const NEG: u8 = 7;
+const POW: u8 = 8;
+const ATOM: u8 = 9;
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write(f, self, BLOCK, true)
+    }
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::F64(x, _) if x.is_sign_negative() => NEG,
+        Expr::F64(..) | Expr::Complex(..) | Expr::Var(..) | Expr::If(..) => ATOM,
+        Expr::Let(..) | Expr::Def(..) => BLOCK,
+        Expr::Call(f, args, _) => match (f.as_str(), args.len()) {
+            ("or", 2) => OR,
+            ("and", 2) => AND,
+            ("not", 1) => NOT,
+            ("<", 2) | (">", 2) | ("<=", 2) | (">=", 2) | ("==", 2) | ("!=", 2) => CMP,
+            ("+", 2) | ("-", 2) => ADD,
+            ("*", 2) | ("/", 2) => MUL,
+            ("neg", 1) => NEG,
+            ("^", 2) => POW,
+            _ => ATOM,
+        },
+    }
+}
+
+// prec is the loosest binding the context can take without parentheses. tail is false
+// when something follows at the same nesting level, which an if can't have because its
+// else branch would swallow it.
+fn write(f: &mut fmt::Formatter, expr: &Expr, prec: u8, tail: bool) -> fmt::Result {
+    let is_if = matches!(expr, Expr::If(..));
+    if precedence(expr) < prec || (is_if && !tail) {
+        write!(f, "(")?;
+        write_bare(f, expr, true)?;
+        write!(f, ")")
+    } else {
+        write_bare(f, expr, tail)
+    }
+}
+
+fn write_bare(f: &mut fmt::Formatter, expr: &Expr, tail: bool) -> fmt::Result {
+    match expr {
+        Expr::F64(x, _) => write_number(f, *x),
+        Expr::Complex(x, y, _) if x.to_bits() == 0 && !y.is_sign_negative() => {
+            write_number(f, *y)?;
+            write!(f, "i")
+        }
+        // In full even when the real part is 0, since -2i would be neg(2i), whose real part
+        // is -0
+        Expr::Complex(x, y, _) => {
+            write!(f, "(")?;
+            write_number(f, *x)?;
+            write!(f, " {} ", if y.is_sign_negative() { "-" } else { "+" })?;
+            write_number(f, y.abs())?;
+            write!(f, "i)")
+        }
+        Expr::Var(v, _) => write!(f, "{}", v),
+        Expr::Call(op, args, _) => match (precedence(expr), &args[..]) {
+            (OR, [a, b]) | (AND, [a, b]) => {
+                let prec = precedence(expr);
+                write(f, a, prec, false)?;
+                write!(f, " {} ", op)?;
+                write(f, b, prec + 1, tail)
+            }
+            (NOT, [a]) => {
+                write!(f, "not ")?;
+                write(f, a, NOT, tail)
+            }
+            (CMP, [a, b]) => {
+                write(f, a, ADD, false)?;
+                write!(f, " {} ", op)?;
+                write(f, b, ADD, tail)
+            }
+            (ADD, [a, b]) => {
+                write(f, a, ADD, false)?;
+                write!(f, " {} ", op)?;
+                write(f, b, MUL, tail)
+            }
+            (MUL, [a, b]) => {
+                write(f, a, MUL, false)?;
+                write!(f, " {} ", op)?;
+                write(f, b, NEG, tail)
+            }
+            (NEG, [a]) => {
+                // "--" would start a comment
+                write!(f, "-")?;
+                let prec = if precedence(a) == NEG { ATOM } else { NEG };
+                write(f, a, prec, tail)
+            }
+            (POW, [a, b]) => {
+                write(f, a, ATOM, false)?;
+                write!(f, "^")?;
+                write(f, b, NEG, tail)
+            }
+            _ => {
+                write!(f, "{}(", op)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write(f, arg, OR, true)?;
+                }
+                write!(f, ")")
+            }
+        },
+        Expr::If(cond, a, b, _) => {
+            write!(f, "if ")?;
+            write(f, cond, OR, true)?;
+            write!(f, " then ")?;
+            write(f, a, OR, true)?;
+            write!(f, " else ")?;
+            write(f, b, OR, tail)
+        }
+        Expr::Let(name, value, body, _) => {
+            write!(f, "{} = ", name)?;
+            write(f, value, OR, true)?;
+            write!(f, "; ")?;
+            write(f, body, BLOCK, tail)
+        }
+        Expr::Def(def, body, _) => {
+            write!(f, "def {}({}) = ", def.name, def.params.join(", "))?;
+            write(f, &def.body, OR, true)?;
+            write!(f, "; ")?;
+            write(f, body, BLOCK, tail)
+        }
+    }
+}
+
+// Plain decimals where they're short, otherwise scientific notation
+fn write_number(f: &mut fmt::Formatter, x: f64) -> fmt::Result {
+    if x.is_sign_negative() {
+        write!(f, "-")?;
+    }
+    let x = x.abs();
+    if x.is_infinite() {
+        write!(f, "1e999")
+    } else if x != 0.0 && !(1e-5..1e16).contains(&x) {
+        write!(f, "{:e}", x)
+    } else {
+        write!(f, "{}", x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Expr, FuncDef, Span};
+    use crate::parse::parse;
+
+    const SPAN: Span = Span { start: 0, end: 0 };
+    const NUMBERS: &[f64] = &[0.0, -0.0, 1.0, 2.0, -3.0, 0.5, -0.25, 123456.789, 1e-5, 1.5e-7, -2.5e20, 1e16, f64::INFINITY, f64::NEG_INFINITY];
+    const NAMES: &[&str] = &["z", "c", "w", "a1", "x_y", "i", "e", "inf"];
+    const BINARY: &[&str] = &["+", "-", "*", "/", "^", "<", ">", "<=", ">=", "==", "!=", "and", "or"];
+    const UNARY: &[&str] = &["neg", "not", "sin", "sqabs"];
+
+    // xorshift, so that a failure comes back every time
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<T: Copy>(&mut self, xs: &[T]) -> T {
+            xs[self.below(xs.len())]
+        }
+
+        fn name(&mut self) -> String {
+            self.pick(NAMES).to_owned()
+        }
+    }
+
+    fn gen(rng: &mut Rng, depth: usize) -> Expr {
+        let kind = if depth == 0 || rng.below(4) == 0 { rng.below(3) } else { 3 + rng.below(6) };
+        let sub = |rng: &mut Rng| Box::new(gen(rng, depth - 1));
+        match kind {
+            0 => Expr::F64(rng.pick(NUMBERS), SPAN),
+            1 => Expr::Complex(rng.pick(NUMBERS), rng.pick(NUMBERS), SPAN),
+            2 => Expr::Var(rng.name(), SPAN),
+            3 => Expr::Call(rng.pick(BINARY).to_owned(), vec![*sub(rng), *sub(rng)], SPAN),
+            4 => Expr::Call(rng.pick(UNARY).to_owned(), vec![*sub(rng)], SPAN),
+            5 => {
+                let args = (0..1 + rng.below(3)).map(|_| *sub(rng)).collect();
+                Expr::Call("f".to_owned(), args, SPAN)
+            }
+            6 => Expr::If(sub(rng), sub(rng), sub(rng), SPAN),
+            7 => Expr::Let(rng.name(), sub(rng), sub(rng), SPAN),
+            _ => {
+                let params = (0..1 + rng.below(3)).map(|_| rng.name()).collect();
+                let def = FuncDef { name: "f".to_owned(), params, body: *sub(rng), span: SPAN };
+                Expr::Def(Box::new(def), sub(rng), SPAN)
+            }
+        }
+    }
+
+    // Undoes the arithmetic numbers that can't be parsed are written as
+    fn fold(e: Expr) -> Expr {
+        match e {
+            Expr::Call(f, args, span) => {
+                let args: Vec<Expr> = args.into_iter().map(fold).collect();
+                match (f.as_str(), &args[..]) {
+                    ("neg", [Expr::F64(x, _)]) => Expr::F64(-x, span),
+                    ("neg", [Expr::Complex(x, y, _)]) => Expr::Complex(-x, -y, span),
+                    ("+", [Expr::F64(x, _), Expr::Complex(zero, y, _)]) if zero.to_bits() == 0 => Expr::Complex(*x, *y, span),
+                    ("-", [Expr::F64(x, _), Expr::Complex(zero, y, _)]) if zero.to_bits() == 0 => Expr::Complex(*x, -y, span),
+                    _ => Expr::Call(f, args, span),
+                }
+            }
+            Expr::If(cond, a, b, span) => Expr::If(Box::new(fold(*cond)), Box::new(fold(*a)), Box::new(fold(*b)), span),
+            Expr::Let(name, value, body, span) => Expr::Let(name, Box::new(fold(*value)), Box::new(fold(*body)), span),
+            Expr::Def(def, body, span) => {
+                let def = FuncDef { body: fold(def.body), ..*def };
+                Expr::Def(Box::new(def), Box::new(fold(*body)), span)
+            }
+            e => e,
+        }
+    }
+
+    // The tree without spans, telling apart every number, 0 from -0 included
+    fn shape(e: &Expr) -> String {
+        let list = |es: &[&Expr]| es.iter().map(|e| shape(e)).collect::<Vec<_>>().join(", ");
+        match e {
+            Expr::F64(x, _) => format!("{:?}", x),
+            Expr::Complex(x, y, _) => format!("({:?}, {:?}i)", x, y),
+            Expr::Var(v, _) => v.clone(),
+            Expr::Call(f, args, _) => format!("{}({})", f, list(&args.iter().collect::<Vec<_>>())),
+            Expr::If(cond, a, b, _) => format!("if({})", list(&[cond, a, b])),
+            Expr::Let(name, value, body, _) => format!("let {}({})", name, list(&[value, body])),
+            Expr::Def(def, body, _) => format!("def {}[{}]({})", def.name, def.params.join(", "), list(&[&def.body, body])),
+        }
+    }
+
+    fn print(text: &str) -> String {
+        parse(text).unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_back_to_the_same_tree() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..3000 {
+            let e = gen(&mut rng, 4);
+            let text = e.to_string();
+            let back = parse(&text).unwrap_or_else(|err| panic!("{} doesn't parse: {}", text, err));
+            assert_eq!(shape(&fold(back)), shape(&fold(e)), "printed as {}", text);
+        }
+    }
+
+    #[test]
+    fn prints_towers_and_folded_numbers() {
+        assert_eq!(print("a^b^c"), "a^b^c");
+        assert_eq!(print("(a^b)^c"), "(a^b)^c");
+        assert_eq!(print("(-a)^-b"), "(-a)^-b");
+        assert_eq!(print("z - -1"), "z - -1");
+        assert_eq!(print("-(-z)"), "-(-z)");
+        assert_eq!(Expr::F64(-2.5e20, SPAN).to_string(), "-2.5e20");
+        assert_eq!(Expr::F64(f64::NEG_INFINITY, SPAN).to_string(), "-1e999");
+        assert_eq!(Expr::Complex(1.0, -2.0, SPAN).to_string(), "(1 - 2i)");
+        assert_eq!(Expr::Complex(0.0, -2.0, SPAN).to_string(), "(0 - 2i)");
+        assert_eq!(Expr::Complex(0.0, 1.5e-7, SPAN).to_string(), "1.5e-7i");
+    }
+}
@@ -0,0 +1,1061 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::rc::Rc;
+use crate::ast::{Expr, FuncDef, Span};
+use crate::check::{check_arg_count, check_arity, ArityErr};
+use crate::diff::{add, div, mul, neg, num, pow, sub};
+use crate::error::Spanned;
+
+// Formulas built from + - * / and whole powers, written as one polynomial in z over another.
+// The coefficients are polynomials in c and any other names the formula uses, so
+// conditions on the coefficients of a map such as
+//
+//     h(z) = (A*z^2 + B) / (D*z^2 + E)
+//
+// can be read off h(h(z)) instead of worked out by hand.
+//
+// Arithmetic is floating point. A coefficient that cancels to within rounding is dropped.
+#[derive(Clone, Debug)]
+pub struct Rational {
+    pub num: Poly,
+    pub den: Poly,
+}
+
+#[derive(Debug)]
+pub enum RationalErr {
+    Arity(ArityErr),
+    NotRational(String),
+    ExponentNotInteger,
+    DivideByZero,
+    // Over the cap given, which is passed along
+    DegreeTooHigh(usize),
+}
+
+// A polynomial in z, lowest power first, without trailing zeros
+#[derive(Clone, Debug, Default)]
+pub struct Poly {
+    coeffs: Vec<Coeff>,
+}
+
+// A polynomial in the parameters with complex coefficients
+#[derive(Clone, Debug, Default)]
+pub struct Coeff {
+    terms: BTreeMap<Mono, Cx>,
+}
+
+// Parameters and their powers, such as A^2*c. Empty for 1.
+type Mono = BTreeMap<String, u32>;
+
+pub type Cx = (f64, f64);
+
+// Relative size below which a sum counts as zero
+const EPS: f64 = 1e-12;
+// Repeated roots only converge linearly, halving the error each step
+const ROOT_STEPS: usize = 500;
+// The highest power of z, or total power of the parameters, that rational() multiplies out.
+// Beyond it the coefficients take too long to work out to be of any use.
+pub const MAX_DEGREE: usize = 64;
+
+pub fn rational(expr: &Expr) -> Result<Rational, Spanned<RationalErr>> {
+    rational_up_to(expr, MAX_DEGREE)
+}
+
+// rational() for callers that give up on a lower degree than MAX_DEGREE
+pub fn rational_up_to(expr: &Expr, max_degree: usize) -> Result<Rational, Spanned<RationalErr>> {
+    Normalizer {
+        env: vec![],
+        funcs: vec![],
+        max_degree,
+    }
+    .expr(expr)
+}
+
+impl fmt::Display for RationalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RationalErr::Arity(e) => write!(f, "{}", e),
+            RationalErr::NotRational(func) => write!(f, "'{}' can't be written as a ratio of polynomials in z", func),
+            RationalErr::ExponentNotInteger => write!(f, "Exponent must be a whole number"),
+            RationalErr::DivideByZero => write!(f, "Division by zero"),
+            RationalErr::DegreeTooHigh(max) => write!(f, "The powers of z or of the parameters would go over {}", max),
+        }
+    }
+}
+
+impl RationalErr {
+    pub fn at(self, span: Span) -> Spanned<RationalErr> {
+        Spanned { span, err: self }
+    }
+}
+
+impl Rational {
+    fn constant(c: Coeff) -> Rational {
+        Rational {
+            num: Poly::constant(c),
+            den: Poly::constant(Coeff::constant((1.0, 0.0))),
+        }
+    }
+
+    fn z() -> Rational {
+        Rational {
+            num: Poly::new(vec![Coeff::default(), Coeff::constant((1.0, 0.0))]),
+            den: Poly::constant(Coeff::constant((1.0, 0.0))),
+        }
+    }
+
+    fn add(&self, other: &Rational) -> Rational {
+        let num = self.num.mul(&other.den).add(&other.num.mul(&self.den));
+        Rational { num, den: self.den.mul(&other.den) }.reduce()
+    }
+
+    fn sub(&self, other: &Rational) -> Rational {
+        self.add(&other.neg())
+    }
+
+    fn neg(&self) -> Rational {
+        Rational {
+            num: self.num.scale(&Coeff::constant((-1.0, 0.0))),
+            den: self.den.clone(),
+        }
+    }
+
+    fn mul(&self, other: &Rational) -> Rational {
+        Rational {
+            num: self.num.mul(&other.num),
+            den: self.den.mul(&other.den),
+        }
+        .reduce()
+    }
+
+    fn div(&self, other: &Rational) -> Result<Rational, RationalErr> {
+        if other.num.is_zero() {
+            return Err(RationalErr::DivideByZero);
+        }
+        Ok(Rational {
+            num: self.num.mul(&other.den),
+            den: self.den.mul(&other.num),
+        }
+        .reduce())
+    }
+
+    // By repeated squaring, once it's clear the result stays within max_degree
+    fn powi(&self, n: i32, max_degree: usize) -> Result<Rational, RationalErr> {
+        if self.degree().saturating_mul(n.unsigned_abs() as usize) > max_degree {
+            return Err(RationalErr::DegreeTooHigh(max_degree));
+        }
+        let one = Rational::constant(Coeff::constant((1.0, 0.0)));
+        let (mut result, mut square, mut k) = (one.clone(), self.clone(), n.unsigned_abs());
+        while k > 0 {
+            if k & 1 == 1 {
+                result = result.mul(&square);
+            }
+            k >>= 1;
+            if k > 0 {
+                square = square.mul(&square);
+            }
+        }
+        if n < 0 {
+            one.div(&result)
+        } else {
+            Ok(result)
+        }
+    }
+
+    // The highest power of z, or total power of the parameters, on either side
+    fn degree(&self) -> usize {
+        let sides = [&self.num, &self.den];
+        let degrees = sides.iter().flat_map(|p| p.coeffs.iter().enumerate().map(|(k, c)| k.max(c.degree() as usize)));
+        degrees.max().unwrap_or(0)
+    }
+
+    // A whole number, with no z and no parameters
+    fn as_integer(&self) -> Option<i32> {
+        let n = self.num.coeffs.first().map_or(Some((0.0, 0.0)), Coeff::as_constant)?;
+        let d = self.den.coeffs.first()?.as_constant()?;
+        if self.num.coeffs.len() > 1 || self.den.coeffs.len() > 1 {
+            return None;
+        }
+        let (x, y) = cx_div(n, d);
+        let rounded = x.round();
+        if y.abs() <= EPS && (x - rounded).abs() <= EPS * x.abs().max(1.0) && rounded.abs() <= i32::MAX as f64 {
+            Some(rounded as i32)
+        } else {
+            None
+        }
+    }
+
+    // self(g(z)). With g = p/q and d the larger degree of self, this is
+    // sum a_k p^k q^(d-k) over sum b_k p^k q^(d-k).
+    pub fn compose(&self, g: &Rational) -> Result<Rational, RationalErr> {
+        Ok(self.compose_unreduced(g)?.reduce())
+    }
+
+    // compose without cancelling common factors, which is slow with many parameters
+    pub fn compose_unreduced(&self, g: &Rational) -> Result<Rational, RationalErr> {
+        if self.degree().saturating_mul(g.degree() + 1) > MAX_DEGREE {
+            return Err(RationalErr::DegreeTooHigh(MAX_DEGREE));
+        }
+        let n = self.num.coeffs.len().max(self.den.coeffs.len());
+        let den = self.den.substitute(g, n);
+        if den.is_zero() {
+            return Err(RationalErr::DivideByZero);
+        }
+        Ok(Rational { num: self.num.substitute(g, n), den })
+    }
+
+    // Cancels everything the numerator and denominator have in common, and makes the
+    // numbers whole where that's possible
+    fn reduce(self) -> Rational {
+        let Rational { mut num, mut den } = self;
+        let g = gcd(&num, &den);
+        if g.degree().is_some_and(|d| d > 0) {
+            if let (Some(n), Some(d)) = (num.div_poly(&g), den.div_poly(&g)) {
+                num = n;
+                den = d;
+            }
+        }
+        let content = coeff_gcd(&num.content(), &den.content());
+        if let (Some(n), Some(d)) = (num.div_exact(&content), den.div_exact(&content)) {
+            num = n;
+            den = d;
+        }
+        let scale = Coeff::constant(whole_scale(&num, &den));
+        Rational {
+            num: num.scale(&scale),
+            den: den.scale(&scale),
+        }
+    }
+
+    // The names other than z that the coefficients use
+    pub fn params(&self) -> BTreeSet<String> {
+        let coeffs = self.num.coeffs.iter().chain(&self.den.coeffs);
+        coeffs.flat_map(|c| c.terms.keys()).flat_map(|m| m.keys()).cloned().collect()
+    }
+
+    // Puts numbers in place of some of the parameters. None if the denominator vanishes.
+    pub fn substitute(&self, values: &BTreeMap<String, Cx>) -> Option<Rational> {
+        let poly = |p: &Poly| Poly::new(p.coeffs.iter().map(|c| c.substitute(values)).collect());
+        let den = poly(&self.den);
+        if den.is_zero() {
+            return None;
+        }
+        Some(Rational { num: poly(&self.num), den }.reduce())
+    }
+
+    // num * other.den - den * other.num, which is zero exactly when the two are equal
+    pub fn difference(&self, other: &Rational) -> Poly {
+        self.num.mul(&other.den).sub(&self.den.mul(&other.num))
+    }
+
+    // The numerator of the derivative, num' den - num den', with any common factor of its
+    // coefficients taken out. Its roots are the critical points in the finite plane.
+    pub fn critical_numerator(&self) -> Poly {
+        let d = self.num.derivative().mul(&self.den).sub(&self.num.mul(&self.den.derivative()));
+        d.primitive()
+    }
+
+    // Equal up to relative rounding of about tolerance
+    pub fn approx_eq(&self, other: &Rational, tolerance: f64) -> bool {
+        let scale = self.num.size() * other.den.size() + self.den.size() * other.num.size();
+        self.difference(other).size() <= tolerance * scale
+    }
+
+    // Applies f to every number, after dividing through by the leading number of the
+    // denominator so that equal functions give equal results
+    pub fn map_numbers(&self, f: impl Fn(Cx) -> Cx) -> Rational {
+        let lead = self.den.lead().and_then(Coeff::leading).map_or((1.0, 0.0), |(_, x)| x);
+        let poly = |p: &Poly| {
+            Poly::new(p.coeffs.iter().map(|c| {
+                let mut result = Coeff::default();
+                for (mono, x) in &c.terms {
+                    result.add_term(mono.clone(), f(cx_div(*x, lead)));
+                }
+                result
+            }).collect())
+        };
+        Rational { num: poly(&self.num), den: poly(&self.den) }.reduce()
+    }
+
+    pub fn is_real(&self) -> bool {
+        let mut coeffs = self.num.coeffs.iter().chain(&self.den.coeffs);
+        coeffs.all(|c| c.terms.values().all(|x| x.1 == 0.0))
+    }
+
+    // num / den as a formula
+    pub fn to_expr(&self, span: Span) -> Expr {
+        let num = self.num.to_expr(span);
+        match self.den.coeffs[..] {
+            [ref d] if d.as_constant() == Some((1.0, 0.0)) => num,
+            _ => div(num, self.den.to_expr(span)),
+        }
+    }
+}
+
+// The formula, then the degree and coefficients of each side:
+//
+//     (z^4 + c + 1) / (2 * z^2 + c)
+//     numerator, degree 4
+//       z^4  1
+//       z^0  c + 1
+//     ...
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = Span { start: 0, end: 0 };
+        writeln!(f, "{}", self.to_expr(span))?;
+        for (name, poly) in [("numerator", &self.num), ("denominator", &self.den)] {
+            match poly.degree() {
+                Some(degree) => writeln!(f, "{}, degree {}", name, degree)?,
+                None => writeln!(f, "{}, zero", name)?,
+            }
+            for (k, coeff) in poly.coeffs.iter().enumerate().rev() {
+                if !coeff.is_zero() {
+                    writeln!(f, "  z^{}  {}", k, coeff.to_expr(span))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Poly {
+    fn new(mut coeffs: Vec<Coeff>) -> Poly {
+        while coeffs.last().is_some_and(Coeff::is_zero) {
+            coeffs.pop();
+        }
+        Poly { coeffs }
+    }
+
+    fn constant(c: Coeff) -> Poly {
+        Poly::new(vec![c])
+    }
+
+    // The sum of the sizes of all the numbers, a rough scale for telling rounding from zero
+    pub fn size(&self) -> f64 {
+        self.coeffs.iter().flat_map(|c| c.terms.values()).map(|x| cx_abs(*x)).sum()
+    }
+
+    // Lowest power first
+    pub fn coeffs(&self) -> &[Coeff] {
+        &self.coeffs
+    }
+
+    // Every root, repeated as often as it divides, when the coefficients are all numbers.
+    // The Aberth-Ehrlich method, as the compiled module uses for coefficients that aren't.
+    pub fn roots(&self) -> Option<Vec<Cx>> {
+        let a: Vec<Cx> = self.coeffs.iter().map(Coeff::as_constant).collect::<Option<_>>()?;
+        let n = a.len().checked_sub(1)?;
+        let b: Vec<Cx> = a.iter().map(|x| cx_div(*x, a[n])).collect();
+        let radius = 1.0 + b[..n].iter().map(|x| cx_abs(*x)).fold(0.0, f64::max);
+        let mut z: Vec<Cx> = (0..n).map(|k| {
+            let angle = 2.0 * std::f64::consts::PI * k as f64 / n as f64 + 0.4;
+            (radius * angle.cos(), radius * angle.sin())
+        }).collect();
+        let sub = |a: Cx, b: Cx| (a.0 - b.0, a.1 - b.1);
+        let add = |a: Cx, b: Cx| (a.0 + b.0, a.1 + b.1);
+        for _ in 0..ROOT_STEPS {
+            let mut moves = 0.0;
+            for k in 0..n {
+                let (mut p, mut dp) = ((1.0, 0.0), (0.0, 0.0));
+                for bj in b[..n].iter().rev() {
+                    dp = add(cx_mul(dp, z[k]), p);
+                    p = add(cx_mul(p, z[k]), *bj);
+                }
+                let newton = cx_div(p, dp);
+                let repulsion = z.iter().enumerate().filter(|(j, _)| *j != k).fold((0.0, 0.0), |sum, (_, zj)| {
+                    add(sum, cx_div((1.0, 0.0), sub(z[k], *zj)))
+                });
+                let w = cx_div(newton, sub((1.0, 0.0), cx_mul(newton, repulsion)));
+                // Exactly on a repeated root, w is 0/0
+                if cx_abs(w).is_finite() {
+                    z[k] = sub(z[k], w);
+                    moves += cx_abs(w);
+                }
+            }
+            if moves <= 1e-15 * radius {
+                break;
+            }
+        }
+        Some(z)
+    }
+
+    // None for the zero polynomial
+    pub fn degree(&self) -> Option<usize> {
+        self.coeffs.len().checked_sub(1)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.coeffs.is_empty()
+    }
+
+    fn lead(&self) -> Option<&Coeff> {
+        self.coeffs.last()
+    }
+
+    fn add(&self, other: &Poly) -> Poly {
+        let len = self.coeffs.len().max(other.coeffs.len());
+        let zero = Coeff::default();
+        Poly::new((0..len).map(|k| {
+            let a = self.coeffs.get(k).unwrap_or(&zero);
+            let b = other.coeffs.get(k).unwrap_or(&zero);
+            a.add(b)
+        }).collect())
+    }
+
+    fn sub(&self, other: &Poly) -> Poly {
+        self.add(&other.scale(&Coeff::constant((-1.0, 0.0))))
+    }
+
+    fn mul(&self, other: &Poly) -> Poly {
+        if self.is_zero() || other.is_zero() {
+            return Poly::default();
+        }
+        let mut coeffs = vec![Coeff::default(); self.coeffs.len() + other.coeffs.len() - 1];
+        for (i, a) in self.coeffs.iter().enumerate() {
+            for (j, b) in other.coeffs.iter().enumerate() {
+                coeffs[i + j] = coeffs[i + j].add(&a.mul(b));
+            }
+        }
+        Poly::new(coeffs)
+    }
+
+    fn scale(&self, c: &Coeff) -> Poly {
+        Poly::new(self.coeffs.iter().map(|a| a.mul(c)).collect())
+    }
+
+    fn derivative(&self) -> Poly {
+        let coeffs = self.coeffs.iter().enumerate().skip(1);
+        Poly::new(coeffs.map(|(k, c)| c.mul(&Coeff::constant((k as f64, 0.0)))).collect())
+    }
+
+    // Times z^k
+    fn shift(&self, k: usize) -> Poly {
+        let mut coeffs = vec![Coeff::default(); k];
+        coeffs.extend(self.coeffs.iter().cloned());
+        Poly::new(coeffs)
+    }
+
+    // Returns q, r and m such that lc(d)^m self = q d + r, with r of lower degree than d.
+    // Works without dividing coefficients, which needn't be possible.
+    fn pseudo_divide(&self, d: &Poly) -> (Poly, Poly, u32) {
+        let (dd, lc) = (d.degree().unwrap(), d.lead().unwrap());
+        let mut q = Poly::default();
+        let mut r = self.clone();
+        let mut m = 0;
+        while let Some(dr) = r.degree().filter(|&dr| dr >= dd) {
+            let t = Poly::constant(r.lead().unwrap().clone()).shift(dr - dd);
+            q = q.scale(lc).add(&t);
+            r = r.scale(lc).sub(&t.mul(d));
+            // The leading terms cancel by construction; don't let rounding leave a trace
+            if r.coeffs.len() > dr {
+                r.coeffs.truncate(dr);
+                r = Poly::new(r.coeffs);
+            }
+            m += 1;
+        }
+        (q, r, m)
+    }
+
+    fn div_exact(&self, c: &Coeff) -> Option<Poly> {
+        let coeffs = self.coeffs.iter().map(|a| a.div_exact(c)).collect::<Option<Vec<_>>>()?;
+        Some(Poly::new(coeffs))
+    }
+
+    // Long division that fails unless d divides self
+    fn div_poly(&self, d: &Poly) -> Option<Poly> {
+        let (dd, lc) = (d.degree()?, d.lead()?);
+        let mut q = vec![Coeff::default(); self.coeffs.len().saturating_sub(dd)];
+        let mut r = self.clone();
+        while let Some(dr) = r.degree().filter(|&dr| dr >= dd) {
+            let t = r.lead().unwrap().div_exact(lc)?;
+            r = r.sub(&Poly::constant(t.clone()).shift(dr - dd).mul(d));
+            if r.coeffs.len() > dr {
+                r.coeffs.truncate(dr);
+                r = Poly::new(r.coeffs);
+            }
+            q[dr - dd] = t;
+        }
+        if r.is_zero() {
+            Some(Poly::new(q))
+        } else {
+            None
+        }
+    }
+
+    // The greatest common divisor of the coefficients
+    fn content(&self) -> Coeff {
+        self.coeffs.iter().fold(Coeff::default(), |g, c| coeff_gcd(&g, c))
+    }
+
+    fn primitive(&self) -> Poly {
+        self.div_exact(&self.content()).unwrap_or_else(|| self.clone())
+    }
+
+    // sum a_k p^k q^(n-k), where g = p/q
+    fn substitute(&self, g: &Rational, n: usize) -> Poly {
+        let powers = |p: &Poly| {
+            let mut powers = vec![Poly::constant(Coeff::constant((1.0, 0.0)))];
+            for k in 1..n {
+                powers.push(powers[k - 1].mul(p));
+            }
+            powers
+        };
+        let (p, q) = (powers(&g.num), powers(&g.den));
+        let mut result = Poly::default();
+        for (k, a) in self.coeffs.iter().enumerate() {
+            result = result.add(&p[k].mul(&q[n - 1 - k]).scale(a));
+        }
+        result
+    }
+
+    // Reads a polynomial in v back into a coefficient
+    fn to_coeff(&self, v: &str) -> Coeff {
+        let mut result = Coeff::default();
+        for (k, coeff) in self.coeffs.iter().enumerate() {
+            for (mono, x) in &coeff.terms {
+                let mut mono = mono.clone();
+                if k > 0 {
+                    mono.insert(v.to_owned(), k as u32);
+                }
+                result.add_term(mono, *x);
+            }
+        }
+        result
+    }
+
+    fn to_expr(&self, span: Span) -> Expr {
+        let terms = self.coeffs.iter().enumerate().rev().filter(|(_, coeff)| !coeff.is_zero()).map(|(k, coeff)| {
+            let power = pow(Expr::Var("z".to_owned(), span), num(k as f64, span));
+            coeff.signed_times(power, span)
+        });
+        sum(terms).unwrap_or_else(|| num(0.0, span))
+    }
+}
+
+// The greatest common divisor, by the primitive remainder sequence. Its coefficients have
+// no common factor.
+fn gcd(a: &Poly, b: &Poly) -> Poly {
+    let (mut a, mut b) = (a.primitive(), b.primitive());
+    while !b.is_zero() {
+        let (_, r, _) = a.pseudo_divide(&b);
+        a = b;
+        b = r.primitive();
+    }
+    a
+}
+
+// The greatest common divisor of two coefficients, with a leading number of 1. Works one
+// parameter at a time, treating the coefficients as polynomials in it.
+fn coeff_gcd(a: &Coeff, b: &Coeff) -> Coeff {
+    if a.is_zero() || b.is_zero() {
+        return a.add(b).monic();
+    }
+    let v = match a.terms.keys().chain(b.terms.keys()).flat_map(|m| m.keys()).min() {
+        Some(v) => v.clone(),
+        None => return Coeff::constant((1.0, 0.0)),
+    };
+    let (pa, pb) = (a.in_var(&v), b.in_var(&v));
+    let content = coeff_gcd(&pa.content(), &pb.content());
+    gcd(&pa, &pb).to_coeff(&v).mul(&content).monic()
+}
+
+// A number that makes every coefficient of num and den whole, if there is one, with the
+// leading coefficient of den positive
+fn whole_scale(num: &Poly, den: &Poly) -> Cx {
+    let numbers: Vec<Cx> = num.coeffs.iter().chain(&den.coeffs).flat_map(|c| c.terms.values().copied()).collect();
+    let lead = match den.lead().and_then(Coeff::leading) {
+        Some((_, x)) => x,
+        None => return (1.0, 0.0),
+    };
+    if numbers.iter().all(|x| is_whole(cx_div(*x, lead))) {
+        return cx_div((1.0, 0.0), lead);
+    }
+    if numbers.iter().all(|x| is_whole(*x)) {
+        let g = numbers.iter().fold(0.0, |g, x| whole_gcd(g, x.0.abs().round()));
+        return (lead.0.signum() / g, 0.0);
+    }
+    (1.0, 0.0)
+}
+
+fn is_whole(x: Cx) -> bool {
+    x.1.abs() <= EPS && (x.0 - x.0.round()).abs() <= 1e-9 * x.0.abs().max(1.0)
+}
+
+fn whole_gcd(a: f64, b: f64) -> f64 {
+    if b == 0.0 {
+        a
+    } else {
+        whole_gcd(b, a % b)
+    }
+}
+
+impl Coeff {
+    pub fn constant(x: Cx) -> Coeff {
+        let mut c = Coeff::default();
+        c.add_term(Mono::new(), x);
+        c
+    }
+
+    pub fn param(name: &str) -> Coeff {
+        Coeff {
+            terms: BTreeMap::from([(Mono::from([(name.to_owned(), 1)]), (1.0, 0.0))]),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    fn as_constant(&self) -> Option<Cx> {
+        match self.terms.iter().next() {
+            None => Some((0.0, 0.0)),
+            Some((mono, x)) if mono.is_empty() && self.terms.len() == 1 => Some(*x),
+            _ => None,
+        }
+    }
+
+    fn add_term(&mut self, mono: Mono, x: Cx) {
+        let old = self.terms.get(&mono).copied().unwrap_or((0.0, 0.0));
+        let sum = (old.0 + x.0, old.1 + x.1);
+        if cx_abs(sum) <= EPS * (cx_abs(old) + cx_abs(x)) {
+            self.terms.remove(&mono);
+        } else {
+            self.terms.insert(mono, sum);
+        }
+    }
+
+    pub fn add(&self, other: &Coeff) -> Coeff {
+        let mut result = self.clone();
+        for (mono, x) in &other.terms {
+            result.add_term(mono.clone(), *x);
+        }
+        result
+    }
+
+    pub fn mul(&self, other: &Coeff) -> Coeff {
+        let mut result = Coeff::default();
+        for (m0, x0) in &self.terms {
+            for (m1, x1) in &other.terms {
+                let mut mono = m0.clone();
+                for (v, e) in m1 {
+                    *mono.entry(v.clone()).or_insert(0) += e;
+                }
+                result.add_term(mono, cx_mul(*x0, *x1));
+            }
+        }
+        result
+    }
+
+    // Scaled so the leading term has coefficient 1
+    fn monic(&self) -> Coeff {
+        match self.leading() {
+            Some((_, x)) => self.mul(&Coeff::constant(cx_div((1.0, 0.0), x))),
+            None => self.clone(),
+        }
+    }
+
+    // As a polynomial in v, with coefficients in the other parameters
+    fn in_var(&self, v: &str) -> Poly {
+        let mut coeffs = vec![];
+        for (mono, x) in &self.terms {
+            let mut mono = mono.clone();
+            let k = mono.remove(v).unwrap_or(0) as usize;
+            if coeffs.len() <= k {
+                coeffs.resize(k + 1, Coeff::default());
+            }
+            coeffs[k].add_term(mono, *x);
+        }
+        Poly::new(coeffs)
+    }
+
+    // Puts numbers in place of the parameters that have them
+    pub fn substitute(&self, values: &BTreeMap<String, Cx>) -> Coeff {
+        let mut result = Coeff::default();
+        for (mono, x) in &self.terms {
+            let (mut mono, mut x) = (mono.clone(), *x);
+            mono.retain(|v, power| match values.get(v) {
+                Some(value) => {
+                    for _ in 0..*power {
+                        x = cx_mul(x, *value);
+                    }
+                    false
+                }
+                None => true,
+            });
+            result.add_term(mono, x);
+        }
+        result
+    }
+
+    // The value, given every parameter
+    pub fn eval(&self, values: &BTreeMap<String, Cx>) -> Cx {
+        self.substitute(values).as_constant().unwrap_or((f64::NAN, f64::NAN))
+    }
+
+    // What the value is made of: the sum of the sizes of the terms, given every parameter
+    pub fn magnitude(&self, values: &BTreeMap<String, Cx>) -> f64 {
+        let size = |(v, power): (&String, &u32)| values.get(v).map_or(f64::NAN, |x| cx_abs(*x).powi(*power as i32));
+        self.terms.iter().map(|(mono, x)| cx_abs(*x) * mono.iter().map(size).product::<f64>()).sum()
+    }
+
+    // The largest total degree of a term
+    fn degree(&self) -> u32 {
+        self.terms.keys().map(|m| m.values().sum::<u32>()).max().unwrap_or(0)
+    }
+
+    // The total degree of every term, or None if they differ
+    pub fn homogeneous_degree(&self) -> Option<u32> {
+        let mut degrees = self.terms.keys().map(|m| m.values().sum::<u32>());
+        let first = degrees.next()?;
+        degrees.all(|d| d == first).then_some(first)
+    }
+
+    pub fn derivative(&self, v: &str) -> Coeff {
+        let mut result = Coeff::default();
+        for (mono, x) in &self.terms {
+            if let Some(&power) = mono.get(v) {
+                let mut mono = mono.clone();
+                if power == 1 {
+                    mono.remove(v);
+                } else {
+                    mono.insert(v.to_owned(), power - 1);
+                }
+                result.add_term(mono, cx_mul(*x, (power as f64, 0.0)));
+            }
+        }
+        result
+    }
+
+    // Groups the terms by the part of their monomial in the parameters that aren't kept, and
+    // returns each group as a polynomial in the kept ones. The whole is zero for every value
+    // of the others exactly when each of these is.
+    pub fn split(&self, keep: impl Fn(&str) -> bool) -> Vec<Coeff> {
+        let mut groups: BTreeMap<Mono, Coeff> = BTreeMap::new();
+        for (mono, x) in &self.terms {
+            let (kept, rest) = mono.iter().map(|(v, e)| (v.clone(), *e)).partition(|(v, _)| keep(v));
+            groups.entry(rest).or_default().add_term(kept, *x);
+        }
+        groups.into_values().collect()
+    }
+
+    // The greatest term in graded lexicographic order
+    fn leading(&self) -> Option<(&Mono, Cx)> {
+        self.terms.iter().max_by(|a, b| mono_cmp(a.0, b.0)).map(|(m, x)| (m, *x))
+    }
+
+    // Multivariate long division, None when d doesn't divide self
+    fn div_exact(&self, d: &Coeff) -> Option<Coeff> {
+        let (dm, dx) = d.leading()?;
+        let mut rem = self.clone();
+        let mut q = Coeff::default();
+        while let Some((m, x)) = rem.leading().map(|(m, x)| (m.clone(), x)) {
+            let mut tm = m.clone();
+            for (v, e) in dm {
+                let have = tm.get_mut(v).filter(|have| **have >= *e)?;
+                *have -= e;
+                if *have == 0 {
+                    tm.remove(v);
+                }
+            }
+            let t = Coeff {
+                terms: BTreeMap::from([(tm, cx_div(x, dx))]),
+            };
+            rem = rem.add(&t.mul(d).mul(&Coeff::constant((-1.0, 0.0))));
+            rem.terms.remove(&m);
+            q = q.add(&t);
+        }
+        Some(q)
+    }
+
+    pub fn to_expr(&self, span: Span) -> Expr {
+        self.times(num(1.0, span), span)
+    }
+
+    // self * e, written the way one would by hand: 2*A*z^2 - B*z rather than (2*A + -B)*z
+    fn times(&self, e: Expr, span: Span) -> Expr {
+        signed(self.signed_times(e, span))
+    }
+
+    // Like times, but a single term with a negative number comes back positive, with true
+    fn signed_times(&self, e: Expr, span: Span) -> (bool, Expr) {
+        let mut terms: Vec<_> = self.terms.iter().collect();
+        terms.sort_by(|a, b| mono_cmp(b.0, a.0));
+        match terms[..] {
+            [] => (false, num(0.0, span)),
+            [(mono, x)] => term(mono, *x, e, span),
+            _ => {
+                let terms = terms.into_iter().map(|(mono, x)| term(mono, *x, num(1.0, span), span));
+                (false, mul(sum(terms).unwrap(), e))
+            }
+        }
+    }
+}
+
+// Adds up signed terms, subtracting the negative ones
+fn sum(terms: impl IntoIterator<Item = (bool, Expr)>) -> Option<Expr> {
+    let mut terms = terms.into_iter();
+    let first = signed(terms.next()?);
+    Some(terms.fold(first, |sum, (negative, t)| if negative { sub(sum, t) } else { add(sum, t) }))
+}
+
+fn signed((negative, e): (bool, Expr)) -> Expr {
+    if negative {
+        negate_leading(e)
+    } else {
+        e
+    }
+}
+
+// |x| * mono * e, and whether x was negative
+fn term(mono: &Mono, x: Cx, e: Expr, span: Span) -> (bool, Expr) {
+    let negative = (x.1 == 0.0 && x.0 < 0.0) || (x.0 == 0.0 && x.1 < 0.0);
+    let mut product = match x {
+        (x, 0.0) => num(x.abs(), span),
+        (0.0, y) if y.abs() == 1.0 => Expr::Var("i".to_owned(), span),
+        (0.0, y) => Expr::Complex(0.0, y.abs(), span),
+        (x, y) => add(num(x, span), Expr::Complex(0.0, y, span)),
+    };
+    for (v, power) in mono {
+        product = mul(product, pow(Expr::Var(v.clone(), span), num(*power as f64, span)));
+    }
+    (negative, mul(product, e))
+}
+
+// Negates the first factor of a product, so it prints as -2 * c rather than -(2 * c)
+fn negate_leading(e: Expr) -> Expr {
+    match e {
+        Expr::Call(f, mut args, span) if f == "*" => {
+            let b = args.pop().unwrap();
+            let a = args.pop().unwrap();
+            Expr::Call(f, vec![negate_leading(a), b], span)
+        }
+        Expr::F64(x, span) => num(-x, span),
+        e => neg(e),
+    }
+}
+
+fn mono_cmp(a: &Mono, b: &Mono) -> Ordering {
+    let degree = |m: &Mono| m.values().sum::<u32>();
+    degree(a).cmp(&degree(b)).then_with(|| {
+        for v in a.keys().chain(b.keys()).collect::<std::collections::BTreeSet<_>>() {
+            let ord = a.get(v).unwrap_or(&0).cmp(b.get(v).unwrap_or(&0));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        Ordering::Equal
+    })
+}
+
+pub fn cx_abs(x: Cx) -> f64 {
+    x.0.hypot(x.1)
+}
+
+pub fn cx_mul(a: Cx, b: Cx) -> Cx {
+    (a.0 * b.0 - a.1 * b.1, a.0 * b.1 + a.1 * b.0)
+}
+
+pub fn cx_div(a: Cx, b: Cx) -> Cx {
+    let d = b.0 * b.0 + b.1 * b.1;
+    ((a.0 * b.0 + a.1 * b.1) / d, (a.1 * b.0 - a.0 * b.1) / d)
+}
+
+// Evaluates a formula to a Rational. Lets and defs are expanded as they're met.
+struct Normalizer {
+    env: Vec<(String, Rational)>,
+    funcs: Vec<Rc<Closure>>,
+    max_degree: usize,
+}
+
+struct Closure {
+    def: FuncDef,
+    env: Vec<(String, Rational)>,
+    funcs: Vec<Rc<Closure>>,
+}
+
+impl Normalizer {
+    fn expr(&mut self, expr: &Expr) -> Result<Rational, Spanned<RationalErr>> {
+        match expr {
+            Expr::F64(x, _) => Ok(Rational::constant(Coeff::constant((*x, 0.0)))),
+            Expr::Complex(x, y, _) => Ok(Rational::constant(Coeff::constant((*x, *y)))),
+            Expr::Var(v, _) => Ok(match self.env.iter().rev().find(|(name, _)| name == v) {
+                Some((_, r)) => r.clone(),
+                None if v == "z" => Rational::z(),
+                None if v == "i" => Rational::constant(Coeff::constant((0.0, 1.0))),
+                None => Rational::constant(Coeff::param(v)),
+            }),
+            Expr::Call(f, args, span) => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect::<Result<Vec<_>, _>>()?;
+                if let Some(closure) = self.funcs.iter().rev().find(|c| c.def.name == *f).cloned() {
+                    return self.inline(&closure, args, *span);
+                }
+                check_arity(f, args.len()).map_err(RationalErr::Arity).and_then(|_| call(f, &args, self.max_degree)).map_err(|e| e.at(*span))
+            }
+            Expr::If(_, _, _, span) => Err(RationalErr::NotRational("if".to_owned()).at(*span)),
+            Expr::Let(name, value, body, _) => {
+                let value = self.expr(value)?;
+                self.env.push((name.clone(), value));
+                let result = self.expr(body);
+                self.env.pop();
+                result
+            }
+            Expr::Def(def, body, _) => {
+                self.funcs.push(Rc::new(Closure {
+                    def: (**def).clone(),
+                    env: self.env.clone(),
+                    funcs: self.funcs.clone(),
+                }));
+                let result = self.expr(body);
+                self.funcs.pop();
+                result
+            }
+        }
+    }
+
+    fn inline(&mut self, closure: &Closure, args: Vec<Rational>, span: Span) -> Result<Rational, Spanned<RationalErr>> {
+        let def = &closure.def;
+        check_arg_count(&def.name, def.params.len(), args.len()).map_err(|e| RationalErr::Arity(e).at(span))?;
+        let env = std::mem::replace(&mut self.env, closure.env.clone());
+        let funcs = std::mem::replace(&mut self.funcs, closure.funcs.clone());
+        self.env.extend(def.params.iter().cloned().zip(args));
+        let result = self.expr(&def.body);
+        self.env = env;
+        self.funcs = funcs;
+        result
+    }
+}
+
+fn call(f: &str, args: &[Rational], max_degree: usize) -> Result<Rational, RationalErr> {
+    let result = match f {
+        "+" => args[0].add(&args[1]),
+        "-" => args[0].sub(&args[1]),
+        "*" => args[0].mul(&args[1]),
+        "/" => args[0].div(&args[1])?,
+        "neg" => args[0].neg(),
+        "^" | "pow" => {
+            let n = args[1].as_integer().ok_or(RationalErr::ExponentNotInteger)?;
+            args[0].powi(n, max_degree)?
+        }
+        _ => return Err(RationalErr::NotRational(f.to_owned())),
+    };
+    if result.degree() > max_degree {
+        return Err(RationalErr::DegreeTooHigh(max_degree));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+
+    const SPAN: Span = Span { start: 0, end: 0 };
+
+    fn rat(text: &str) -> Rational {
+        rational(&parse(text).unwrap()).unwrap_or_else(|e| panic!("{}: {}", text, e.err))
+    }
+
+    fn poly(text: &str) -> Poly {
+        rat(text).num
+    }
+
+    fn assert_reduces_to(text: &str, expected: &str) {
+        assert_eq!(rat(text).to_expr(SPAN).to_string(), expected, "{}", text);
+    }
+
+    #[test]
+    fn composes_a_map_with_itself() {
+        let h = "def h(z) = (A*z^2 + B) / (D*z^2 + E); ";
+        let hh = rat(&format!("{}h(h(z))", h));
+        // (A p^2 + B q^2) / (D p^2 + E q^2) with p = A z^2 + B and q = D z^2 + E
+        let by_hand = rat("((A^3 + B*D^2)*z^4 + (2*A^2*B + 2*B*D*E)*z^2 + A*B^2 + B*E^2) / ((A^2*D + D^2*E)*z^4 + (2*A*B*D + 2*D*E^2)*z^2 + B^2*D + E^3)");
+        assert!(hh.approx_eq(&by_hand, 1e-12), "{}", hh);
+        assert_eq!(hh.num.degree(), Some(4));
+        assert_eq!(hh.den.degree(), Some(4));
+        let h = rat(&format!("{}h(z)", h));
+        assert!(h.compose(&h).unwrap().approx_eq(&by_hand, 1e-12));
+    }
+
+    #[test]
+    fn cancels_common_factors() {
+        assert_reduces_to("(z^2 - 1)/(z - 1)", "z + 1");
+        assert_reduces_to("(c*z^2 - c)/(c*z - c)", "z + 1");
+        assert_reduces_to("(A*z^2 - A*B^2)/(z - B)", "A * z + A * B");
+        assert_reduces_to("1/(1/z + 1/z^2)", "z^2 / (z + 1)");
+    }
+
+    #[test]
+    fn makes_numbers_whole() {
+        assert_reduces_to("(z/2 + 1/4)/(z/4)", "(2 * z + 1) / z");
+        assert_reduces_to("(6*z + 4)/(4*z^2)", "(3 * z + 2) / (2 * z^2)");
+        assert_reduces_to("(6*z + 4)/(-2*z)", "(-3 * z - 2) / z");
+        assert_reduces_to("(z + 0.1)/3", "(z + 0.1) / 3");
+    }
+
+    #[test]
+    fn finds_common_divisors() {
+        let g = gcd(&poly("(z - 1)^2 * (z + 2)"), &poly("(z - 1) * (z + 3)"));
+        assert!(g.div_poly(&poly("z - 1")).is_some_and(|q| q.degree() == Some(0)));
+        assert_eq!(gcd(&poly("z^2 + 1"), &poly("z - 1")).degree(), Some(0));
+        let g = coeff_gcd(&poly("A^2*B - B^3").coeffs[0], &poly("A*C + B*C").coeffs[0]);
+        assert_eq!(g.to_expr(SPAN).to_string(), "A + B");
+        assert_eq!(coeff_gcd(&Coeff::default(), &poly("2*A").coeffs[0]).to_expr(SPAN).to_string(), "A");
+    }
+
+    #[test]
+    fn divides_polynomials() {
+        let (a, d) = (poly("A*z^3 + z + 1"), poly("B*z + 2"));
+        let (q, r, m) = a.pseudo_divide(&d);
+        assert!(r.degree() < d.degree());
+        // lc(d)^m a = q d + r
+        assert_eq!(m, 3);
+        assert!(poly("B^3").mul(&a).sub(&q.mul(&d).add(&r)).is_zero());
+        assert_eq!(poly("z^3 - 8").div_poly(&poly("z - 2")).unwrap().to_expr(SPAN).to_string(), "z^2 + 2 * z + 4");
+        assert!(poly("z^3 - 7").div_poly(&poly("z - 2")).is_none());
+    }
+
+    // Each expected root is found, as often as it's expected
+    fn assert_roots(p: &Poly, expected: &[Cx], tolerance: f64) {
+        let mut roots = p.roots().unwrap();
+        assert_eq!(roots.len(), expected.len());
+        for x in expected {
+            let nearest = (0..roots.len()).min_by(|&j, &k| {
+                let d = |r: Cx| cx_abs((r.0 - x.0, r.1 - x.1));
+                d(roots[j]).total_cmp(&d(roots[k]))
+            });
+            let r = roots.swap_remove(nearest.unwrap());
+            assert!(cx_abs((r.0 - x.0, r.1 - x.1)) <= tolerance, "{:?} for {:?} of {}", r, x, p.to_expr(SPAN));
+        }
+    }
+
+    #[test]
+    fn finds_every_root() {
+        let half = 3f64.sqrt() / 2.0;
+        assert_roots(&poly("z^3 - 1"), &[(1.0, 0.0), (-0.5, half), (-0.5, -half)], 1e-12);
+        assert_roots(&poly("z^2 + 1"), &[(0.0, 1.0), (0.0, -1.0)], 1e-12);
+        assert_roots(&poly("(z - 2) * (z + 0.5) * z"), &[(2.0, 0.0), (-0.5, 0.0), (0.0, 0.0)], 1e-12);
+        assert_roots(&poly("3*z - 1"), &[(1.0 / 3.0, 0.0)], 1e-12);
+        assert_eq!(poly("5").roots().unwrap().len(), 0);
+        assert!(poly("z^2 + c").roots().is_none());
+        assert!(Poly::default().roots().is_none());
+    }
+
+    #[test]
+    fn finds_repeated_roots() {
+        // These only converge linearly, and rounding moves a root of multiplicity m by
+        // about eps^(1/m)
+        assert_roots(&poly("(z - 1)^2 * (z + 2)"), &[(1.0, 0.0), (1.0, 0.0), (-2.0, 0.0)], 1e-6);
+        assert_roots(&poly("z^3"), &[(0.0, 0.0); 3], 1e-6);
+        assert_roots(&poly("(z^2 + 1)^2"), &[(0.0, 1.0), (0.0, 1.0), (0.0, -1.0), (0.0, -1.0)], 1e-6);
+    }
+
+    #[test]
+    fn reports_what_it_cannot_expand() {
+        let err = |text: &str| rational(&parse(text).unwrap()).unwrap_err().err.to_string();
+        assert_eq!(err("sin(z)"), "'sin' can't be written as a ratio of polynomials in z");
+        assert_eq!(err("z^0.5"), "Exponent must be a whole number");
+        assert_eq!(err("1/(z - z)"), "Division by zero");
+        assert_eq!(err("z^100"), "The powers of z or of the parameters would go over 64");
+        assert_eq!(err("def h(z) = z^9; h(h(z))"), "The powers of z or of the parameters would go over 64");
+    }
+}